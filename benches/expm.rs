@@ -11,10 +11,29 @@ pub fn expm_cpu(b: &mut Bencher) {
 
         let graph = Graph::new();
         let x = graph.tensor(x);
-        
+
         for _ in 0..10 {
             let z = x.expm();
             z.forward(); // forward pass
         }
     });
 }
+
+#[bench]
+pub fn expm_cpu_recompute(b: &mut Bencher) {
+    let x = ndarray::array![[0.01, 0.0, 0.0], [0.0, 0.01, 0.0], [0.0, 0.0, 0.01]].into_dyn();
+
+    // Build the chain once; each iteration only swaps the input and re-evaluates
+    let graph = Graph::new();
+    let t = graph.tensor(x.clone());
+    let mut z = t.expm();
+    for _ in 1..10 {
+        z = z.expm();
+    }
+    z.forward();
+
+    b.iter(|| {
+        t.set_value(x.clone());
+        graph.recompute();
+    });
+}
@@ -0,0 +1,113 @@
+#![feature(test)]
+extern crate test;
+use rust_grad::tensor::TensorType;
+use rust_grad::Graph;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use test::Bencher;
+
+///
+/// Counts heap allocations so the benches can report them.
+/// Run with `cargo +nightly bench --bench inplace -- --nocapture` to see the counts.
+///
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn count_allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+#[bench]
+pub fn mul_backward_cpu(b: &mut Bencher) {
+    let graph = Graph::new();
+    let x = graph.tensor(ndarray::Array::ones((8, 8)).into_dyn());
+    let y = graph.tensor(ndarray::Array::ones((8, 8)).into_dyn());
+    let z = x * y;
+
+    // Small arrays: every pass still leaks its Raw buffers (see the TODO on Raw)
+    z.forward();
+
+    let allocs = count_allocations(|| {
+        for _ in 0..100 {
+            z.backward(ndarray::Array::ones((8, 8)).into_dyn());
+        }
+    });
+    eprintln!("mul backward: {} allocations per pass", allocs / 100);
+
+    b.iter(|| z.backward(ndarray::Array::ones((8, 8)).into_dyn()));
+}
+
+#[bench]
+pub fn accumulate_allocating(b: &mut Bencher) {
+    let w = ndarray::Array::ones((64, 64)).into_dyn();
+    let mut grad = ndarray::Array::zeros((64, 64)).into_dyn();
+
+    let allocs = count_allocations(|| {
+        for _ in 0..100 {
+            grad = TensorType::add(&grad, &w);
+        }
+    });
+    eprintln!("allocating add: {} allocations per step", allocs / 100);
+
+    b.iter(|| grad = TensorType::add(&grad, &w));
+}
+
+#[bench]
+pub fn accumulate_in_place(b: &mut Bencher) {
+    let w = ndarray::Array::ones((64, 64)).into_dyn();
+    let mut grad = ndarray::Array::zeros((64, 64)).into_dyn();
+
+    let allocs = count_allocations(|| {
+        for _ in 0..100 {
+            grad.add_assign(&w);
+        }
+    });
+    eprintln!("in-place add: {} allocations per step", allocs / 100);
+
+    b.iter(|| grad.add_assign(&w));
+}
+
+#[bench]
+pub fn backward_pooled(b: &mut Bencher) {
+    let graph = Graph::new();
+    let x = graph.tensor(ndarray::Array::ones((8, 8)).into_dyn());
+    let y = graph.tensor(ndarray::Array::ones((8, 8)).into_dyn());
+    let z = (x * y) * x;
+    z.forward();
+
+    // Pooling reclaims gradient buffers; op contexts still leak (see the TODO on Raw)
+    let passes = |pooling: bool| {
+        graph.set_pooling(pooling);
+        count_allocations(|| {
+            for _ in 0..100 {
+                z.forward();
+                z.backward_with(ndarray::Array::ones((8, 8)).into_dyn(), false);
+            }
+        })
+    };
+    let fresh = passes(false);
+    let pooled = passes(true);
+    eprintln!(
+        "100 forward/backward passes: {} allocations fresh, {} pooled",
+        fresh, pooled
+    );
+
+    b.iter(|| z.backward_with(ndarray::Array::ones((8, 8)).into_dyn(), false));
+}
@@ -0,0 +1,69 @@
+#![feature(test)]
+extern crate test;
+use rust_grad::tensor::{set_matmul_backend, MatmulBackend, TensorType};
+
+use test::Bencher;
+
+fn operands() -> (
+    ndarray::ArrayD<f32>,
+    ndarray::ArrayD<f32>,
+    ndarray::ArrayD<f32>,
+) {
+    let x = ndarray::Array::from_shape_fn((256, 256), |(i, j)| (i + j) as f32 * 1e-3).into_dyn();
+    let y = ndarray::Array::from_shape_fn((256, 256), |(i, j)| (i * j) as f32 * 1e-5).into_dyn();
+    let grad = ndarray::Array::ones((256, 256)).into_dyn();
+    (x, y, grad)
+}
+
+///
+/// The gradients of x @ y computed by first materializing both transposes
+///
+#[bench]
+pub fn matmul_backward_cloned_transpose(b: &mut Bencher) {
+    let (x, y, grad) = operands();
+    b.iter(|| {
+        let a = grad.matmul(&TensorType::t(&y));
+        let b = TensorType::t(&x).matmul(&grad);
+        (a, b)
+    });
+}
+
+///
+/// The same gradients using transposed views
+///
+#[bench]
+pub fn matmul_backward_transposed_view(b: &mut Bencher) {
+    let (x, y, grad) = operands();
+    b.iter(|| {
+        let a = grad.matmul_transposed(&y, false, true);
+        let b = x.matmul_transposed(&grad, true, false);
+        (a, b)
+    });
+}
+
+fn large_operands() -> (ndarray::ArrayD<f32>, ndarray::ArrayD<f32>) {
+    let x = ndarray::Array::from_shape_fn((512, 512), |(i, j)| (i + j) as f32 * 1e-3).into_dyn();
+    let y = ndarray::Array::from_shape_fn((512, 512), |(i, j)| (i * j) as f32 * 1e-5).into_dyn();
+    (x, y)
+}
+
+///
+/// A 512x512 forward product with the naive triple loop
+///
+#[bench]
+pub fn matmul_forward_naive(b: &mut Bencher) {
+    let (x, y) = large_operands();
+    set_matmul_backend(MatmulBackend::Naive);
+    b.iter(|| x.matmul(&y));
+    set_matmul_backend(MatmulBackend::Ndarray);
+}
+
+///
+/// The same product through `ndarray` (BLAS when built with `--features blas`)
+///
+#[bench]
+pub fn matmul_forward_ndarray(b: &mut Bencher) {
+    let (x, y) = large_operands();
+    set_matmul_backend(MatmulBackend::Ndarray);
+    b.iter(|| x.matmul(&y));
+}
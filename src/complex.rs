@@ -0,0 +1,296 @@
+use crate::functions::Comparison;
+use crate::tensor::{MatmulBackend, Tensor, TensorType};
+use ndarray::{Array, Ix2, IxDyn};
+
+pub use num_complex::Complex32;
+
+///
+/// Complex-valued tensor data, e.g. to differentiate a unitary evolution `U = expm(iH)`.
+/// A wrapper rather than a second `ArrayD` impl, so that `Array::ones(..)` and friends keep
+/// inferring `f32` in real code
+///
+/// Gradients follow the convention that makes real and complex tensors agree: the gradient
+/// stored for `z = x + iy` is `dL/dx - i dL/dy`, i.e. `2 dL/dz` in Wirtinger calculus, with
+/// the loss taken as the real part of the seeded output. Holomorphic ops then backpropagate
+/// with the plain chain rule, and the descent direction is the conjugate of the gradient
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComplexArray(pub Array<Complex32, IxDyn>);
+
+impl From<Array<Complex32, IxDyn>> for ComplexArray {
+    fn from(data: Array<Complex32, IxDyn>) -> Self {
+        ComplexArray(data)
+    }
+}
+
+impl ComplexArray {
+    ///
+    /// Combine real and imaginary parts of the same shape
+    ///
+    pub fn from_parts(re: &Array<f32, IxDyn>, im: &Array<f32, IxDyn>) -> Self {
+        let mut out = re.mapv(|r| Complex32::new(r, 0.0));
+        out.zip_mut_with(im, |z, &i| z.im = i);
+        ComplexArray(out)
+    }
+
+    ///
+    /// Split into real and imaginary parts
+    ///
+    pub fn parts(&self) -> (Array<f32, IxDyn>, Array<f32, IxDyn>) {
+        (self.0.mapv(|z| z.re), self.0.mapv(|z| z.im))
+    }
+
+    fn map(&self, f: impl Fn(Complex32) -> Complex32) -> Self {
+        ComplexArray(self.0.mapv(f))
+    }
+
+    ///
+    /// Apply a real-linear structural op, e.g. a reshape or a slice, to both parts
+    ///
+    fn via_parts(&self, f: impl Fn(&Array<f32, IxDyn>) -> Array<f32, IxDyn>) -> Self {
+        let (re, im) = self.parts();
+        Self::from_parts(&f(&re), &f(&im))
+    }
+}
+
+fn real_only(op: &str) -> ! {
+    panic!("{} is only defined for real tensors", op)
+}
+
+impl<'d> TensorType<'d> for ComplexArray {
+    fn add_assign(&mut self, other: &Self) {
+        self.0 += &other.0;
+    }
+    fn sub_assign(&mut self, other: &Self) {
+        self.0 -= &other.0;
+    }
+    fn mul_assign(&mut self, other: &Self) {
+        self.0 *= &other.0;
+    }
+    fn div_assign(&mut self, other: &Self) {
+        self.0 /= &other.0;
+    }
+    fn scale(&mut self, val: f32) {
+        self.0.mapv_inplace(|z| z * val);
+    }
+    fn copy_from(&mut self, other: &Self) -> bool {
+        if self.0.shape() != other.0.shape() {
+            return false;
+        }
+        self.0.assign(&other.0);
+        true
+    }
+    ///
+    /// The real part; use `Tensor::value_complex` for the full value
+    ///
+    fn get_value_cpu(&self) -> Array<f32, IxDyn> {
+        self.0.mapv(|z| z.re)
+    }
+    fn tensor(&self) -> &Self {
+        self
+    }
+    fn add(&self, other: &Self) -> Self {
+        ComplexArray(&self.0 + &other.0)
+    }
+    fn sub(&self, other: &Self) -> Self {
+        ComplexArray(&self.0 - &other.0)
+    }
+    fn mul(&self, other: &Self) -> Self {
+        ComplexArray(&self.0 * &other.0)
+    }
+    fn div(&self, other: &Self) -> Self {
+        ComplexArray(&self.0 / &other.0)
+    }
+    fn matmul(&self, other: &Self) -> Self {
+        let x = self
+            .0
+            .view()
+            .into_dimensionality::<Ix2>()
+            .expect("Not a 2x2 matrix");
+        let y = other
+            .0
+            .view()
+            .into_dimensionality::<Ix2>()
+            .expect("Not a 2x2 matrix");
+
+        let out = match crate::tensor::matmul_backend() {
+            MatmulBackend::Ndarray => x.dot(&y),
+            MatmulBackend::Naive => {
+                let (m, k) = x.dim();
+                let (k2, n) = y.dim();
+                assert_eq!(k, k2, "Inner dimensions do not match");
+                Array::from_shape_fn((m, n), |(i, j)| (0..k).map(|l| x[[i, l]] * y[[l, j]]).sum())
+            }
+        };
+        ComplexArray(out.into_dyn())
+    }
+    ///
+    /// Reverses the axes without conjugating, so that holomorphic backward passes stay the
+    /// plain chain rule
+    ///
+    fn t(&self) -> Self {
+        ComplexArray(self.0.clone().reversed_axes())
+    }
+    fn conj_t(&'d self) -> Self {
+        ComplexArray(self.0.t().mapv(|z| z.conj()))
+    }
+    fn expm(&self) -> Self {
+        self.map(|z| z.exp())
+    }
+    fn floor(&'d self) -> Self {
+        real_only("floor")
+    }
+    fn ceil(&'d self) -> Self {
+        real_only("ceil")
+    }
+    fn round(&'d self) -> Self {
+        real_only("round")
+    }
+    fn ge(&'d self, _other: &Self) -> Self {
+        real_only("ge")
+    }
+    fn shape(&self) -> Vec<usize> {
+        self.0.shape().to_vec()
+    }
+    fn sum(&'d self) -> Self {
+        ComplexArray(Array::from_elem(IxDyn(&[]), self.0.sum()))
+    }
+    fn reshape(&'d self, shape: &[usize]) -> Self {
+        self.via_parts(|x| TensorType::reshape(x, shape))
+    }
+    fn sum_axes(&'d self, axes: &[usize]) -> Self {
+        self.via_parts(|x| x.sum_axes(axes))
+    }
+    fn tile(&'d self, reps: &[usize]) -> Self {
+        self.via_parts(|x| x.tile(reps))
+    }
+    fn broadcast_to(&'d self, shape: &[usize]) -> Self {
+        self.via_parts(|x| x.broadcast_to(shape))
+    }
+    fn slice_ranges(&'d self, ranges: &[(usize, usize)]) -> Self {
+        self.via_parts(|x| x.slice_ranges(ranges))
+    }
+    fn scatter_ranges(&'d self, shape: &[usize], ranges: &[(usize, usize)]) -> Self {
+        self.via_parts(|x| x.scatter_ranges(shape, ranges))
+    }
+    fn permute(&'d self, axes: &[usize]) -> Self {
+        self.via_parts(|x| x.permute(axes))
+    }
+    fn gather(&'d self, axis: usize, indices: &[usize]) -> Self {
+        self.via_parts(|x| TensorType::gather(x, axis, indices))
+    }
+    fn scatter_add(&'d self, shape: &[usize], axis: usize, indices: &[usize]) -> Self {
+        self.via_parts(|x| x.scatter_add(shape, axis, indices))
+    }
+    fn sqrt(&'d self) -> Self {
+        self.map(|z| z.sqrt())
+    }
+    fn mul_scalar(&'d self, val: f32) -> Self {
+        self.map(|z| z * val)
+    }
+    fn add_scalar(&'d self, val: f32) -> Self {
+        self.map(|z| z + val)
+    }
+    fn log_softmax(&'d self, _axis: usize) -> Self {
+        real_only("log_softmax")
+    }
+    fn softmax(&'d self, _axis: usize) -> Self {
+        real_only("softmax")
+    }
+    fn one_hot(&'d self, targets: &[usize]) -> Self {
+        let (re, _) = self.parts();
+        ComplexArray(re.one_hot(targets).mapv(|r| Complex32::new(r, 0.0)))
+    }
+    fn clamp(&'d self, _min: f32, _max: f32) -> Self {
+        real_only("clamp")
+    }
+    fn in_range(&'d self, _min: f32, _max: f32) -> Self {
+        real_only("in_range")
+    }
+    fn sigmoid(&'d self) -> Self {
+        self.map(|z| ((-z).exp() + 1.0).inv())
+    }
+    fn ln(&'d self) -> Self {
+        self.map(|z| z.ln())
+    }
+    fn softplus(&'d self) -> Self {
+        self.map(|z| (z.exp() + 1.0).ln())
+    }
+    fn cumsum(&'d self, axis: usize) -> Self {
+        self.via_parts(|x| x.cumsum(axis))
+    }
+    fn stack(&'d self, rest: &[&Self]) -> Self {
+        let views: Vec<_> = std::iter::once(self)
+            .chain(rest.iter().copied())
+            .map(|x| x.0.view())
+            .collect();
+        ComplexArray(ndarray::stack(ndarray::Axis(0), &views).expect("Shapes differ"))
+    }
+    fn masked_fill(&'d self, mask: &Array<bool, IxDyn>, value: f32) -> Self {
+        let (re, im) = self.parts();
+        Self::from_parts(&re.masked_fill(mask, value), &im.masked_fill(mask, 0.0))
+    }
+    fn compare(&'d self, _other: &Self, _cmp: Comparison) -> Self {
+        real_only("compare")
+    }
+    fn select(&'d self, mask: &Self, other: &Self) -> Self {
+        let mut out = self.0.clone();
+        ndarray::Zip::from(&mut out)
+            .and(&mask.0)
+            .and(&other.0)
+            .for_each(|z, m, &w| {
+                if m.re == 0.0 {
+                    *z = w
+                }
+            });
+        ComplexArray(out)
+    }
+    fn pad(&'d self, pads: &[(usize, usize)], value: f32) -> Self {
+        let (re, im) = self.parts();
+        Self::from_parts(&re.pad(pads, value), &im.pad(pads, 0.0))
+    }
+    fn div_eps(&self, other: &'d Self, eps: f32) -> Self {
+        ComplexArray(&self.0 / &other.0.mapv(|z| z + eps))
+    }
+    fn conj(&'d self) -> Self {
+        self.map(|z| z.conj())
+    }
+    fn abs2(&'d self) -> Self {
+        self.map(|z| Complex32::new(z.norm_sqr(), 0.0))
+    }
+    fn upload_like(&'d self, data: Array<f32, IxDyn>) -> Self {
+        ComplexArray(data.mapv(|x| Complex32::new(x, 0.0)))
+    }
+    fn val_like(&'d self, val: f32) -> Self {
+        ComplexArray(Array::from_elem(self.0.raw_dim(), Complex32::new(val, 0.0)))
+    }
+    fn ones_like(&'d self) -> Self {
+        ComplexArray(Array::ones(self.0.raw_dim()))
+    }
+    fn eye_like(&'d self) -> Self {
+        ComplexArray(Array::eye(self.0.shape()[0]).into_dyn())
+    }
+}
+
+impl<'d, 'g> Tensor<'d, 'g, ComplexArray> {
+    ///
+    /// Returns a copy of the complex value
+    ///
+    pub fn value_complex(&self) -> ComplexArray {
+        let nodes = self.graph.nodes.borrow();
+        let node = nodes[self.index].borrow();
+        let val = node.value.as_ref().expect("Was forward called?");
+        val.value().clone()
+    }
+
+    ///
+    /// Returns a copy of the gradient `dL/dx - i dL/dy`; its conjugate is the direction of
+    /// steepest ascent
+    ///
+    pub fn grad_complex(&self) -> ComplexArray {
+        let nodes = self.graph.nodes.borrow();
+        let node = nodes[self.index].borrow();
+        let val = node.grad.as_ref().expect("Was backward called?");
+        val.value().clone()
+    }
+}
@@ -1,17 +1,73 @@
 use crate::tensor::Raw;
 use crate::tensor::TensorType;
 use enum_dispatch::enum_dispatch;
+use ndarray::{Array1, ArrayD, Ix2, IxDyn};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 
 #[enum_dispatch(OneValuedFn<T>)]
+#[derive(Clone)]
 pub enum OneValuedFnEnum<'d, T: TensorType<'d> + Clone> {
     ExpM(ExpM<'d, T>),
+    Floor,
+    Ceil,
+    Round,
+    StraightThrough,
+    Transpose,
+    Tile,
+    BroadcastTo,
+    Slice,
+    Gather,
+    BatchNorm(BatchNorm<'d, T>),
+    SoftmaxCrossEntropy(SoftmaxCrossEntropy<'d, T>),
+    HardSigmoid(HardSigmoid<'d, T>),
+    HardTanh(HardTanh<'d, T>),
+    Elu(Elu<'d, T>),
+    Silu(Silu<'d, T>),
+    NllLoss(NllLoss<'d, T>),
+    LogSoftmax(LogSoftmax<'d, T>),
+    CumSum(CumSum),
+    MaskedFill(MaskedFill),
+    Rsqrt(Rsqrt<'d, T>),
+    Softmax(Softmax<'d, T>),
+    Scale(Scale),
+    WidthScale(WidthScale),
+    SpectralNorm(SpectralNorm<'d, T>),
+    FroNorm(FroNorm<'d, T>),
+    ExpmSo3(ExpmSo3<'d, T>),
+    QuatExp(QuatExp<'d, T>),
+    QuatLog(QuatLog<'d, T>),
+    RotationLog(RotationLog<'d, T>),
+    CustomOne(CustomOne<'d, T>),
+    SumAxis(SumAxis),
+    Reshape(Reshape),
+    Pad(Pad),
+    Abs2(Abs2<'d, T>),
+    ConjTranspose(ConjTranspose),
+    AbsMax(AbsMax),
+    GradReverse(GradReverse),
+    Alias(Alias),
 }
 
 #[enum_dispatch(TwoValuedFn<T>)]
+#[derive(Clone)]
 pub enum TwoValuedFnEnum<'d, T: TensorType<'d> + Clone> {
     Add,
     Mul(Mul<'d, T>),
     MatMul(MatMul<'d, T>),
+    Maximum(Maximum<'d, T>),
+    Minimum(Minimum<'d, T>),
+    Dot(Dot<'d, T>),
+    Outer(Outer<'d, T>),
+    BceLoss(BceLoss<'d, T>),
+    BceWithLogits(BceWithLogits<'d, T>),
+    Stack(Stack),
+    RmsNorm(RmsNorm<'d, T>),
+    Kron(Kron<'d, T>),
+    Commutator(Commutator<'d, T>),
+    CustomTwo(CustomTwo<'d, T>),
+    Compare(Compare<'d, T>),
+    TensorDot(TensorDot<'d, T>),
 }
 
 ///
@@ -20,41 +76,221 @@ pub enum TwoValuedFnEnum<'d, T: TensorType<'d> + Clone> {
 /// - Single Valued e.g.: x.sin()
 /// - Double Valued e.g.: x + y
 ///
+#[derive(Clone)]
 pub enum Function<'d, T: TensorType<'d> + Clone> {
     None,
     One(OneValuedFnEnum<'d, T>),
     Two(TwoValuedFnEnum<'d, T>),
 }
 
+impl<'d, T: TensorType<'d> + Clone> Function<'d, T> {
+    ///
+    /// A short name for the function kind, e.g. "Mul"
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::None => "Leaf",
+            Function::One(f) => f.name(),
+            Function::Two(f) => f.name(),
+        }
+    }
+
+    ///
+    /// Whether the output depends only on the inputs, so that a value computed from
+    /// unchanged inputs can be reused
+    ///
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Function::None => true,
+            Function::One(f) => f.is_pure(),
+            Function::Two(f) => f.is_pure(),
+        }
+    }
+
+    ///
+    /// See `OneValuedFn::detach`
+    ///
+    pub fn detach(&mut self) {
+        match self {
+            Function::None => {}
+            Function::One(f) => f.detach(),
+            Function::Two(f) => f.detach(),
+        }
+    }
+}
+
+///
+/// Strip the module path and generic parameters off a type name
+///
+fn short_type_name<F: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<F>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
 #[enum_dispatch]
 pub trait OneValuedFn<'d, T: TensorType<'d>> {
     fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T>;
     fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2];
+    fn name(&self) -> &'static str {
+        short_type_name::<Self>()
+    }
+    /// Whether the output depends only on the inputs. Impure ops are rerun on every forward
+    /// and never constant folded
+    fn is_pure(&self) -> bool {
+        true
+    }
+    /// Forget the buffers saved by the last forward and take a private copy of any state
+    /// shared with other ops, so that a `Graph::deep_clone` copy owns everything it uses
+    fn detach(&mut self) {}
+    /// A copy of the op, needed to `Graph::deep_clone` a graph holding it as a custom op
+    fn boxed_clone(&self) -> Option<Box<dyn OneValuedFn<'d, T> + 'd>> {
+        None
+    }
 }
 
 #[enum_dispatch]
 pub trait TwoValuedFn<'d, T: TensorType<'d>> {
     fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T>;
     fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2];
+    fn name(&self) -> &'static str {
+        short_type_name::<Self>()
+    }
+    /// Whether the output depends only on the inputs. Impure ops are rerun on every forward
+    /// and never constant folded
+    fn is_pure(&self) -> bool {
+        true
+    }
+    /// Forget the buffers saved by the last forward and take a private copy of any state
+    /// shared with other ops, so that a `Graph::deep_clone` copy owns everything it uses
+    fn detach(&mut self) {}
+    /// A copy of the op, needed to `Graph::deep_clone` a graph holding it as a custom op
+    fn boxed_clone(&self) -> Option<Box<dyn TwoValuedFn<'d, T> + 'd>> {
+        None
+    }
+}
+
+///
+/// A user-defined single input op registered through `Graph::apply_one`. Copies made by
+/// `Graph::deep_clone` hold their own copy of the op, see `OneValuedFn::boxed_clone`
+///
+#[derive(Clone)]
+pub struct CustomOne<'d, T: 'd + TensorType<'d>>(pub Rc<RefCell<Box<dyn OneValuedFn<'d, T> + 'd>>>);
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for CustomOne<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        self.0.borrow_mut().forward(t_a)
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        self.0.borrow().backward(grad)
+    }
+    fn name(&self) -> &'static str {
+        self.0.borrow().name()
+    }
+    /// User code may read state that changes between passes, so it is never reused
+    fn is_pure(&self) -> bool {
+        false
+    }
+    fn detach(&mut self) {
+        let mut op = self.0.borrow().boxed_clone().unwrap_or_else(|| {
+            panic!(
+                "custom op {} needs `boxed_clone` to be deep cloned",
+                self.name()
+            )
+        });
+        op.detach();
+        self.0 = Rc::new(RefCell::new(op));
+    }
+}
+
+///
+/// A user-defined two input op registered through `Graph::apply_two`
+///
+#[derive(Clone)]
+pub struct CustomTwo<'d, T: 'd + TensorType<'d>>(pub Rc<RefCell<Box<dyn TwoValuedFn<'d, T> + 'd>>>);
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for CustomTwo<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        self.0.borrow_mut().forward(t_a, t_b)
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        self.0.borrow().backward(grad)
+    }
+    fn name(&self) -> &'static str {
+        self.0.borrow().name()
+    }
+    /// User code may read state that changes between passes, so it is never reused
+    fn is_pure(&self) -> bool {
+        false
+    }
+    fn detach(&mut self) {
+        let mut op = self.0.borrow().boxed_clone().unwrap_or_else(|| {
+            panic!(
+                "custom op {} needs `boxed_clone` to be deep cloned",
+                self.name()
+            )
+        });
+        op.detach();
+        self.0 = Rc::new(RefCell::new(op));
+    }
 }
 
 ///
 /// Add two tensors together element-wise
 ///
-pub struct Add;
+#[derive(Clone)]
+pub struct Add {
+    pub in_shapes: Option<[Vec<usize>; 2]>,
+}
 impl<'d, T: 'd + TensorType<'d>> TwoValuedFn<'d, T> for Add {
     fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        self.in_shapes = Some([t_a.value().shape(), t_b.value().shape()]);
         let t_c = t_a.value().add(t_b.value());
         Raw::new(t_c)
     }
     fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
-        [Some(grad), Some(grad)]
+        let [a, b] = self.in_shapes.as_ref().unwrap();
+        [reduce_broadcast(grad, a), reduce_broadcast(grad, b)]
+    }
+}
+
+///
+/// The gradient of an operand that may have been broadcast: passed through unchanged if
+/// the shapes match, otherwise summed down with `sum_to`
+///
+fn reduce_broadcast<'d, T: 'd + TensorType<'d>>(
+    grad: Raw<'d, T>,
+    shape: &[usize],
+) -> Option<Raw<'d, T>> {
+    if grad.value().shape() == shape {
+        Some(grad)
+    } else {
+        Some(Raw::new(grad.value().sum_to(shape)))
     }
 }
 
+///
+/// Run a computation without a backend kernel on a CPU copy of `x`, placing the result on
+/// the device of `x`
+///
+fn on_cpu<'d, T: TensorType<'d>>(x: &'d T, f: impl FnOnce(&ArrayD<f32>) -> ArrayD<f32>) -> T {
+    x.upload_like(f(&x.get_value_cpu()))
+}
+
+///
+/// Panic with both shapes unless they broadcast together (trailing axes equal or 1)
+///
+pub(crate) fn assert_broadcastable(op: &str, a: &[usize], b: &[usize]) {
+    let ok = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .all(|(&x, &y)| x == y || x == 1 || y == 1);
+    assert!(ok, "cannot {} tensors of shapes {:?} and {:?}", op, a, b);
+}
+
 ///
 /// Multiply two tensors element-wise
 ///
+#[derive(Clone)]
 pub struct Mul<'d, T: 'd + TensorType<'d>> {
     pub x_ctx: Option<Raw<'d, T>>,
     pub y_ctx: Option<Raw<'d, T>>,
@@ -63,6 +299,7 @@ impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Mul<'d, T> {
     fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
         self.x_ctx = Some(t_a);
         self.y_ctx = Some(t_b);
+        assert_broadcastable("multiply", &t_a.value().shape(), &t_b.value().shape());
         let t_c = t_a.value().mul(t_b.value());
         Raw::new(t_c)
     }
@@ -70,10 +307,17 @@ impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Mul<'d, T> {
         let x_ctx = self.x_ctx.unwrap();
         let y_ctx = self.y_ctx.unwrap();
 
-        let a = y_ctx.value().mul(grad.value());
-        let b = x_ctx.value().mul(grad.value());
+        let a = Raw::new(grad.value().mul(y_ctx.value()));
+        let b = Raw::new(grad.value().mul(x_ctx.value()));
 
-        [Some(Raw::new(a)), Some(Raw::new(b))]
+        [
+            reduce_broadcast(a, &x_ctx.value().shape()),
+            reduce_broadcast(b, &y_ctx.value().shape()),
+        ]
+    }
+    fn detach(&mut self) {
+        self.x_ctx = None;
+        self.y_ctx = None;
     }
 }
 
@@ -81,6 +325,7 @@ impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Mul<'d, T> {
 /// Perform a matrix product (only on 2-D)
 /// TODO: support various dimensions
 ///
+#[derive(Clone)]
 pub struct MatMul<'d, T: 'd + TensorType<'d>> {
     pub x_ctx: Option<Raw<'d, T>>,
     pub y_ctx: Option<Raw<'d, T>>,
@@ -94,16 +339,206 @@ impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for MatMul<'d, T> {
         Raw::new(t_c)
     }
     fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
-        let x_ctx = self.x_ctx.unwrap().value().t();
-        let y_ctx = self.y_ctx.unwrap().value().t();
+        let x_ctx = self.x_ctx.unwrap().value();
+        let y_ctx = self.y_ctx.unwrap().value();
 
-        let a = grad.value().matmul(&y_ctx);
-        let b = x_ctx.matmul(grad.value());
+        let a = grad.value().matmul_transposed(y_ctx, false, true);
+        let b = x_ctx.matmul_transposed(grad.value(), true, false);
 
         [Some(Raw::new(a)), Some(Raw::new(b))]
     }
+    fn detach(&mut self) {
+        self.x_ctx = None;
+        self.y_ctx = None;
+    }
+}
+
+///
+/// Contraction over pairs of axes, as numpy's `tensordot`
+///
+/// Each operand is permuted so that its contracted axes are innermost for the left and
+/// outermost for the right, flattened to a matrix, and multiplied; backward is the matmul
+/// backward mapped back through the reshape and permutation
+///
+#[derive(Clone)]
+pub struct TensorDot<'d, T: 'd + TensorType<'d>> {
+    pub axes: (Vec<usize>, Vec<usize>),
+    /// The permutation applied to each operand
+    pub perms: [Vec<usize>; 2],
+    /// Each operand's shape after its permutation
+    pub shapes: [Vec<usize>; 2],
+    /// Each operand as the matrix fed to the product
+    pub mats: [Option<Raw<'d, T>>; 2],
+}
+
+impl<'d, T: TensorType<'d>> TensorDot<'d, T> {
+    ///
+    /// Permute and flatten one operand to a matrix, the contracted axes at the end when
+    /// `contracted_last` and at the start otherwise
+    ///
+    fn flatten_operand(&mut self, side: usize, t: Raw<'d, T>, contracted_last: bool) -> Raw<'d, T> {
+        let shape = t.value().shape();
+        let contracted = if side == 0 {
+            &self.axes.0
+        } else {
+            &self.axes.1
+        };
+        let free: Vec<usize> = (0..shape.len())
+            .filter(|a| !contracted.contains(a))
+            .collect();
+        let perm: Vec<usize> = if contracted_last {
+            free.iter().chain(contracted).cloned().collect()
+        } else {
+            contracted.iter().chain(&free).cloned().collect()
+        };
+
+        let k: usize = contracted.iter().map(|&a| shape[a]).product();
+        let rest: usize = free.iter().map(|&a| shape[a]).product();
+        let dims = if contracted_last {
+            [rest, k]
+        } else {
+            [k, rest]
+        };
+
+        self.shapes[side] = perm.iter().map(|&a| shape[a]).collect();
+        let permuted = Raw::new(t.value().permute(&perm));
+        self.perms[side] = perm;
+        Raw::new(permuted.value().reshape(&dims))
+    }
+
+    ///
+    /// Undo `flatten_operand` on a gradient of the matrix for operand `side`
+    ///
+    fn unflatten_grad(&self, side: usize, grad: Raw<'d, T>) -> Raw<'d, T> {
+        let mut inverse = vec![0; self.perms[side].len()];
+        for (i, &a) in self.perms[side].iter().enumerate() {
+            inverse[a] = i;
+        }
+        let unflat = Raw::new(grad.value().reshape(&self.shapes[side]));
+        Raw::new(unflat.value().permute(&inverse))
+    }
+}
+
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for TensorDot<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        let (sa, sb) = (t_a.value().shape(), t_b.value().shape());
+        for (&i, &j) in self.axes.0.iter().zip(&self.axes.1) {
+            assert!(
+                i < sa.len() && j < sb.len() && sa[i] == sb[j],
+                "tensordot cannot contract axis {} of {:?} with axis {} of {:?}",
+                i,
+                sa,
+                j,
+                sb
+            );
+        }
+
+        let a = self.flatten_operand(0, t_a, true);
+        let b = self.flatten_operand(1, t_b, false);
+        self.mats = [Some(a), Some(b)];
+
+        let n = self.axes.0.len();
+        let out_shape: Vec<usize> = self.shapes[0][..sa.len() - n]
+            .iter()
+            .chain(&self.shapes[1][n..])
+            .cloned()
+            .collect();
+        let c = Raw::new(a.value().matmul(b.value()));
+        Raw::new(c.value().reshape(&out_shape))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let a = self.mats[0].unwrap().value();
+        let b = self.mats[1].unwrap().value();
+        let (m, n) = (a.shape()[0], b.shape()[1]);
+        let g = Raw::new(grad.value().reshape(&[m, n]));
+
+        let da = Raw::new(g.value().matmul_transposed(b, false, true));
+        let db = Raw::new(a.matmul_transposed(g.value(), true, false));
+        [
+            Some(self.unflatten_grad(0, da)),
+            Some(self.unflatten_grad(1, db)),
+        ]
+    }
+    fn detach(&mut self) {
+        self.mats = [None, None];
+    }
+}
+
+///
+/// Panic with a readable message unless `shape` is a square matrix, or a batch `[B, N, N]`
+/// of them
+///
+pub(crate) fn assert_square(shape: &[usize]) {
+    let n = shape.len();
+    assert!(
+        (n == 2 || n == 3) && shape[n - 2] == shape[n - 1],
+        "matrix exponential requires a square matrix, got {:?}",
+        shape
+    );
+}
+
+///
+/// The exponential of a single (diagonal) matrix
+///
+fn expm_matrix<'d, T: TensorType<'d>>(a: &'d T) -> T {
+    a.eye_like().mul(&a.expm())
+}
+
+///
+/// The backward pass implements a truncated power series for the derivative of exponential map
+/// of a lie group
+///
+/// https://en.wikipedia.org/wiki/Derivative_of_the_exponential_map
+///
+fn expm_matrix_backward<'d, T: 'd + TensorType<'d> + Clone>(a: &T, res: &T, grad: &T) -> T {
+    let commu = |a: &T, b: &T| bracket(a, b, false);
+
+    let mut p_commu = grad.clone();
+    let mut total = grad.clone();
+
+    let mut factorial: i32 = 1;
+
+    for o in 2..7 {
+        factorial = factorial * o;
+        let factor = if o % 2 == 0 { -1 } else { 1 };
+
+        p_commu = commu(a, &p_commu);
+
+        let mut term = p_commu.clone();
+        term.scale(1.0 / (factor * factorial) as f32);
+        total.add_assign(&term);
+    }
+
+    res.matmul(&total)
+}
+
+///
+/// Apply `f` to each `[N, N]` slice of a `[B, N, N]` batch, with the matching slices of the
+/// `args` batches, and stack the results into a `[B, N, N]` batch
+///
+fn map_batch<'d, T: TensorType<'d>>(args: &[&'d T], f: impl Fn(&[&'d T]) -> T) -> T {
+    let shape = args[0].shape();
+    let (b, n) = (shape[0], shape[1]);
+    let results: Vec<&'d T> = (0..b)
+        .map(|i| {
+            let slices: Vec<&'d T> = args
+                .iter()
+                .map(|x| {
+                    let slice = Raw::new(x.slice_ranges(&[(i, i + 1)]));
+                    Raw::new(slice.value().reshape(&[n, n])).value()
+                })
+                .collect();
+            Raw::new(f(&slices)).value()
+        })
+        .collect();
+    let (first, rest) = results.split_first().expect("expm needs a non-empty batch");
+    first.stack(rest)
 }
 
+///
+/// Matrix exponential of a square matrix `[N, N]`, or of each matrix in a batch `[B, N, N]`
+///
+#[derive(Clone)]
 // TODO: Implement more generic expm
 // https://dl.acm.org/doi/10.1137/S0895479895283409
 pub struct ExpM<'d, T: 'd + TensorType<'d>> {
@@ -114,45 +549,1978 @@ impl<'d, T: TensorType<'d> + Clone> OneValuedFn<'d, T> for ExpM<'d, T> {
     fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
         self.a = Some(t_a);
         let val = t_a.value();
+        let shape = val.shape();
+        assert_square(&shape);
 
-        let eye = val.eye_like();
-        let t_out = eye.mul(&val.expm());
+        let t_out = if shape.len() == 3 {
+            map_batch(&[val], |x| expm_matrix(x[0]))
+        } else {
+            expm_matrix(val)
+        };
         let t_out = Raw::new(t_out);
         self.res = Some(t_out);
         t_out
     }
-    ///
-    /// The backward pass implements a truncated power series for the derivative of exponential map
-    /// of a lie group
-    ///
-    /// https://en.wikipedia.org/wiki/Derivative_of_the_exponential_map
-    ///
     fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
         let a = self.a.unwrap().value();
         let res = self.res.unwrap().value();
         let grad = grad.value();
 
-        let commu = |a: &T, b: &T| a.matmul(b).sub(&b.matmul(a));
+        let da = if a.shape().len() == 3 {
+            map_batch(&[a, res, grad], |x| expm_matrix_backward(x[0], x[1], x[2]))
+        } else {
+            expm_matrix_backward(a, res, grad)
+        };
+        [Some(Raw::new(da)), None]
+    }
+    fn detach(&mut self) {
+        self.a = None;
+        self.res = None;
+    }
+}
+
+///
+/// Round each element down. The function is piecewise constant, so the gradient is zero
+///
+#[derive(Clone)]
+pub struct Floor;
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Floor {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().floor())
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(Raw::new(grad.value().val_like(0.0))), None]
+    }
+}
 
-        let mut p_commu = grad.clone();
-        let mut total = grad.clone();
+///
+/// Round each element up. The function is piecewise constant, so the gradient is zero
+///
+#[derive(Clone)]
+pub struct Ceil;
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Ceil {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().ceil())
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(Raw::new(grad.value().val_like(0.0))), None]
+    }
+}
 
-        let mut factorial: i32 = 1;
+///
+/// Round each element to the nearest integer. The function is piecewise constant, so the
+/// gradient is zero
+///
+#[derive(Clone)]
+pub struct Round;
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Round {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().round())
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(Raw::new(grad.value().val_like(0.0))), None]
+    }
+}
 
-        for o in 2..7 {
-            factorial = factorial * o;
-            let factor = if o % 2 == 0 { -1 } else { 1 };
+///
+/// Non-differentiable element-wise ops which can be wrapped by a straight-through estimator
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Rounding {
+    Floor,
+    Ceil,
+    Round,
+}
 
-            let new_commu = commu(a, &p_commu);
-            p_commu = new_commu.clone();
+///
+/// Element-wise comparison operators
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Comparison {
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    Eq,
+}
 
-            //TODO: create an element-wise operation which does not require creation of another
-            //matrix
-            let fac_mat = a.val_like((factor * factorial) as f32);
-            total = total.add(&new_commu.div(&fac_mat));
+impl Comparison {
+    pub fn holds(self, a: f32, b: f32) -> bool {
+        match self {
+            Comparison::Gt => a > b,
+            Comparison::Lt => a < b,
+            Comparison::Ge => a >= b,
+            Comparison::Le => a <= b,
+            Comparison::Eq => a == b,
         }
+    }
+}
 
-        let a = res.matmul(&total);
-        [Some(Raw::new(a)), None]
+///
+/// Apply a rounding op in the forward pass but pass the gradient through unchanged,
+/// as is common in quantization-aware training
+///
+#[derive(Clone)]
+pub struct StraightThrough {
+    pub op: Rounding,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for StraightThrough {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let val = t_a.value();
+        let t_out = match self.op {
+            Rounding::Floor => val.floor(),
+            Rounding::Ceil => val.ceil(),
+            Rounding::Round => val.round(),
+        };
+        Raw::new(t_out)
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(grad), None]
+    }
+}
+
+///
+/// Route a gradient through a 0/1 mask, sending `grad * mask` to the first operand and
+/// `grad * (1 - mask)` to the second
+///
+fn route_by_mask<'d, T: 'd + TensorType<'d>>(
+    mask: Raw<'d, T>,
+    grad: Raw<'d, T>,
+) -> [Option<Raw<'d, T>>; 2] {
+    let mask = mask.value();
+    let grad = grad.value();
+
+    let a = grad.mul(mask);
+    let b = grad.mul(&mask.ones_like().sub(mask));
+
+    [Some(Raw::new(a)), Some(Raw::new(b))]
+}
+
+///
+/// Element-wise maximum of two tensors
+///
+/// The mask records where the first operand won, so the gradient can be routed back
+///
+#[derive(Clone)]
+pub struct Maximum<'d, T: 'd + TensorType<'d>> {
+    pub mask: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Maximum<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        let a = t_a.value();
+        let b = t_b.value();
+
+        let mask = Raw::new(a.ge(b));
+        self.mask = Some(mask);
+
+        Raw::new(a.select(mask.value(), b))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        route_by_mask(self.mask.unwrap(), grad)
+    }
+    fn detach(&mut self) {
+        self.mask = None;
+    }
+}
+
+///
+/// Element-wise minimum of two tensors
+///
+/// The mask records where the first operand won, so the gradient can be routed back
+///
+#[derive(Clone)]
+pub struct Minimum<'d, T: 'd + TensorType<'d>> {
+    pub mask: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Minimum<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        let a = t_a.value();
+        let b = t_b.value();
+
+        let mask = Raw::new(b.ge(a));
+        self.mask = Some(mask);
+
+        Raw::new(a.select(mask.value(), b))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        route_by_mask(self.mask.unwrap(), grad)
+    }
+    fn detach(&mut self) {
+        self.mask = None;
+    }
+}
+
+///
+/// Swap the two axes of a matrix
+///
+#[derive(Clone)]
+pub struct Transpose;
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Transpose {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().t())
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(Raw::new(grad.value().t())), None]
+    }
+}
+
+///
+/// Full contraction of two equally shaped tensors into a scalar, i.e. `sum(a * b)`
+///
+#[derive(Clone)]
+pub struct Dot<'d, T: 'd + TensorType<'d>> {
+    pub x_ctx: Option<Raw<'d, T>>,
+    pub y_ctx: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Dot<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        self.x_ctx = Some(t_a);
+        self.y_ctx = Some(t_b);
+
+        let prod = Raw::new(t_a.value().mul(t_b.value()));
+        Raw::new(prod.value().sum())
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let x_ctx = self.x_ctx.unwrap();
+        let y_ctx = self.y_ctx.unwrap();
+
+        // The scalar gradient broadcasts over the other operand
+        let a = y_ctx.value().mul(grad.value());
+        let b = x_ctx.value().mul(grad.value());
+
+        [Some(Raw::new(a)), Some(Raw::new(b))]
+    }
+    fn detach(&mut self) {
+        self.x_ctx = None;
+        self.y_ctx = None;
+    }
+}
+
+///
+/// Outer product of two vectors
+///
+#[derive(Clone)]
+pub struct Outer<'d, T: 'd + TensorType<'d>> {
+    pub x_ctx: Option<Raw<'d, T>>,
+    pub y_ctx: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Outer<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        self.x_ctx = Some(t_a);
+        self.y_ctx = Some(t_b);
+
+        let a = t_a.value();
+        let b = t_b.value();
+        let n = a.shape()[0];
+        let m = b.shape()[0];
+
+        let t_c = a.reshape(&[n, 1]).matmul(&b.reshape(&[1, m]));
+        Raw::new(t_c)
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let x = self.x_ctx.unwrap().value();
+        let y = self.y_ctx.unwrap().value();
+        let n = x.shape()[0];
+        let m = y.shape()[0];
+
+        let a = Raw::new(grad.value().matmul(&y.reshape(&[m, 1])));
+        let b = Raw::new(x.reshape(&[1, n]).matmul(grad.value()));
+
+        [
+            Some(Raw::new(a.value().reshape(&[n]))),
+            Some(Raw::new(b.value().reshape(&[m]))),
+        ]
+    }
+    fn detach(&mut self) {
+        self.x_ctx = None;
+        self.y_ctx = None;
+    }
+}
+
+///
+/// The einsum patterns understood by `Tensor::einsum`
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EinsumSpec {
+    MatMul,
+    Transpose,
+    Dot,
+    Outer,
+}
+
+impl EinsumSpec {
+    ///
+    /// Classify a spec such as `"ij,jk->ik"` by its index structure
+    ///
+    pub fn parse(spec: &str) -> Self {
+        let compact: String = spec.chars().filter(|c| !c.is_whitespace()).collect();
+        let (lhs, out) = compact
+            .split_once("->")
+            .unwrap_or_else(|| panic!("einsum spec {:?} is missing '->'", spec));
+        let ins: Vec<Vec<char>> = lhs.split(',').map(|x| x.chars().collect()).collect();
+        let out: Vec<char> = out.chars().collect();
+
+        match ins.as_slice() {
+            [a] if a.len() == 2 && a[0] != a[1] && out == [a[1], a[0]] => EinsumSpec::Transpose,
+            [a, b]
+                if a.len() == 2
+                    && b.len() == 2
+                    && a[1] == b[0]
+                    && a[0] != a[1]
+                    && b[0] != b[1]
+                    && a[0] != b[1]
+                    && out == [a[0], b[1]] =>
+            {
+                EinsumSpec::MatMul
+            }
+            [a, b] if a == b && !a.is_empty() && out.is_empty() => EinsumSpec::Dot,
+            [a, b] if a.len() == 1 && b.len() == 1 && a[0] != b[0] && out == [a[0], b[0]] => {
+                EinsumSpec::Outer
+            }
+            _ => panic!("Unsupported einsum spec {:?}", spec),
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        match self {
+            EinsumSpec::Transpose => 1,
+            _ => 2,
+        }
+    }
+}
+
+///
+/// Repeat a tensor along each axis. The backward pass sums the gradient over the copies
+///
+#[derive(Clone)]
+pub struct Tile {
+    pub reps: Vec<usize>,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Tile {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().tile(&self.reps))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let grad = grad.value();
+
+        // Split each axis of length r*n into (r, n) and sum over the r's
+        let mut split = Vec::new();
+        for (n, r) in grad.shape().iter().zip(&self.reps) {
+            split.push(*r);
+            split.push(n / r);
+        }
+        let copies: Vec<usize> = (0..self.reps.len()).map(|i| 2 * i).collect();
+
+        let grad = Raw::new(grad.reshape(&split));
+        [Some(Raw::new(grad.value().sum_axes(&copies))), None]
+    }
+}
+
+///
+/// Expand a tensor to a larger shape. The backward pass sums the gradient over every
+/// broadcasted axis so it matches the input shape again
+///
+#[derive(Clone)]
+pub struct BroadcastTo {
+    pub shape: Vec<usize>,
+    pub in_shape: Option<Vec<usize>>,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for BroadcastTo {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let val = t_a.value();
+        self.in_shape = Some(val.shape());
+        Raw::new(val.broadcast_to(&self.shape))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let in_shape = self.in_shape.as_ref().unwrap();
+        [Some(Raw::new(grad.value().sum_to(in_shape))), None]
+    }
+}
+
+///
+/// Extract a contiguous sub-region. The backward pass scatters the gradient into zeros
+/// of the input shape
+///
+#[derive(Clone)]
+pub struct Slice {
+    pub ranges: Vec<(usize, usize)>,
+    pub in_shape: Option<Vec<usize>>,
+}
+impl Slice {
+    ///
+    /// The ranges for an input of `shape`, with an end of `usize::MAX` running to the end of
+    /// the axis
+    ///
+    fn resolve(&self, shape: &[usize]) -> Vec<(usize, usize)> {
+        self.ranges
+            .iter()
+            .enumerate()
+            .map(|(ax, &(start, end))| (start, if end == usize::MAX { shape[ax] } else { end }))
+            .collect()
+    }
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Slice {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let val = t_a.value();
+        let in_shape = val.shape();
+        let ranges = self.resolve(&in_shape);
+        self.in_shape = Some(in_shape);
+        Raw::new(val.slice_ranges(&ranges))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let in_shape = self.in_shape.as_ref().unwrap();
+        let a = grad
+            .value()
+            .scatter_ranges(in_shape, &self.resolve(in_shape));
+        [Some(Raw::new(a)), None]
+    }
+}
+
+///
+/// Select entries along an axis by index. The backward pass scatters the gradient back to
+/// the selected positions, accumulating over duplicate indices
+///
+#[derive(Clone)]
+pub struct Gather {
+    pub axis: usize,
+    pub indices: Vec<usize>,
+    pub in_shape: Option<Vec<usize>>,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Gather {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let val = t_a.value();
+        self.in_shape = Some(val.shape());
+        Raw::new(val.gather(self.axis, &self.indices))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let in_shape = self.in_shape.as_ref().unwrap();
+        let a = grad.value().scatter_add(in_shape, self.axis, &self.indices);
+        [Some(Raw::new(a)), None]
+    }
+}
+
+///
+/// Running statistics and mode shared between an `nn::BatchNorm` layer and the graph nodes
+/// it creates
+///
+pub struct BatchNormStats<'d, T: 'd + TensorType<'d>> {
+    pub training: Cell<bool>,
+    pub momentum: f32,
+    pub eps: f32,
+    pub running_mean: Cell<Option<Raw<'d, T>>>,
+    pub running_var: Cell<Option<Raw<'d, T>>>,
+}
+
+///
+/// Normalize over the batch (first) axis
+///
+/// In training mode the batch statistics are used and folded into the running statistics.
+/// In eval mode the running statistics are used as constants.
+///
+#[derive(Clone)]
+pub struct BatchNorm<'d, T: 'd + TensorType<'d>> {
+    pub stats: Rc<BatchNormStats<'d, T>>,
+    pub x_hat: Option<Raw<'d, T>>,
+    pub inv_std: Option<Raw<'d, T>>,
+    pub batch_stats: bool,
+    /// Flattened running mean and variance to start from, e.g. when rebuilt from a spec,
+    /// moved to the input's device on the first forward
+    pub initial_stats: Option<(Vec<f32>, Vec<f32>)>,
+}
+impl<'d, T: TensorType<'d> + Clone> OneValuedFn<'d, T> for BatchNorm<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = t_a.value();
+        let stats = &self.stats;
+        self.batch_stats = stats.training.get();
+        if let Some((mean, var)) = self.initial_stats.take() {
+            let shape = &x.shape()[1..];
+            let upload = |data: Vec<f32>| {
+                let data = ArrayD::from_shape_vec(IxDyn(shape), data)
+                    .expect("running statistics do not match the input features");
+                Some(Raw::new(x.upload_like(data)))
+            };
+            stats.running_mean.set(upload(mean));
+            stats.running_var.set(upload(var));
+        }
+
+        let (mean, var) = if self.batch_stats {
+            let n = x.shape()[0] as f32;
+            let mean = Raw::new(x.sum_axes(&[0]));
+            let mean = Raw::new(mean.value().mul_scalar(1.0 / n));
+            let centered = x.sub(mean.value());
+            let sq = Raw::new(centered.mul(&centered));
+            let sq_sum = Raw::new(sq.value().sum_axes(&[0]));
+            let var = Raw::new(sq_sum.value().mul_scalar(1.0 / n));
+
+            // Running variance uses the unbiased estimate
+            let m = stats.momentum;
+            let unbiased = Raw::new(sq_sum.value().mul_scalar(1.0 / (n - 1.0).max(1.0)));
+            let (r_mean, r_var) = match (stats.running_mean.get(), stats.running_var.get()) {
+                (Some(r_mean), Some(r_var)) => (r_mean, r_var),
+                _ => (
+                    Raw::new(mean.value().val_like(0.0)),
+                    Raw::new(mean.value().ones_like()),
+                ),
+            };
+            let r_mean = r_mean
+                .value()
+                .mul_scalar(1.0 - m)
+                .add(&mean.value().mul_scalar(m));
+            let r_var = r_var
+                .value()
+                .mul_scalar(1.0 - m)
+                .add(&unbiased.value().mul_scalar(m));
+            stats.running_mean.set(Some(Raw::new(r_mean)));
+            stats.running_var.set(Some(Raw::new(r_var)));
+
+            (mean, var)
+        } else {
+            (
+                stats
+                    .running_mean
+                    .get()
+                    .expect("BatchNorm has no running mean"),
+                stats
+                    .running_var
+                    .get()
+                    .expect("BatchNorm has no running variance"),
+            )
+        };
+
+        // 1 / sqrt(var + eps) = sqrt(1 / (var + eps))
+        let inv_var = Raw::new(var.value().ones_like().div_eps(var.value(), stats.eps));
+        let inv_std = Raw::new(inv_var.value().sqrt());
+        self.inv_std = Some(inv_std);
+
+        let x_hat = Raw::new(x.sub(mean.value()).mul(inv_std.value()));
+        self.x_hat = Some(x_hat);
+        x_hat
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let grad = grad.value();
+        let inv_std = self.inv_std.unwrap().value();
+
+        if !self.batch_stats {
+            return [Some(Raw::new(grad.mul(inv_std))), None];
+        }
+
+        // dx = inv_std * (g - mean(g) - x_hat * mean(g * x_hat))
+        let x_hat = self.x_hat.unwrap().value();
+        let n = grad.shape()[0] as f32;
+
+        let g_sum = Raw::new(grad.sum_axes(&[0]));
+        let g_mean = g_sum.value().mul_scalar(1.0 / n);
+        let gx_sum = Raw::new(grad.mul(x_hat));
+        let gx_sum = Raw::new(gx_sum.value().sum_axes(&[0]));
+        let gx_mean = gx_sum.value().mul_scalar(1.0 / n);
+
+        let a = grad.sub(&g_mean).sub(&x_hat.mul(&gx_mean)).mul(inv_std);
+        [Some(Raw::new(a)), None]
+    }
+    /// The output depends on the train/eval mode and training updates the running statistics
+    fn is_pure(&self) -> bool {
+        false
+    }
+    /// The copy no longer follows the layer's mode, and keeps running statistics of its own
+    fn detach(&mut self) {
+        let copy = |stat: &Cell<Option<Raw<'d, T>>>| {
+            Cell::new(stat.get().map(|s| Raw::new(s.value().clone())))
+        };
+        self.stats = Rc::new(BatchNormStats {
+            training: Cell::new(self.stats.training.get()),
+            momentum: self.stats.momentum,
+            eps: self.stats.eps,
+            running_mean: copy(&self.stats.running_mean),
+            running_var: copy(&self.stats.running_var),
+        });
+        self.x_hat = None;
+        self.inv_std = None;
+    }
+}
+
+///
+/// Mean cross-entropy of row-wise softmax probabilities against integer class targets
+///
+/// The loss is computed from a log-sum-exp, and the backward pass is `(softmax - onehot) / N`
+///
+#[derive(Clone)]
+pub struct SoftmaxCrossEntropy<'d, T: 'd + TensorType<'d>> {
+    pub targets: Vec<usize>,
+    pub softmax: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for SoftmaxCrossEntropy<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let logits = t_a.value();
+        let n = logits.shape()[0];
+        assert_eq!(n, self.targets.len(), "Expected one target per row");
+
+        let log_p = logits.log_softmax(1);
+        self.softmax = Some(Raw::new(logits.softmax(1)));
+
+        let picked = Raw::new(log_p.mul(&logits.one_hot(&self.targets)));
+        let total = Raw::new(picked.value().sum());
+        Raw::new(total.value().mul_scalar(-1.0 / n as f32))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let softmax = self.softmax.unwrap().value();
+        let n = self.targets.len() as f32;
+
+        let diff = Raw::new(
+            softmax
+                .sub(&softmax.one_hot(&self.targets))
+                .mul(grad.value()),
+        );
+        [Some(Raw::new(diff.value().mul_scalar(1.0 / n))), None]
+    }
+    fn detach(&mut self) {
+        self.softmax = None;
+    }
+}
+
+///
+/// Piecewise-linear approximation of the sigmoid, `clamp(x / 6 + 1 / 2, 0, 1)`
+///
+/// The mask selects the linear region (-3, 3), outside of which the gradient is zero
+///
+#[derive(Clone)]
+pub struct HardSigmoid<'d, T: 'd + TensorType<'d>> {
+    pub mask: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for HardSigmoid<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let val = t_a.value();
+        self.mask = Some(Raw::new(val.in_range(-3.0, 3.0)));
+
+        let linear = Raw::new(val.mul_scalar(1.0 / 6.0));
+        let linear = Raw::new(linear.value().add_scalar(0.5));
+        Raw::new(linear.value().clamp(0.0, 1.0))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let masked = Raw::new(grad.value().mul(self.mask.unwrap().value()));
+        [Some(Raw::new(masked.value().mul_scalar(1.0 / 6.0))), None]
+    }
+    fn detach(&mut self) {
+        self.mask = None;
+    }
+}
+
+///
+/// Piecewise-linear approximation of tanh, `clamp(x, -1, 1)`
+///
+/// The mask selects the linear region (-1, 1), outside of which the gradient is zero
+///
+#[derive(Clone)]
+pub struct HardTanh<'d, T: 'd + TensorType<'d>> {
+    pub mask: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for HardTanh<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let val = t_a.value();
+        self.mask = Some(Raw::new(val.in_range(-1.0, 1.0)));
+        Raw::new(val.clamp(-1.0, 1.0))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let a = grad.value().mul(self.mask.unwrap().value());
+        [Some(Raw::new(a)), None]
+    }
+    fn detach(&mut self) {
+        self.mask = None;
+    }
+}
+
+///
+/// Exponential linear unit, `x` for `x >= 0` and `alpha * (exp(x) - 1)` otherwise
+///
+/// The derivative (`1` or `alpha * exp(x)`) is cached in forward
+///
+#[derive(Clone)]
+pub struct Elu<'d, T: 'd + TensorType<'d>> {
+    pub alpha: f32,
+    pub deriv: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for Elu<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = t_a.value();
+
+        let pos = Raw::new(x.ge(&x.val_like(0.0)));
+        let pos = pos.value();
+
+        // Only the negative branch needs exp, so keep large positive inputs from overflowing
+        let exp = Raw::new(x.clamp(f32::NEG_INFINITY, 0.0).expm());
+        let scaled_exp = Raw::new(exp.value().mul_scalar(self.alpha));
+        let scaled_exp = scaled_exp.value();
+
+        let deriv = pos.select(pos, scaled_exp);
+        self.deriv = Some(Raw::new(deriv));
+
+        let neg_branch = Raw::new(scaled_exp.add_scalar(-self.alpha));
+        Raw::new(x.select(pos, neg_branch.value()))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let a = grad.value().mul(self.deriv.unwrap().value());
+        [Some(Raw::new(a)), None]
+    }
+    fn detach(&mut self) {
+        self.deriv = None;
+    }
+}
+
+///
+/// Sigmoid linear unit `x * sigmoid(x)`, with derivative `s * (1 + x * (1 - s))`
+///
+#[derive(Clone)]
+pub struct Silu<'d, T: 'd + TensorType<'d>> {
+    pub x: Option<Raw<'d, T>>,
+    pub sigmoid: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for Silu<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = t_a.value();
+        let s = Raw::new(x.sigmoid());
+        self.x = Some(t_a);
+        self.sigmoid = Some(s);
+        Raw::new(x.mul(s.value()))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let x = self.x.unwrap().value();
+        let s = self.sigmoid.unwrap().value();
+
+        let one_minus_s = s.ones_like().sub(s);
+        let inner = Raw::new(x.mul(&one_minus_s));
+        let deriv = s.mul(&inner.value().add_scalar(1.0));
+
+        [Some(Raw::new(grad.value().mul(&deriv))), None]
+    }
+    fn detach(&mut self) {
+        self.x = None;
+        self.sigmoid = None;
+    }
+}
+
+///
+/// Mean negative log-likelihood of row-wise log-probabilities at integer class targets
+///
+/// The backward pass places `-1 / N` at each target position
+///
+#[derive(Clone)]
+pub struct NllLoss<'d, T: 'd + TensorType<'d>> {
+    pub targets: Vec<usize>,
+    pub x_ctx: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for NllLoss<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let log_p = t_a.value();
+        let n = log_p.shape()[0];
+        assert_eq!(n, self.targets.len(), "Expected one target per row");
+        self.x_ctx = Some(t_a);
+
+        let picked = Raw::new(log_p.mul(&log_p.one_hot(&self.targets)));
+        let total = Raw::new(picked.value().sum());
+        Raw::new(total.value().mul_scalar(-1.0 / n as f32))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let x = self.x_ctx.unwrap().value();
+        let n = self.targets.len() as f32;
+
+        let placed = Raw::new(x.one_hot(&self.targets).mul(grad.value()));
+        [Some(Raw::new(placed.value().mul_scalar(-1.0 / n))), None]
+    }
+    fn detach(&mut self) {
+        self.x_ctx = None;
+    }
+}
+
+///
+/// Sum `x` along `axis`, keeping the axis with length 1 so the result broadcasts against `x`
+///
+fn sum_keepdims<'d, T: 'd + TensorType<'d>>(x: &'d T, axis: usize) -> T {
+    let mut shape = x.shape();
+    shape[axis] = 1;
+    let summed = Raw::new(x.sum_axes(&[axis]));
+    summed.value().reshape(&shape)
+}
+
+///
+/// Log-softmax along an axis, `x - logsumexp(x)`
+///
+/// The backward pass is `grad - softmax(x) * sum(grad)` along the axis
+///
+#[derive(Clone)]
+pub struct LogSoftmax<'d, T: 'd + TensorType<'d>> {
+    pub axis: usize,
+    /// The logits are divided by this before normalizing
+    pub temperature: f32,
+    pub softmax: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for LogSoftmax<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = with_temperature(t_a, self.temperature).value();
+        self.softmax = Some(Raw::new(x.softmax(self.axis)));
+        Raw::new(x.log_softmax(self.axis))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let grad = grad.value();
+        let softmax = self.softmax.unwrap().value();
+
+        let grad_sum = sum_keepdims(grad, self.axis);
+        let a = Raw::new(grad.sub(&softmax.mul(&grad_sum)));
+        [Some(with_temperature(a, self.temperature)), None]
+    }
+    fn detach(&mut self) {
+        self.softmax = None;
+    }
+}
+
+///
+/// `x / temperature`, passing `x` through untouched at the default temperature of one
+///
+fn with_temperature<'d, T: 'd + TensorType<'d>>(x: Raw<'d, T>, temperature: f32) -> Raw<'d, T> {
+    if temperature == 1.0 {
+        x
+    } else {
+        Raw::new(x.value().mul_scalar(1.0 / temperature))
+    }
+}
+
+///
+/// Mean over all elements, returned as a scalar
+///
+fn mean_all<'d, T: 'd + TensorType<'d>>(x: &'d T) -> T {
+    let n: usize = x.shape().iter().product();
+    let total = Raw::new(x.sum());
+    total.value().mul_scalar(1.0 / n as f32)
+}
+
+///
+/// Binary cross-entropy on probabilities, `-mean(t * ln(p) + (1 - t) * ln(1 - p))`
+///
+/// The backward pass is `(p - t) / (p * (1 - p) * N)`; the target gets no gradient
+///
+#[derive(Clone)]
+pub struct BceLoss<'d, T: 'd + TensorType<'d>> {
+    pub p: Option<Raw<'d, T>>,
+    pub t: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for BceLoss<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        self.p = Some(t_a);
+        self.t = Some(t_b);
+        let p = t_a.value();
+        let t = t_b.value();
+
+        let one_minus_p = Raw::new(p.ones_like().sub(p));
+        let one_minus_t = t.ones_like().sub(t);
+        let ll = t
+            .mul(&p.ln())
+            .add(&one_minus_t.mul(&one_minus_p.value().ln()));
+        let ll = Raw::new(ll);
+        let mean = Raw::new(mean_all(ll.value()));
+        Raw::new(mean.value().mul_scalar(-1.0))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let p = self.p.unwrap().value();
+        let t = self.t.unwrap().value();
+        let n: usize = p.shape().iter().product();
+
+        let denom = Raw::new(p.mul(&p.ones_like().sub(p)));
+        let denom = denom.value().mul_scalar(n as f32);
+        let a = p.sub(t).div(&denom).mul(grad.value());
+
+        [Some(Raw::new(a)), None]
+    }
+    fn detach(&mut self) {
+        self.p = None;
+        self.t = None;
+    }
+}
+
+///
+/// Binary cross-entropy on logits, `mean(softplus(x) - x * t)`
+///
+/// The backward pass is `(sigmoid(x) - t) / N`; the target gets no gradient
+///
+#[derive(Clone)]
+pub struct BceWithLogits<'d, T: 'd + TensorType<'d>> {
+    pub x: Option<Raw<'d, T>>,
+    pub t: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for BceWithLogits<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        self.x = Some(t_a);
+        self.t = Some(t_b);
+        let x = t_a.value();
+        let t = t_b.value();
+
+        let loss = Raw::new(x.softplus().sub(&x.mul(t)));
+        Raw::new(mean_all(loss.value()))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let x = self.x.unwrap().value();
+        let t = self.t.unwrap().value();
+        let n: usize = x.shape().iter().product();
+
+        let diff = Raw::new(x.sigmoid().sub(t).mul(grad.value()));
+        [
+            Some(Raw::new(diff.value().mul_scalar(1.0 / n as f32))),
+            None,
+        ]
+    }
+    fn detach(&mut self) {
+        self.x = None;
+        self.t = None;
+    }
+}
+
+///
+/// Prefix sum along an axis. The gradient is the reverse prefix sum of the upstream gradient
+///
+#[derive(Clone)]
+pub struct CumSum {
+    pub axis: usize,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for CumSum {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().cumsum(self.axis))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let grad = grad.value();
+
+        // sum_{j >= i} g_j = total - cumsum(g)_i + g_i
+        let total = Raw::new(sum_keepdims(grad, self.axis));
+        let a = grad.sub(&grad.cumsum(self.axis)).add(total.value());
+        [Some(Raw::new(a)), None]
+    }
+}
+
+///
+/// Stack two equally shaped tensors along a new leading axis
+///
+#[derive(Clone)]
+pub struct Stack {
+    pub in_shape: Option<Vec<usize>>,
+}
+impl<'d, T: 'd + TensorType<'d>> TwoValuedFn<'d, T> for Stack {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        let (a, b) = (t_a.value(), t_b.value());
+        assert_eq!(a.shape(), b.shape(), "stack requires equal shapes");
+        self.in_shape = Some(a.shape());
+        Raw::new(a.stack(&[b]))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let grad = grad.value();
+        let in_shape = self.in_shape.as_ref().unwrap();
+
+        let half = |i: usize| {
+            let part = Raw::new(grad.slice_ranges(&[(i, i + 1)]));
+            Some(Raw::new(part.value().reshape(in_shape)))
+        };
+        [half(0), half(1)]
+    }
+}
+
+///
+/// Overwrite the positions where `mask` is true with `value`; those positions get no gradient
+///
+#[derive(Clone)]
+pub struct MaskedFill {
+    pub mask: ndarray::ArrayD<bool>,
+    pub value: f32,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for MaskedFill {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().masked_fill(&self.mask, self.value))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let a = grad.value().masked_fill(&self.mask, 0.0);
+        [Some(Raw::new(a)), None]
+    }
+}
+
+///
+/// Reciprocal square root `r = 1 / sqrt(x)`, with derivative `-0.5 * r^3`
+///
+#[derive(Clone)]
+pub struct Rsqrt<'d, T: 'd + TensorType<'d>> {
+    pub res: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for Rsqrt<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = t_a.value();
+        let sqrt = Raw::new(x.sqrt());
+        let r = Raw::new(x.ones_like().div(sqrt.value()));
+        self.res = Some(r);
+        r
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let r = self.res.unwrap().value();
+
+        let cube = Raw::new(r.mul(r).mul(r));
+        let a = cube.value().mul_scalar(-0.5).mul(grad.value());
+        [Some(Raw::new(a)), None]
+    }
+    fn detach(&mut self) {
+        self.res = None;
+    }
+}
+
+///
+/// RMS normalization over the last axis with a learned scale, `x * rsqrt(mean(x^2) + eps) * w`
+///
+#[derive(Clone)]
+pub struct RmsNorm<'d, T: 'd + TensorType<'d>> {
+    pub eps: f32,
+    pub x_hat: Option<Raw<'d, T>>,
+    pub inv_rms: Option<Raw<'d, T>>,
+    pub weight: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for RmsNorm<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        let x = t_a.value();
+        let axis = x.shape().len() - 1;
+        let dim = x.shape()[axis];
+
+        let sq = Raw::new(x.mul(x));
+        let ms = Raw::new(sum_keepdims(sq.value(), axis));
+        let ms = Raw::new(ms.value().mul_scalar(1.0 / dim as f32));
+        let inv_ms = Raw::new(ms.value().ones_like().div_eps(ms.value(), self.eps));
+        let inv_rms = Raw::new(inv_ms.value().sqrt());
+        let x_hat = Raw::new(x.mul(inv_rms.value()));
+
+        self.x_hat = Some(x_hat);
+        self.inv_rms = Some(inv_rms);
+        self.weight = Some(t_b);
+        Raw::new(x_hat.value().mul(t_b.value()))
+    }
+    ///
+    /// With `g = grad * w`: `dx = inv_rms * (g - x_hat * mean(g * x_hat))` along the last
+    /// axis, and `dw` is `grad * x_hat` summed over every other axis
+    ///
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let grad = grad.value();
+        let x_hat = self.x_hat.unwrap().value();
+        let inv_rms = self.inv_rms.unwrap().value();
+        let weight = self.weight.unwrap().value();
+        let axis = x_hat.shape().len() - 1;
+        let dim = x_hat.shape()[axis];
+
+        let g = Raw::new(grad.mul(weight));
+        let gx = Raw::new(g.value().mul(x_hat));
+        let proj = Raw::new(sum_keepdims(gx.value(), axis));
+        let proj = Raw::new(proj.value().mul_scalar(1.0 / dim as f32));
+        let centered = Raw::new(g.value().sub(&x_hat.mul(proj.value())));
+        let a = centered.value().mul(inv_rms);
+
+        let leading: Vec<usize> = (0..axis).collect();
+        let b = Raw::new(grad.mul(x_hat)).value().sum_axes(&leading);
+
+        [Some(Raw::new(a)), Some(Raw::new(b))]
+    }
+    fn detach(&mut self) {
+        self.x_hat = None;
+        self.inv_rms = None;
+        self.weight = None;
+    }
+}
+
+///
+/// Numerically stable softmax along an axis
+///
+#[derive(Clone)]
+pub struct Softmax<'d, T: 'd + TensorType<'d>> {
+    pub axis: usize,
+    /// The logits are divided by this before normalizing
+    pub temperature: f32,
+    pub res: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for Softmax<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = with_temperature(t_a, self.temperature);
+        let y = Raw::new(x.value().softmax(self.axis));
+        self.res = Some(y);
+        y
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let grad = grad.value();
+        let y = self.res.unwrap().value();
+
+        // y * (g - sum(g * y))
+        let gy = Raw::new(grad.mul(y));
+        let dot = Raw::new(sum_keepdims(gy.value(), self.axis));
+        let a = Raw::new(y.mul(&grad.sub(dot.value())));
+        [Some(with_temperature(a, self.temperature)), None]
+    }
+    fn detach(&mut self) {
+        self.res = None;
+    }
+}
+
+///
+/// Multiply by a constant factor
+///
+#[derive(Clone)]
+pub struct Scale {
+    pub factor: f32,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Scale {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().mul_scalar(self.factor))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(Raw::new(grad.value().mul_scalar(self.factor))), None]
+    }
+}
+
+///
+/// Multiply by `1/sqrt(d)`, where `d` is the size of the last axis, as for attention scores.
+/// The factor is taken from the input on each forward
+///
+#[derive(Clone)]
+pub struct WidthScale {
+    pub factor: Option<f32>,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for WidthScale {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let shape = t_a.value().shape();
+        let width = *shape.last().expect("WidthScale needs at least one axis");
+        let factor = 1.0 / (width as f32).sqrt();
+        self.factor = Some(factor);
+        Raw::new(t_a.value().mul_scalar(factor))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let factor = self.factor.unwrap();
+        [Some(Raw::new(grad.value().mul_scalar(factor))), None]
+    }
+}
+
+///
+/// Identity in forward, multiplies the gradient by `-lambda` in backward, the gradient
+/// reversal layer of domain-adversarial training
+///
+#[derive(Clone)]
+pub struct GradReverse {
+    pub lambda: f32,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for GradReverse {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        // A copy rather than the input itself, so that the two nodes never alias
+        Raw::new(t_a.value().mul_scalar(1.0))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(Raw::new(grad.value().mul_scalar(-self.lambda))), None]
+    }
+}
+
+///
+/// Largest singular value by power iteration, keeping the singular vectors for backward
+///
+#[derive(Clone)]
+pub struct SpectralNorm<'d, T: 'd + TensorType<'d>> {
+    pub iters: usize,
+    /// Left singular vector as a column `[m, 1]`
+    pub u: Option<Raw<'d, T>>,
+    /// Right singular vector as a row `[1, n]`
+    pub v: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for SpectralNorm<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let w = t_a.value();
+        let shape = w.shape();
+        assert_eq!(
+            shape.len(),
+            2,
+            "spectral_norm expects a matrix, got {:?}",
+            shape
+        );
+        let (m, n) = (shape[0], shape[1]);
+
+        let iters = self.iters;
+        let packed = Raw::new(on_cpu(w, |w| power_iteration(w, iters)));
+        let packed = packed.value();
+        let sigma = Raw::new(packed.slice_ranges(&[(0, 1)]));
+        let u = Raw::new(packed.slice_ranges(&[(1, 1 + m)]));
+        let v = Raw::new(packed.slice_ranges(&[(1 + m, 1 + m + n)]));
+
+        self.u = Some(Raw::new(u.value().reshape(&[m, 1])));
+        self.v = Some(Raw::new(v.value().reshape(&[1, n])));
+        Raw::new(sigma.value().reshape(&[]))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let u = self.u.unwrap().value();
+        let v = self.v.unwrap().value();
+
+        let outer = u.matmul(v);
+        [Some(Raw::new(outer.mul(grad.value()))), None]
+    }
+    fn detach(&mut self) {
+        self.u = None;
+        self.v = None;
+    }
+}
+
+///
+/// Estimate the top singular triplet of a matrix `[m, n]` by power iteration, packed as
+/// `[sigma, u_0..u_m, v_0..v_n]`
+///
+fn power_iteration(w: &ArrayD<f32>, iters: usize) -> ArrayD<f32> {
+    let w = w
+        .view()
+        .into_dimensionality::<Ix2>()
+        .expect("Not a 2x2 matrix");
+    let (m, n) = w.dim();
+    let normalize = |x: Array1<f32>| {
+        let norm = x.dot(&x).sqrt().max(f32::EPSILON);
+        x / norm
+    };
+
+    let mut v = normalize(Array1::ones(n));
+    let mut u = normalize(w.dot(&v));
+    for _ in 0..iters {
+        v = normalize(w.t().dot(&u));
+        u = normalize(w.dot(&v));
+    }
+    let sigma = u.dot(&w.dot(&v));
+
+    let mut packed = Vec::with_capacity(1 + m + n);
+    packed.push(sigma);
+    packed.extend(u.iter());
+    packed.extend(v.iter());
+    ArrayD::from_shape_vec(IxDyn(&[1 + m + n]), packed).unwrap()
+}
+
+///
+/// Frobenius norm `sqrt(sum(x^2))` as a scalar. The gradient `x / norm` is taken to be zero
+/// at `x = 0`, where the norm is not differentiable.
+///
+#[derive(Clone)]
+pub struct FroNorm<'d, T: 'd + TensorType<'d>> {
+    pub x: Option<Raw<'d, T>>,
+    pub norm: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for FroNorm<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = t_a.value();
+        let sq = Raw::new(x.mul(x));
+        let total = Raw::new(sq.value().sum());
+        let norm = Raw::new(total.value().sqrt());
+        self.x = Some(t_a);
+        self.norm = Some(norm);
+        norm
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let x = self.x.unwrap().value();
+        let norm = self.norm.unwrap().value();
+
+        if norm.get_value_cpu().sum() == 0.0 {
+            return [Some(Raw::new(x.val_like(0.0))), None];
+        }
+        let a = x.div(norm).mul(grad.value());
+        [Some(Raw::new(a)), None]
+    }
+    fn detach(&mut self) {
+        self.x = None;
+        self.norm = None;
+    }
+}
+
+///
+/// The largest absolute value as a scalar, e.g. the scale for symmetric quantization
+///
+/// The whole gradient goes to the first element attaining the maximum, times its sign
+///
+#[derive(Clone)]
+pub struct AbsMax {
+    pub in_shape: Vec<usize>,
+    /// Per-axis `(i, i + 1)` ranges selecting the winning element
+    pub at: Vec<(usize, usize)>,
+    pub sign: f32,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for AbsMax {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = t_a.value();
+        let cpu = x.get_value_cpu();
+        let (pos, &v) = cpu
+            .iter()
+            .enumerate()
+            .fold(None, |best: Option<(usize, &f32)>, (i, v)| match best {
+                Some((_, b)) if b.abs() >= v.abs() => best,
+                _ => Some((i, v)),
+            })
+            .expect("abs_max of an empty tensor");
+
+        self.in_shape = x.shape();
+        self.sign = if v < 0.0 { -1.0 } else { 1.0 };
+        let mut rest = pos;
+        self.at = vec![(0, 0); self.in_shape.len()];
+        for (ax, &n) in self.in_shape.iter().enumerate().rev() {
+            self.at[ax] = (rest % n, rest % n + 1);
+            rest /= n;
+        }
+
+        let winner = Raw::new(x.slice_ranges(&self.at));
+        let winner = Raw::new(winner.value().mul_scalar(self.sign));
+        Raw::new(winner.value().reshape(&[]))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let ones = vec![1; self.in_shape.len()];
+        let g = Raw::new(grad.value().reshape(&ones));
+        let g = Raw::new(g.value().mul_scalar(self.sign));
+        let a = g.value().scatter_ranges(&self.in_shape, &self.at);
+        [Some(Raw::new(a)), None]
+    }
+}
+
+///
+/// Kronecker product of two matrices, `[m, n] x [p, q] -> [m * p, n * q]`
+///
+/// Both operands are viewed as `[m, p, n, q]` blocks so that forward is an element-wise
+/// product and backward sums out the other operand's axes
+///
+#[derive(Clone)]
+pub struct Kron<'d, T: 'd + TensorType<'d>> {
+    /// `a` broadcast to `[m, p, n, q]`
+    pub a4: Option<Raw<'d, T>>,
+    /// `b` reshaped to `[1, p, 1, q]`
+    pub b4: Option<Raw<'d, T>>,
+    pub shape4: Option<[usize; 4]>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Kron<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        let (a, b) = (t_a.value(), t_b.value());
+        let (sa, sb) = (a.shape(), b.shape());
+        assert!(
+            sa.len() == 2 && sb.len() == 2,
+            "kron expects two matrices, got {:?} and {:?}",
+            sa,
+            sb
+        );
+        let (m, n, p, q) = (sa[0], sa[1], sb[0], sb[1]);
+
+        let a4 = Raw::new(a.reshape(&[m, 1, n, 1]));
+        let a4 = Raw::new(a4.value().broadcast_to(&[m, p, n, q]));
+        let b4 = Raw::new(b.reshape(&[1, p, 1, q]));
+        self.a4 = Some(a4);
+        self.b4 = Some(b4);
+        self.shape4 = Some([m, p, n, q]);
+
+        let k = Raw::new(a4.value().mul(b4.value()));
+        Raw::new(k.value().reshape(&[m * p, n * q]))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let [m, p, n, q] = self.shape4.unwrap();
+        let a4 = self.a4.unwrap().value();
+        let b4 = self.b4.unwrap().value();
+
+        let g4 = Raw::new(grad.value().reshape(&[m, p, n, q]));
+        let ga = Raw::new(g4.value().mul(b4)).value().sum_axes(&[1, 3]);
+        let gb = Raw::new(g4.value().mul(a4)).value().sum_axes(&[0, 2]);
+
+        [Some(Raw::new(ga)), Some(Raw::new(gb))]
+    }
+    fn detach(&mut self) {
+        self.a4 = None;
+        self.b4 = None;
+    }
+}
+
+///
+/// `AB - BA`, or `AB + BA` when `anti` is set
+///
+fn bracket<'d, T: TensorType<'d>>(a: &T, b: &T, anti: bool) -> T {
+    let (ab, ba) = (a.matmul(b), b.matmul(a));
+    if anti {
+        ab.add(&ba)
+    } else {
+        ab.sub(&ba)
+    }
+}
+
+///
+/// The commutator `[A, B] = AB - BA`, or the anticommutator `{A, B} = AB + BA`
+///
+#[derive(Clone)]
+pub struct Commutator<'d, T: 'd + TensorType<'d>> {
+    pub anti: bool,
+    pub a: Option<Raw<'d, T>>,
+    pub b: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Commutator<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        self.a = Some(t_a);
+        self.b = Some(t_b);
+        Raw::new(bracket(t_a.value(), t_b.value(), self.anti))
+    }
+    ///
+    /// `dA = G B^T -/+ B^T G` and `dB = A^T G -/+ G A^T`, from the two products
+    ///
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let a = self.a.unwrap().value();
+        let b = self.b.unwrap().value();
+        let g = grad.value();
+
+        let combine = |x: T, y: T| if self.anti { x.add(&y) } else { x.sub(&y) };
+        let ga = combine(
+            g.matmul_transposed(b, false, true),
+            b.matmul_transposed(g, true, false),
+        );
+        let gb = combine(
+            a.matmul_transposed(g, true, false),
+            g.matmul_transposed(a, false, true),
+        );
+        [Some(Raw::new(ga)), Some(Raw::new(gb))]
+    }
+    fn detach(&mut self) {
+        self.a = None;
+        self.b = None;
+    }
+}
+
+///
+/// Coefficients of Rodrigues' formula `R = I + a K + b K^2` and the derivatives `a' / theta`,
+/// `b' / theta`, using Taylor series near zero where the closed forms lose precision
+///
+fn rodrigues_coefficients(theta: f32) -> [f32; 4] {
+    let t2 = theta * theta;
+    if theta < 1e-3 {
+        return [
+            1.0 - t2 / 6.0,
+            0.5 - t2 / 24.0,
+            -1.0 / 3.0 + t2 / 30.0,
+            -1.0 / 12.0 + t2 / 180.0,
+        ];
+    }
+    let (s, c) = theta.sin_cos();
+    [
+        s / theta,
+        (1.0 - c) / t2,
+        (theta * c - s) / (t2 * theta),
+        (theta * s - 2.0 * (1.0 - c)) / (t2 * t2),
+    ]
+}
+
+///
+/// Matrix exponential of a 3x3 skew-symmetric generator via Rodrigues' formula, exact and
+/// cheaper than the series. Any other input falls back to the general `ExpM`.
+///
+/// The angle is taken as `theta = |K|_F / sqrt(2)`, which equals the rotation angle for
+/// skew-symmetric `K` and keeps the map smooth in every entry
+///
+#[derive(Clone)]
+pub struct ExpmSo3<'d, T: 'd + TensorType<'d>> {
+    pub k: Option<Raw<'d, T>>,
+    pub theta: f32,
+    pub general: Option<ExpM<'d, T>>,
+}
+impl<'d, T: TensorType<'d> + Clone> OneValuedFn<'d, T> for ExpmSo3<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let k = t_a.value();
+        let cpu = k.get_value_cpu();
+        let skew = cpu.shape() == [3, 3]
+            && cpu
+                .iter()
+                .zip(cpu.t().iter())
+                .all(|(x, y)| (x + y).abs() < 1e-6);
+        if !skew {
+            let mut general = ExpM { a: None, res: None };
+            let out = general.forward(t_a);
+            self.general = Some(general);
+            return out;
+        }
+        self.general = None;
+
+        let theta = (cpu.mapv(|x| x * x).sum() / 2.0).sqrt();
+        let [a, b, _, _] = rodrigues_coefficients(theta);
+        self.k = Some(t_a);
+        self.theta = theta;
+
+        let k2 = Raw::new(k.matmul(k));
+        let ak = Raw::new(k.mul_scalar(a));
+        let bk2 = Raw::new(k2.value().mul_scalar(b));
+        Raw::new(k.eye_like().add(ak.value()).add(bk2.value()))
+    }
+    ///
+    /// `dK = a G + b (G K^T + K^T G) + (a'/theta <G, K> + b'/theta <G, K^2>) K / 2`
+    ///
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        if let Some(general) = &self.general {
+            return general.backward(grad);
+        }
+        let k = self.k.unwrap().value();
+        let g = grad.value();
+        let [a, b, da, db] = rodrigues_coefficients(self.theta);
+
+        let k2 = Raw::new(k.matmul(k));
+        let gk = g.mul(k).get_value_cpu().sum();
+        let gk2 = g.mul(k2.value()).get_value_cpu().sum();
+
+        let sym = Raw::new(
+            g.matmul_transposed(k, false, true)
+                .add(&k.matmul_transposed(g, true, false)),
+        );
+        let terms = [
+            Raw::new(g.mul_scalar(a)),
+            Raw::new(sym.value().mul_scalar(b)),
+            Raw::new(k.mul_scalar((da * gk + db * gk2) / 2.0)),
+        ];
+        let total = terms[0].value().add(terms[1].value()).add(terms[2].value());
+        [Some(Raw::new(total)), None]
+    }
+    fn detach(&mut self) {
+        self.k = None;
+    }
+}
+
+///
+/// Exponential map from a tangent 3-vector to a unit quaternion
+///
+#[derive(Clone)]
+pub struct QuatExp<'d, T: 'd + TensorType<'d>> {
+    pub v: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for QuatExp<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        self.v = Some(t_a);
+        Raw::new(on_cpu(t_a.value(), quat_exp))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let g = grad.value().get_value_cpu();
+        let a = on_cpu(self.v.unwrap().value(), |v| quat_exp_backward(v, &g));
+        [Some(Raw::new(a)), None]
+    }
+    fn detach(&mut self) {
+        self.v = None;
+    }
+}
+
+///
+/// Logarithm map from a unit quaternion to a tangent 3-vector
+///
+#[derive(Clone)]
+pub struct QuatLog<'d, T: 'd + TensorType<'d>> {
+    pub q: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for QuatLog<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        self.q = Some(t_a);
+        Raw::new(on_cpu(t_a.value(), quat_log))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let g = grad.value().get_value_cpu();
+        let a = on_cpu(self.q.unwrap().value(), |q| quat_log_backward(q, &g));
+        [Some(Raw::new(a)), None]
+    }
+    fn detach(&mut self) {
+        self.q = None;
+    }
+}
+
+///
+/// Unit quaternion `[cos|v|, sin|v| v / |v|]` from a 3-vector `v`
+///
+fn quat_exp(v: &ArrayD<f32>) -> ArrayD<f32> {
+    let v = v
+        .as_slice()
+        .expect("quat_exp expects a contiguous 3-vector");
+    assert_eq!(v.len(), 3, "quat_exp expects a 3-vector");
+    let theta = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let s = sinc(theta);
+    ndarray::arr1(&[theta.cos(), s * v[0], s * v[1], s * v[2]]).into_dyn()
+}
+
+///
+/// Inverse of `quat_exp` for a quaternion `[w, x, y, z]`
+///
+fn quat_log(q: &ArrayD<f32>) -> ArrayD<f32> {
+    let q = q
+        .as_slice()
+        .expect("quat_log expects a contiguous quaternion");
+    assert_eq!(q.len(), 4, "quat_log expects a quaternion [w, x, y, z]");
+    let (w, u) = (q[0], &q[1..]);
+    let n = u.iter().map(|x| x * x).sum::<f32>().sqrt();
+    // The series expands atan2(n, w) / n about n = 0 only for w > 0; near w = -1, i.e.
+    // |v| close to pi, the ratio is large and atan2 is accurate
+    let f = if n < 1e-4 && w > 0.0 {
+        1.0 / w - n * n / (3.0 * w * w * w)
+    } else {
+        n.atan2(w) / n
+    };
+    ndarray::arr1(&[f * u[0], f * u[1], f * u[2]]).into_dyn()
+}
+
+///
+/// Vector-Jacobian product of `quat_exp` at `v`
+///
+fn quat_exp_backward(v: &ArrayD<f32>, grad: &ArrayD<f32>) -> ArrayD<f32> {
+    let v = v.as_slice().unwrap();
+    let g = grad.as_slice().expect("Expected a contiguous gradient");
+    let theta = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let s = sinc(theta);
+    // d(sinc)/d(theta) / theta
+    let c2 = if theta < 1e-3 {
+        -1.0 / 3.0 + theta * theta / 30.0
+    } else {
+        (theta * theta.cos() - theta.sin()) / (theta * theta * theta)
+    };
+    let vg: f32 = (0..3).map(|i| v[i] * g[i + 1]).sum();
+    let out: Vec<f32> = (0..3)
+        .map(|i| -g[0] * s * v[i] + s * g[i + 1] + c2 * v[i] * vg)
+        .collect();
+    ArrayD::from_shape_vec(IxDyn(&[3]), out).unwrap()
+}
+
+///
+/// Vector-Jacobian product of `quat_log` at `q`
+///
+fn quat_log_backward(q: &ArrayD<f32>, grad: &ArrayD<f32>) -> ArrayD<f32> {
+    let q = q.as_slice().unwrap();
+    let g = grad.as_slice().expect("Expected a contiguous gradient");
+    let (w, u) = (q[0], &q[1..]);
+    let n2: f32 = u.iter().map(|x| x * x).sum();
+    let n = n2.sqrt();
+    let r2 = n2 + w * w;
+    // v = f(w, |u|) u with f = atan2(|u|, w) / |u|; h = (df/du) / u
+    let (f, h) = if n < 1e-4 && w > 0.0 {
+        (1.0 / w - n2 / (3.0 * w * w * w), -2.0 / (3.0 * w * w * w))
+    } else {
+        let phi = n.atan2(w);
+        (phi / n, w / (r2 * n2) - phi / (n2 * n))
+    };
+    let ug: f32 = (0..3).map(|i| u[i] * g[i]).sum();
+    let mut out = vec![-ug / r2];
+    out.extend((0..3).map(|i| f * g[i] + ug * h * u[i]));
+    ArrayD::from_shape_vec(IxDyn(&[4]), out).unwrap()
+}
+
+///
+/// `sin(x) / x`, continuous at zero
+///
+fn sinc(x: f32) -> f32 {
+    if x < 1e-4 {
+        1.0 - x * x / 6.0
+    } else {
+        x.sin() / x
+    }
+}
+
+///
+/// Logarithm map from a 3x3 rotation matrix to its axis-angle 3-vector
+///
+#[derive(Clone)]
+pub struct RotationLog<'d, T: 'd + TensorType<'d>> {
+    pub r: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for RotationLog<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        self.r = Some(t_a);
+        Raw::new(on_cpu(t_a.value(), rotation_log))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let g = grad.value().get_value_cpu();
+        let a = on_cpu(self.r.unwrap().value(), |r| rotation_log_backward(r, &g));
+        [Some(Raw::new(a)), None]
+    }
+    fn detach(&mut self) {
+        self.r = None;
+    }
+}
+
+///
+/// Axis-angle 3-vector `theta * axis` of a 3x3 rotation matrix, the SO(3) logarithm
+///
+fn rotation_log(r: &ArrayD<f32>) -> ArrayD<f32> {
+    let (s, f, _) = rotation_log_parts(r);
+    ndarray::arr1(&[f * s[0], f * s[1], f * s[2]]).into_dyn()
+}
+
+///
+/// Vector-Jacobian product of `rotation_log` at `r`
+///
+fn rotation_log_backward(r: &ArrayD<f32>, grad: &ArrayD<f32>) -> ArrayD<f32> {
+    let (s, f, dfdc) = rotation_log_parts(r);
+    let g = grad.as_slice().expect("Expected a contiguous gradient");
+    // w = f(c) s with s the axial vector of R - R^T and c = (tr(R) - 1) / 2
+    let gs = [f * g[0], f * g[1], f * g[2]];
+    let diag = 0.5 * dfdc * (0..3).map(|i| g[i] * s[i]).sum::<f32>();
+    let mut out = ndarray::Array2::eye(3) * diag;
+    for (k, &(i, j)) in [(2, 1), (0, 2), (1, 0)].iter().enumerate() {
+        out[[i, j]] += gs[k];
+        out[[j, i]] -= gs[k];
+    }
+    out.into_dyn()
+}
+
+///
+/// For a 3x3 matrix `R`: the axial vector `s` of `R - R^T`, and `f = theta / (2 sin(theta))`
+/// with its derivative in `c = cos(theta) = (tr(R) - 1) / 2`, so that `log(R) = f s`
+///
+fn rotation_log_parts(r: &ArrayD<f32>) -> ([f32; 3], f32, f32) {
+    assert_eq!(
+        r.shape(),
+        [3, 3],
+        "rotation_log expects a 3x3 rotation matrix"
+    );
+    let s = [
+        r[[2, 1]] - r[[1, 2]],
+        r[[0, 2]] - r[[2, 0]],
+        r[[1, 0]] - r[[0, 1]],
+    ];
+    let c = ((r[[0, 0]] + r[[1, 1]] + r[[2, 2]] - 1.0) / 2.0).clamp(-1.0, 1.0);
+    let theta = c.acos();
+    let (f, dfdc) = if theta < 1e-3 {
+        (
+            0.5 + theta * theta / 12.0,
+            -1.0 / 6.0 - theta * theta / 15.0,
+        )
+    } else {
+        let sin = theta.sin();
+        (
+            theta / (2.0 * sin),
+            -(sin - theta * c) / (2.0 * sin * sin * sin),
+        )
+    };
+    (s, f, dfdc)
+}
+
+///
+/// Element-wise comparison as a 0/1 mask. Not differentiable, so both inputs get zero gradient
+///
+#[derive(Clone)]
+pub struct Compare<'d, T: 'd + TensorType<'d>> {
+    pub cmp: Comparison,
+    pub a: Option<Raw<'d, T>>,
+    pub b: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> TwoValuedFn<'d, T> for Compare<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>, t_b: Raw<'d, T>) -> Raw<'d, T> {
+        self.a = Some(t_a);
+        self.b = Some(t_b);
+        Raw::new(t_a.value().compare(t_b.value(), self.cmp))
+    }
+    fn backward(&self, _grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let a = self.a.unwrap().value();
+        let b = self.b.unwrap().value();
+        [
+            Some(Raw::new(a.val_like(0.0))),
+            Some(Raw::new(b.val_like(0.0))),
+        ]
+    }
+    fn detach(&mut self) {
+        self.a = None;
+        self.b = None;
+    }
+}
+
+///
+/// Sum, or mean, over one or more axes, optionally keeping them with length 1
+///
+#[derive(Clone)]
+pub struct SumAxis {
+    pub axes: Vec<usize>,
+    pub keepdims: bool,
+    pub mean: bool,
+    pub in_shape: Option<Vec<usize>>,
+}
+impl SumAxis {
+    ///
+    /// `shape` with each reduced axis kept with length 1
+    ///
+    pub fn kept_shape(&self, shape: &[usize]) -> Vec<usize> {
+        let mut kept = shape.to_vec();
+        for &axis in &self.axes {
+            kept[axis] = 1;
+        }
+        kept
+    }
+    ///
+    /// How many elements of `shape` are reduced into each output element
+    ///
+    fn count(&self, shape: &[usize]) -> usize {
+        self.axes.iter().map(|&axis| shape[axis]).product()
+    }
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for SumAxis {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let x = t_a.value();
+        let in_shape = x.shape();
+        let mut out = Raw::new(x.sum_axes(&self.axes));
+        if self.keepdims {
+            out = Raw::new(out.value().reshape(&self.kept_shape(&in_shape)));
+        }
+        if self.mean {
+            out = Raw::new(out.value().mul_scalar(1.0 / self.count(&in_shape) as f32));
+        }
+        self.in_shape = Some(in_shape);
+        out
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let in_shape = self.in_shape.as_ref().unwrap();
+
+        let g = Raw::new(grad.value().reshape(&self.kept_shape(in_shape)));
+        let mut a = Raw::new(g.value().broadcast_to(in_shape));
+        if self.mean {
+            a = Raw::new(a.value().mul_scalar(1.0 / self.count(in_shape) as f32));
+        }
+        [Some(a), None]
+    }
+}
+
+///
+/// Target of a `Reshape`, resolved against the input shape at forward time
+///
+#[derive(Clone, Debug)]
+pub enum ReshapeTo {
+    Shape(Vec<usize>),
+    /// Collapse every axis from the given one onward into a single axis
+    Flatten(usize),
+    /// Remove the given axis, which must have length 1
+    Squeeze(usize),
+    /// Insert an axis of length 1 at the given position
+    Unsqueeze(usize),
+}
+impl ReshapeTo {
+    pub fn resolve(&self, in_shape: &[usize]) -> Vec<usize> {
+        match self {
+            ReshapeTo::Shape(shape) => shape.clone(),
+            ReshapeTo::Flatten(start) => {
+                let mut shape = in_shape[..*start].to_vec();
+                shape.push(in_shape[*start..].iter().product());
+                shape
+            }
+            ReshapeTo::Squeeze(axis) => {
+                assert_eq!(
+                    in_shape[*axis], 1,
+                    "cannot squeeze axis {} of shape {:?}",
+                    axis, in_shape
+                );
+                let mut shape = in_shape.to_vec();
+                shape.remove(*axis);
+                shape
+            }
+            ReshapeTo::Unsqueeze(axis) => {
+                let mut shape = in_shape.to_vec();
+                shape.insert(*axis, 1);
+                shape
+            }
+        }
+    }
+}
+
+///
+/// Change the shape while keeping the elements in order
+///
+#[derive(Clone)]
+pub struct Reshape {
+    pub to: ReshapeTo,
+    pub in_shape: Option<Vec<usize>>,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Reshape {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let val = t_a.value();
+        let in_shape = val.shape();
+        let shape = self.to.resolve(&in_shape);
+        assert_eq!(
+            shape.iter().product::<usize>(),
+            in_shape.iter().product::<usize>(),
+            "cannot reshape {:?} into {:?}",
+            in_shape,
+            shape
+        );
+        self.in_shape = Some(in_shape);
+        Raw::new(val.reshape(&shape))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let in_shape = self.in_shape.as_ref().unwrap();
+        [Some(Raw::new(grad.value().reshape(in_shape))), None]
+    }
+}
+
+///
+/// Pad each axis by `(before, after)` entries of a constant value. The backward pass slices
+/// the gradient back to the original region
+///
+#[derive(Clone)]
+pub struct Pad {
+    pub pads: Vec<(usize, usize)>,
+    pub value: f32,
+    pub in_shape: Option<Vec<usize>>,
+}
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Pad {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        let val = t_a.value();
+        let in_shape = val.shape();
+        assert_eq!(
+            self.pads.len(),
+            in_shape.len(),
+            "pad needs one (before, after) pair per axis of {:?}",
+            in_shape
+        );
+        self.in_shape = Some(in_shape);
+        Raw::new(val.pad(&self.pads, self.value))
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let in_shape = self.in_shape.as_ref().unwrap();
+        let ranges: Vec<(usize, usize)> = self
+            .pads
+            .iter()
+            .zip(in_shape)
+            .map(|(&(before, _), &len)| (before, before + len))
+            .collect();
+        [Some(Raw::new(grad.value().slice_ranges(&ranges))), None]
+    }
+}
+
+///
+/// Squared magnitude `|z|^2`. The output is real, so only the real part of the incoming
+/// gradient flows back: `dz = 2 Re(grad) conj(z)`, which is `2 x grad` for real tensors
+///
+#[derive(Clone)]
+pub struct Abs2<'d, T: 'd + TensorType<'d>> {
+    pub a: Option<Raw<'d, T>>,
+}
+impl<'d, T: TensorType<'d>> OneValuedFn<'d, T> for Abs2<'d, T> {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        self.a = Some(t_a);
+        Raw::new(t_a.value().abs2())
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        let grad = grad.value();
+        let re2 = Raw::new(grad.add(&grad.conj()));
+        let a_conj = Raw::new(self.a.unwrap().value().conj());
+        [Some(Raw::new(re2.value().mul(a_conj.value()))), None]
+    }
+    fn detach(&mut self) {
+        self.a = None;
+    }
+}
+
+///
+/// Another node's value, left in place of a duplicate merged by `Graph::dedup` so that
+/// Tensors pointing at the duplicate keep working. The value and gradient are passed through
+/// without copying
+///
+#[derive(Clone)]
+pub struct Alias;
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for Alias {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        t_a
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(grad), None]
+    }
+}
+
+///
+/// Conjugate transpose. The map is anti-holomorphic, so the gradient is the conjugate
+/// transpose of the incoming one rather than its plain transpose
+///
+#[derive(Clone)]
+pub struct ConjTranspose;
+impl<'d, T: 'd + TensorType<'d>> OneValuedFn<'d, T> for ConjTranspose {
+    fn forward(&mut self, t_a: Raw<'d, T>) -> Raw<'d, T> {
+        Raw::new(t_a.value().conj_t())
+    }
+    fn backward(&self, grad: Raw<'d, T>) -> [Option<Raw<'d, T>>; 2] {
+        [Some(Raw::new(grad.value().conj_t())), None]
     }
 }
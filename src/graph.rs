@@ -1,7 +1,207 @@
-use crate::functions::Function;
+use crate::functions::{
+    self, Comparison, CustomOne, CustomTwo, Function, OneValuedFn, Rounding, TwoValuedFn,
+};
 use crate::tensor::{Raw, Tensor, TensorType};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+///
+/// Errors from reading values or gradients that have not been computed
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    /// The node has no value, forward has not run up to it
+    NotComputed { index: usize },
+    /// The node has no gradient, either backward has not run or the node was not reached
+    NoGradient { index: usize, forward_ran: bool },
+    /// The node's op cannot be described, e.g. it has no `OpSpec` or ONNX counterpart
+    Unsupported { index: usize, op: &'static str },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::NotComputed { index } => {
+                write!(f, "node v{} has no value, was forward called?", index)
+            }
+            GraphError::NoGradient { index, forward_ran } => write!(
+                f,
+                "node v{} has no gradient, was backward called? (forward {})",
+                index,
+                if *forward_ran { "ran" } else { "did not run" }
+            ),
+            GraphError::Unsupported { index, op } => {
+                write!(
+                    f,
+                    "node v{} is a {} op, which cannot be exported",
+                    index, op
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+///
+/// One operation for `Graph::run`. Operands are node indices: existing nodes, or the outputs
+/// of earlier ops in the same list, which take the next free indices in order
+///
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OpSpec {
+    Add(usize, usize),
+    Mul(usize, usize),
+    MatMul(usize, usize),
+    ExpM(usize),
+    Silu(usize),
+    Softmax {
+        input: usize,
+        axis: usize,
+        temperature: f32,
+    },
+    Scale {
+        input: usize,
+        factor: f32,
+    },
+    Reshape {
+        input: usize,
+        shape: Vec<usize>,
+    },
+    SumAxis {
+        input: usize,
+        axis: usize,
+        keepdims: bool,
+    },
+    MeanAxis {
+        input: usize,
+        axis: usize,
+        keepdims: bool,
+    },
+    SumAxes {
+        input: usize,
+        axes: Vec<usize>,
+    },
+    MeanAxes {
+        input: usize,
+        axes: Vec<usize>,
+    },
+    Floor(usize),
+    Ceil(usize),
+    Round(usize),
+    Transpose(usize),
+    ConjTranspose(usize),
+    HardSigmoid(usize),
+    HardTanh(usize),
+    Rsqrt(usize),
+    FroNorm(usize),
+    AbsMax(usize),
+    ExpmSo3(usize),
+    QuatExp(usize),
+    QuatLog(usize),
+    RotationLog(usize),
+    Abs2(usize),
+    WidthScale(usize),
+    Alias(usize),
+    Maximum(usize, usize),
+    Minimum(usize, usize),
+    Dot(usize, usize),
+    Outer(usize, usize),
+    Stack(usize, usize),
+    Kron(usize, usize),
+    Commutator(usize, usize),
+    Anticommutator(usize, usize),
+    BceLoss(usize, usize),
+    BceWithLogits(usize, usize),
+    TensorDot {
+        a: usize,
+        b: usize,
+        axes: (Vec<usize>, Vec<usize>),
+    },
+    Compare {
+        a: usize,
+        b: usize,
+        cmp: Comparison,
+    },
+    RmsNorm {
+        input: usize,
+        weight: usize,
+        eps: f32,
+    },
+    StraightThrough {
+        input: usize,
+        op: Rounding,
+    },
+    Tile {
+        input: usize,
+        reps: Vec<usize>,
+    },
+    BroadcastTo {
+        input: usize,
+        shape: Vec<usize>,
+    },
+    Slice {
+        input: usize,
+        ranges: Vec<(usize, usize)>,
+    },
+    Gather {
+        input: usize,
+        axis: usize,
+        indices: Vec<usize>,
+    },
+    Pad {
+        input: usize,
+        pads: Vec<(usize, usize)>,
+        value: f32,
+    },
+    CumSum {
+        input: usize,
+        axis: usize,
+    },
+    MaskedFill {
+        input: usize,
+        mask_shape: Vec<usize>,
+        mask: Vec<bool>,
+        value: f32,
+    },
+    LogSoftmax {
+        input: usize,
+        axis: usize,
+        temperature: f32,
+    },
+    SoftmaxCrossEntropy {
+        input: usize,
+        targets: Vec<usize>,
+    },
+    NllLoss {
+        input: usize,
+        targets: Vec<usize>,
+    },
+    Elu {
+        input: usize,
+        alpha: f32,
+    },
+    GradReverse {
+        input: usize,
+        lambda: f32,
+    },
+    SpectralNorm {
+        input: usize,
+        iters: usize,
+    },
+    /// A batch norm node with its own statistics. The running mean and variance, flattened,
+    /// are included when the spec is taken with values
+    BatchNorm {
+        input: usize,
+        momentum: f32,
+        eps: f32,
+        training: bool,
+        running: Option<(Vec<f32>, Vec<f32>)>,
+    },
+}
 
 ///
 /// Represents a node in a Wengert list
@@ -13,9 +213,58 @@ use std::fmt;
 pub struct Node<'d, T: TensorType<'d> + Clone> {
     pub deps: [usize; 2],
     pub func: Function<'d, T>,
+    /// Change through `Tensor::set_value`. Forward only sees a direct write if `version` is
+    /// also reset to 0
     pub value: Option<Raw<'d, T>>,
     pub grad: Option<Raw<'d, T>>,
     pub ctx: [Option<Raw<'d, T>>; 2],
+    pub requires_grad: bool,
+    /// The graph epoch at which the value was last computed or set. Forward recomputes an
+    /// op whose input has a newer version; 0 marks a value that must be recomputed
+    pub version: u64,
+    /// Whether the value is dropped after forward and recomputed for backward, set for the
+    /// interior of a `Tensor::checkpoint` segment
+    pub recompute: bool,
+    /// Whether `Graph::free_intermediate_grads` keeps this node's gradient
+    pub retain_grad: bool,
+    /// Whether the node is a leaf created by `Graph::constant`, or the result of folding
+    /// such leaves, so that `Graph::fold_constants` may fold the ops reading it
+    pub constant: bool,
+    /// CPU copy of a device value, filled by `Tensor::value` and cleared when the value changes
+    pub cpu_value: Option<ndarray::ArrayD<f32>>,
+    /// The emptied buffer of a value freed by a checkpoint, refilled when it is recomputed.
+    /// While it is set, the contexts of the ops reading this node point at a dropped value;
+    /// only their backward reads them, and backward recomputes the value first
+    pub discarded: Option<Raw<'d, T>>,
+}
+
+impl<'d, T: TensorType<'d> + Clone> Node<'d, T> {
+    ///
+    /// Whether an input changed since this op's value was computed
+    ///
+    pub(crate) fn is_stale(&self, nodes: &[RefCell<Node<'d, T>>]) -> bool {
+        match self.func {
+            Function::None => false,
+            _ => self
+                .deps
+                .iter()
+                .any(|&d| nodes[d].borrow().version > self.version),
+        }
+    }
+
+    ///
+    /// Set a freshly computed value. A value freed by a checkpoint is moved back into its
+    /// old buffer, which the contexts of the ops reading it still point at
+    ///
+    pub(crate) fn store(&mut self, value: Raw<'d, T>) {
+        self.value = Some(match self.discarded.take() {
+            Some(buffer) if buffer.data != value.data => {
+                unsafe { std::ptr::write(buffer.data, *value.get_box()) };
+                buffer
+            }
+            _ => value,
+        });
+    }
 }
 
 impl<'d, T: TensorType<'d> + Clone> fmt::Debug for Node<'d, T> {
@@ -31,7 +280,13 @@ impl<'d, T: TensorType<'d> + Clone> fmt::Debug for Node<'d, T> {
 ///
 /// We want several instances to be able to push to the node list, hence RefCell<Vec>>
 /// It may be possible to allow construction in several threads via a RwLock,
-/// but for now we assume single-threaded construction of the graph
+/// but for now we assume single-threaded construction of the graph. The RefCells and raw
+/// pointers make a Graph neither `Send` nor `Sync`, so this is checked at compile time:
+///
+/// ```compile_fail
+/// let graph: rust_grad::Graph<ndarray::ArrayD<f32>> = rust_grad::Graph::new();
+/// std::thread::spawn(move || graph.len());
+/// ```
 ///
 /// In addition, we have cases where we need to borrow the contents of a Node struct both mutably
 /// and immutably, so we wrap it with a RefCell.
@@ -39,6 +294,42 @@ impl<'d, T: TensorType<'d> + Clone> fmt::Debug for Node<'d, T> {
 
 pub struct Graph<'d, T: TensorType<'d> + Clone> {
     pub nodes: RefCell<Vec<RefCell<Node<'d, T>>>>,
+    /// Per-node gradient buffers from the previous backward pass, reused when pooling is on
+    pool: RefCell<Vec<Option<Raw<'d, T>>>>,
+    pooling: Cell<bool>,
+    /// Whether backward leaves explicit zeros on nodes it reaches without a contribution
+    pub(crate) grad_zeros: Cell<bool>,
+    /// Output nodes of `Tensor::checkpoint` segments, whose values are always kept
+    pub(crate) checkpoints: RefCell<Vec<usize>>,
+    /// Bumped by every `Tensor::set_value`, versioning node values
+    pub(crate) epoch: Cell<u64>,
+    /// Time spent per op kind, recorded once `Graph::enable_profiling` is called
+    profile: RefCell<Option<HashMap<&'static str, OpProfile>>>,
+    /// Callbacks run on a node's value each time forward computes it
+    forward_hooks: RefCell<HashMap<usize, Vec<ForwardHook>>>,
+    /// Callbacks run on a node's gradient once backward has finished accumulating it
+    backward_hooks: RefCell<HashMap<usize, Vec<BackwardHook<T>>>>,
+}
+
+///
+/// A callback on a node's value, see `Graph::register_forward_hook`
+///
+pub type ForwardHook = Box<dyn Fn(&ndarray::ArrayD<f32>)>;
+
+///
+/// A callback that may modify a node's gradient in place, see `Graph::register_backward_hook`
+///
+pub type BackwardHook<T> = Box<dyn Fn(&mut T)>;
+
+///
+/// Accumulated wall-clock time of one op kind, see `Graph::profile_report`
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpProfile {
+    /// Number of forward evaluations
+    pub calls: usize,
+    pub forward: Duration,
+    pub backward: Duration,
 }
 
 impl<'d, T: TensorType<'d> + Clone> Default for Graph<'d, T> {
@@ -54,6 +345,14 @@ impl<'d, T: TensorType<'d> + Clone> Graph<'d, T> {
     pub fn new() -> Self {
         Graph {
             nodes: RefCell::new(Vec::new()),
+            pool: RefCell::new(Vec::new()),
+            pooling: Cell::new(false),
+            grad_zeros: Cell::new(false),
+            checkpoints: RefCell::new(Vec::new()),
+            epoch: Cell::new(1),
+            profile: RefCell::new(None),
+            forward_hooks: RefCell::new(HashMap::new()),
+            backward_hooks: RefCell::new(HashMap::new()),
         }
     }
 
@@ -80,6 +379,574 @@ impl<'d, T: TensorType<'d> + Clone> Graph<'d, T> {
             value: Some(value),
             grad: None,
             ctx: [None, None],
+            requires_grad: true,
+            version: self.epoch.get(),
+            recompute: false,
+            retain_grad: false,
+            constant: false,
+            cpu_value: None,
+            discarded: None,
+        }));
+        Tensor {
+            graph: self,
+            index: len,
+        }
+    }
+
+    ///
+    /// Create a leaf Tensor for data which never receives a gradient, such as inputs or
+    /// targets. No gradient buffer is ever allocated for it during backward.
+    ///
+    pub fn constant<'g>(&'g self, value: T) -> Tensor<'d, 'g, T> {
+        let tensor = self.tensor(value).requires_grad(false);
+        self.nodes.borrow()[tensor.index].borrow_mut().constant = true;
+        tensor
+    }
+
+    ///
+    /// The Tensor for the node at `index`, e.g. to find a node of a `deep_clone` copy or of
+    /// a graph rebuilt from its spec. Panics if there is no such node
+    ///
+    pub fn tensor_at<'g>(&'g self, index: usize) -> Tensor<'d, 'g, T> {
+        let len = self.nodes.borrow().len();
+        assert!(
+            index < len,
+            "no node v{} in a graph of {} nodes",
+            index,
+            len
+        );
+        Tensor { graph: self, index }
+    }
+
+    ///
+    /// All leaf tensors (those created through `tensor`), e.g. to hand to an optimizer
+    ///
+    pub fn leaves<'g>(&'g self) -> Vec<Tensor<'d, 'g, T>> {
+        self.nodes
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| matches!(node.borrow().func, Function::None))
+            .map(|(index, _)| Tensor { graph: self, index })
+            .collect()
+    }
+
+    ///
+    /// Copy the graph, giving every value and gradient a fresh allocation, so that the copy
+    /// can be run and differentiated without touching this one
+    ///
+    /// Ops keep no buffers of the original, so the copy reruns them on its first forward or
+    /// backward. Custom ops must implement `boxed_clone`
+    ///
+    pub fn deep_clone(&self) -> Graph<'d, T> {
+        let nodes = self
+            .nodes
+            .borrow()
+            .iter()
+            .map(|node| {
+                let node = node.borrow();
+                let mut func = node.func.clone();
+                func.detach();
+                let leaf = matches!(func, Function::None);
+                RefCell::new(Node {
+                    deps: node.deps,
+                    func,
+                    value: node.value.map(|v| Raw::new(v.value().clone())),
+                    grad: node.grad.map(|g| Raw::new(g.value().clone())),
+                    ctx: [None, None],
+                    requires_grad: node.requires_grad,
+                    version: if leaf { node.version } else { 0 },
+                    recompute: node.recompute,
+                    retain_grad: node.retain_grad,
+                    constant: node.constant,
+                    cpu_value: node.cpu_value.clone(),
+                    discarded: None,
+                })
+            })
+            .collect();
+        Graph {
+            nodes: RefCell::new(nodes),
+            pool: RefCell::new(Vec::new()),
+            pooling: Cell::new(self.pooling.get()),
+            grad_zeros: Cell::new(self.grad_zeros.get()),
+            checkpoints: RefCell::new(self.checkpoints.borrow().clone()),
+            epoch: Cell::new(self.epoch.get()),
+            profile: RefCell::new(None),
+            forward_hooks: RefCell::new(HashMap::new()),
+            backward_hooks: RefCell::new(HashMap::new()),
+        }
+    }
+
+    ///
+    /// A CPU copy of every node's value, `None` for nodes forward has not reached
+    ///
+    pub fn values(&self) -> Vec<Option<ndarray::ArrayD<f32>>> {
+        self.nodes
+            .borrow()
+            .iter()
+            .map(|node| node.borrow().value.map(|v| v.value().get_value_cpu()))
+            .collect()
+    }
+
+    ///
+    /// `(index, mean |grad|, max |grad|)` for every node holding a gradient, to spot vanishing
+    /// or exploding gradients along the graph
+    ///
+    pub fn grad_summary(&self) -> Vec<(usize, f32, f32)> {
+        self.nodes
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| {
+                let grad = node.borrow().grad?.value().get_value_cpu();
+                let abs = grad.mapv(f32::abs);
+                let mean = abs.mean().unwrap_or(0.0);
+                let max = abs.fold(0.0f32, |m, &v| m.max(v));
+                Some((index, mean, max))
+            })
+            .collect()
+    }
+
+    ///
+    /// `(index, L2 norm)` of every leaf's gradient, e.g. to log per-parameter gradient norms
+    /// and spot dead or exploding weights. Leaves without a gradient are skipped
+    ///
+    pub fn leaf_grad_norms(&self) -> Vec<(usize, f32)> {
+        self.nodes
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| {
+                let node = node.borrow();
+                if !matches!(node.func, Function::None) {
+                    return None;
+                }
+                let grad = node.grad?.value().get_value_cpu();
+                Some((index, grad.mapv(|g| g * g).sum().sqrt()))
+            })
+            .collect()
+    }
+
+    ///
+    /// How many nodes of each op kind the graph holds, keyed by op name, e.g. `"MatMul"`.
+    /// Leaves are not counted
+    ///
+    pub fn op_count(&self) -> HashMap<&'static str, usize> {
+        let mut counts = HashMap::new();
+        for node in self.nodes.borrow().iter() {
+            let node = node.borrow();
+            if !matches!(node.func, Function::None) {
+                *counts.entry(node.func.name()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    ///
+    /// A rough count of the multiply-adds in one forward pass: `m * k * n` for a matrix
+    /// product (also as built by `einsum`), the output size times the contracted size for a
+    /// tensor contraction, `n` for a dot product, `n^3` per matrix for a matrix exponential,
+    /// two products for a commutator, and one per output element for every other op. Only
+    /// nodes forward has reached are counted
+    ///
+    pub fn flops(&self) -> usize {
+        use crate::functions::{OneValuedFnEnum, TwoValuedFnEnum};
+
+        let nodes = self.nodes.borrow();
+        let shape_of = |i: usize| nodes[i].borrow().value.map(|v| v.value().shape());
+        nodes
+            .iter()
+            .filter_map(|node| {
+                let node = node.borrow();
+                let out: usize = node.value?.value().shape().iter().product();
+                let flops = match &node.func {
+                    Function::None => 0,
+                    Function::Two(TwoValuedFnEnum::MatMul(_)) => {
+                        let a = shape_of(node.deps[0])?;
+                        let b = shape_of(node.deps[1])?;
+                        a.iter().product::<usize>() * b.last().copied().unwrap_or(1)
+                    }
+                    Function::Two(TwoValuedFnEnum::TensorDot(f)) => {
+                        let a = shape_of(node.deps[0])?;
+                        out * f.axes.0.iter().map(|&ax| a[ax]).product::<usize>()
+                    }
+                    Function::Two(TwoValuedFnEnum::Dot(_)) => {
+                        shape_of(node.deps[0])?.iter().product()
+                    }
+                    Function::Two(TwoValuedFnEnum::Commutator(_)) => {
+                        let n = shape_of(node.deps[0])?[0];
+                        2 * n * n * n
+                    }
+                    Function::One(OneValuedFnEnum::ExpM(_)) => {
+                        let n = shape_of(node.deps[0])?.last().copied().unwrap_or(1);
+                        out * n
+                    }
+                    // One multiply per output element
+                    Function::Two(TwoValuedFnEnum::Outer(_))
+                    | Function::Two(TwoValuedFnEnum::Kron(_)) => out,
+                    _ => out,
+                };
+                Some(flops)
+            })
+            .sum()
+    }
+
+    ///
+    /// The nodes `root` depends on, itself included, in an order where every node comes after
+    /// its inputs
+    ///
+    pub fn topo_order(&self, root: usize) -> Vec<usize> {
+        let nodes = self.nodes.borrow();
+        let mut reached = vec![false; root + 1];
+        reached[root] = true;
+
+        // The Wengert list is already topologically ordered, so one backward sweep finds
+        // every ancestor and the indices give the schedule
+        for i in (0..=root).rev() {
+            if reached[i] {
+                for &d in nodes[i].borrow().deps.iter().filter(|&&d| d != i) {
+                    reached[d] = true;
+                }
+            }
+        }
+        (0..=root).filter(|&i| reached[i]).collect()
+    }
+
+    ///
+    /// The graph in Graphviz DOT format, one box per node labelled like `Display`
+    ///
+    pub fn to_dot(&self) -> String {
+        self.dot(false)
+    }
+
+    ///
+    /// Like `to_dot`, with every edge labelled by the L2 norm of the gradient the last
+    /// backward pass sent along it. Edges that carried no gradient are dashed
+    ///
+    pub fn to_dot_with_grads(&self) -> String {
+        self.dot(true)
+    }
+
+    fn dot(&self, with_grads: bool) -> String {
+        let mut out = String::from("digraph {\n    node [shape=box];\n");
+        for (i, node) in self.nodes.borrow().iter().enumerate() {
+            let node = node.borrow();
+            let shape = match &node.value {
+                Some(value) => format!("{:?}", value.value().shape()),
+                None => "(not computed)".to_string(),
+            };
+            out.push_str(&format!(
+                "    v{} [label=\"v{} = {}\\n{}\"];\n",
+                i,
+                i,
+                node.func.name(),
+                shape
+            ));
+
+            let inputs = match node.func {
+                Function::None => 0,
+                Function::One(_) => 1,
+                Function::Two(_) => 2,
+            };
+            for j in 0..inputs {
+                let attrs = match (with_grads, &node.ctx[j]) {
+                    (false, _) => String::new(),
+                    (true, Some(grad)) => {
+                        let grad = grad.value().get_value_cpu();
+                        format!(" [label=\"{:.4}\"]", grad.mapv(|g| g * g).sum().sqrt())
+                    }
+                    (true, None) => " [style=dashed]".to_string(),
+                };
+                out.push_str(&format!("    v{} -> v{}{};\n", node.deps[j], i, attrs));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    ///
+    /// Record a user-defined single input op, e.g. from a downstream crate, on `input`
+    ///
+    pub fn apply_one<'g>(
+        &'g self,
+        input: Tensor<'d, 'g, T>,
+        f: Box<dyn OneValuedFn<'d, T> + 'd>,
+    ) -> Tensor<'d, 'g, T> {
+        assert!(
+            std::ptr::eq(self, input.graph),
+            "Input belongs to a different graph"
+        );
+        let func = Function::One(CustomOne(Rc::new(RefCell::new(f))).into());
+        self.push([input.index, input.index], func)
+    }
+
+    ///
+    /// Record a user-defined two input op on `a` and `b`
+    ///
+    pub fn apply_two<'g>(
+        &'g self,
+        a: Tensor<'d, 'g, T>,
+        b: Tensor<'d, 'g, T>,
+        f: Box<dyn TwoValuedFn<'d, T> + 'd>,
+    ) -> Tensor<'d, 'g, T> {
+        assert!(
+            std::ptr::eq(self, a.graph) && std::ptr::eq(self, b.graph),
+            "Inputs belong to a different graph"
+        );
+        let func = Function::Two(CustomTwo(Rc::new(RefCell::new(f))).into());
+        self.push([a.index, b.index], func)
+    }
+
+    ///
+    /// Record a list of operations, e.g. from a model loaded at runtime, and return the
+    /// output of the last one
+    ///
+    pub fn run<'g>(&'g self, ops: &[OpSpec]) -> Tensor<'d, 'g, T> {
+        assert!(!ops.is_empty(), "run needs at least one op");
+        let mut out = None;
+        for op in ops {
+            let node = |index: usize| {
+                assert!(
+                    index < self.len(),
+                    "{:?} refers to node v{} but the graph only has {} nodes",
+                    op,
+                    index,
+                    self.len()
+                );
+                Tensor { graph: self, index }
+            };
+            out = Some(match op {
+                OpSpec::Add(a, b) => node(*a) + node(*b),
+                OpSpec::Mul(a, b) => node(*a) * node(*b),
+                OpSpec::MatMul(a, b) => node(*a).matmul(node(*b)),
+                OpSpec::ExpM(a) => node(*a).expm(),
+                OpSpec::Silu(a) => node(*a).silu(),
+                OpSpec::Softmax {
+                    input,
+                    axis,
+                    temperature,
+                } => node(*input).softmax_t(*axis, *temperature),
+                OpSpec::Scale { input, factor } => node(*input).scale(*factor),
+                OpSpec::Reshape { input, shape } => node(*input).reshape(shape.clone()),
+                OpSpec::SumAxis {
+                    input,
+                    axis,
+                    keepdims,
+                } => node(*input).sum_axis(*axis, *keepdims),
+                OpSpec::MeanAxis {
+                    input,
+                    axis,
+                    keepdims,
+                } => node(*input).mean_axis(*axis, *keepdims),
+                OpSpec::SumAxes { input, axes } => node(*input).sum_axes(axes.clone()),
+                OpSpec::MeanAxes { input, axes } => node(*input).mean_axes(axes.clone()),
+                OpSpec::Floor(a) => node(*a).floor(),
+                OpSpec::Ceil(a) => node(*a).ceil(),
+                OpSpec::Round(a) => node(*a).round(),
+                OpSpec::Transpose(a) => node(*a).t(),
+                OpSpec::ConjTranspose(a) => node(*a).conj_t(),
+                OpSpec::HardSigmoid(a) => node(*a).hard_sigmoid(),
+                OpSpec::HardTanh(a) => node(*a).hard_tanh(),
+                OpSpec::Rsqrt(a) => node(*a).rsqrt(),
+                OpSpec::FroNorm(a) => node(*a).fro_norm(),
+                OpSpec::AbsMax(a) => node(*a).abs_max(),
+                OpSpec::ExpmSo3(a) => node(*a).expm_so3(),
+                OpSpec::QuatExp(a) => node(*a).quat_exp(),
+                OpSpec::QuatLog(a) => node(*a).quat_log(),
+                OpSpec::RotationLog(a) => node(*a).rotation_to_axis_angle(),
+                OpSpec::Abs2(a) => node(*a).abs2(),
+                OpSpec::WidthScale(a) => {
+                    let node = node(*a);
+                    let func = functions::WidthScale { factor: None };
+                    self.push([node.index, node.index], Function::One(func.into()))
+                }
+                OpSpec::Alias(a) => {
+                    let node = node(*a);
+                    self.push(
+                        [node.index, node.index],
+                        Function::One(functions::Alias.into()),
+                    )
+                }
+                OpSpec::Maximum(a, b) => node(*a).maximum(node(*b)),
+                OpSpec::Minimum(a, b) => node(*a).minimum(node(*b)),
+                OpSpec::Dot(a, b) => Tensor::einsum("i,i->", node(*a), node(*b)),
+                OpSpec::Outer(a, b) => Tensor::einsum("i,j->ij", node(*a), node(*b)),
+                OpSpec::Stack(a, b) => node(*a).stack(node(*b)),
+                OpSpec::Kron(a, b) => node(*a).kron(node(*b)),
+                OpSpec::Commutator(a, b) => node(*a).commutator(node(*b)),
+                OpSpec::Anticommutator(a, b) => node(*a).anticommutator(node(*b)),
+                OpSpec::BceLoss(a, b) => node(*a).bce_loss(node(*b)),
+                OpSpec::BceWithLogits(a, b) => node(*a).bce_with_logits(node(*b)),
+                OpSpec::TensorDot { a, b, axes } => node(*a).tensordot(node(*b), axes.clone()),
+                OpSpec::Compare { a, b, cmp } => node(*a).compare(node(*b), *cmp),
+                OpSpec::RmsNorm { input, weight, eps } => {
+                    crate::nn::rms_norm(self, node(*input), node(*weight), *eps)
+                }
+                OpSpec::StraightThrough { input, op } => node(*input).straight_through(*op),
+                OpSpec::Tile { input, reps } => node(*input).tile(reps.clone()),
+                OpSpec::BroadcastTo { input, shape } => node(*input).broadcast_to(shape.clone()),
+                OpSpec::Slice { input, ranges } => node(*input).slice(ranges.clone()),
+                OpSpec::Gather {
+                    input,
+                    axis,
+                    indices,
+                } => node(*input).gather(*axis, indices.clone()),
+                OpSpec::Pad { input, pads, value } => node(*input).pad(pads.clone(), *value),
+                OpSpec::CumSum { input, axis } => node(*input).cumsum(*axis),
+                OpSpec::MaskedFill {
+                    input,
+                    mask_shape,
+                    mask,
+                    value,
+                } => {
+                    let mask = ndarray::ArrayD::from_shape_vec(mask_shape.clone(), mask.clone())
+                        .expect("mask length does not match its shape");
+                    node(*input).masked_fill(mask, *value)
+                }
+                OpSpec::LogSoftmax {
+                    input,
+                    axis,
+                    temperature,
+                } => node(*input).log_softmax_t(*axis, *temperature),
+                OpSpec::SoftmaxCrossEntropy { input, targets } => {
+                    node(*input).softmax_cross_entropy(targets.clone())
+                }
+                OpSpec::NllLoss { input, targets } => node(*input).nll_loss(targets.clone()),
+                OpSpec::Elu { input, alpha } => node(*input).elu(*alpha),
+                OpSpec::GradReverse { input, lambda } => node(*input).grad_reverse(*lambda),
+                OpSpec::SpectralNorm { input, iters } => node(*input).spectral_norm(*iters),
+                OpSpec::BatchNorm {
+                    input,
+                    momentum,
+                    eps,
+                    training,
+                    running,
+                } => {
+                    let node = node(*input);
+                    let func = functions::BatchNorm {
+                        stats: Rc::new(functions::BatchNormStats {
+                            training: Cell::new(*training),
+                            momentum: *momentum,
+                            eps: *eps,
+                            running_mean: Cell::new(None),
+                            running_var: Cell::new(None),
+                        }),
+                        x_hat: None,
+                        inv_std: None,
+                        batch_stats: *training,
+                        initial_stats: running.clone(),
+                    };
+                    self.push([node.index, node.index], Function::One(func.into()))
+                }
+            });
+        }
+        out.unwrap()
+    }
+
+    ///
+    /// Start timing every op evaluated by forward and backward, see `profile_report`.
+    /// Enabling again clears the times recorded so far
+    ///
+    pub fn enable_profiling(&self) {
+        *self.profile.borrow_mut() = Some(HashMap::new());
+    }
+
+    ///
+    /// Forward and backward time per op kind since `enable_profiling`, keyed by op name,
+    /// e.g. `"MatMul"`. Empty when profiling is off
+    ///
+    pub fn profile_report(&self) -> HashMap<&'static str, OpProfile> {
+        self.profile.borrow().clone().unwrap_or_default()
+    }
+
+    ///
+    /// Call `hook` with a CPU copy of node `node`'s value every time forward computes it,
+    /// e.g. to log or check activations. Hooks run while the graph is borrowed, so they must
+    /// not use the graph themselves
+    ///
+    pub fn register_forward_hook(&self, node: usize, hook: ForwardHook) {
+        assert!(node < self.len(), "No node v{} to hook", node);
+        self.forward_hooks
+            .borrow_mut()
+            .entry(node)
+            .or_default()
+            .push(hook);
+    }
+
+    pub(crate) fn run_forward_hooks(&self, index: usize, value: &T) {
+        let hooks = self.forward_hooks.borrow();
+        // Only copy the value off the backend when someone is listening
+        if let Some(hooks) = hooks.get(&index) {
+            let cpu = value.get_value_cpu();
+            for hook in hooks {
+                hook(&cpu);
+            }
+        }
+    }
+
+    ///
+    /// Call `hook` on node `node`'s gradient during backward, once every use of the node has
+    /// contributed and before it flows on to the node's inputs. Changes made by the hook
+    /// are what the inputs see, e.g. negating gives a gradient reversal layer. For a leaf
+    /// the hook sees the gradient accumulated so far
+    ///
+    pub fn register_backward_hook(&self, node: usize, hook: BackwardHook<T>) {
+        assert!(node < self.len(), "No node v{} to hook", node);
+        self.backward_hooks
+            .borrow_mut()
+            .entry(node)
+            .or_default()
+            .push(hook);
+    }
+
+    pub(crate) fn run_backward_hooks(&self, index: usize, grad: Raw<'d, T>) {
+        if let Some(hooks) = self.backward_hooks.borrow().get(&index) {
+            for hook in hooks {
+                unsafe { hook(&mut *grad.data) }
+            }
+        }
+    }
+
+    ///
+    /// The start time of an op evaluation, `None` when profiling is off so that no clock
+    /// is read
+    ///
+    pub(crate) fn profile_start(&self) -> Option<Instant> {
+        self.profile.borrow().as_ref().map(|_| Instant::now())
+    }
+
+    pub(crate) fn profile_record(&self, op: &'static str, start: Option<Instant>, backward: bool) {
+        if let (Some(start), Some(profile)) = (start, self.profile.borrow_mut().as_mut()) {
+            let entry = profile.entry(op).or_default();
+            if backward {
+                entry.backward += start.elapsed();
+            } else {
+                entry.calls += 1;
+                entry.forward += start.elapsed();
+            }
+        }
+    }
+
+    ///
+    /// Push a function node onto the Wengert list and return a Tensor pointing to it
+    ///
+    pub(crate) fn push<'g>(&'g self, deps: [usize; 2], func: Function<'d, T>) -> Tensor<'d, 'g, T> {
+        let mut nodes = self.nodes.borrow_mut();
+        let len = nodes.len();
+
+        nodes.push(RefCell::new(Node {
+            deps,
+            func,
+            value: None,
+            grad: None,
+            ctx: [None, None],
+            requires_grad: true,
+            version: 0,
+            recompute: false,
+            retain_grad: false,
+            constant: false,
+            cpu_value: None,
+            discarded: None,
         }));
         Tensor {
             graph: self,
@@ -88,6 +955,345 @@ impl<'d, T: TensorType<'d> + Clone> Graph<'d, T> {
     }
 }
 
+impl<'d, T: TensorType<'d> + Clone> Graph<'d, T> {
+    ///
+    /// For every node, whether backward has to compute a gradient for it: a leaf needs one if
+    /// it requires grad, an op needs one if it requires grad and any of its inputs needs one
+    ///
+    pub(crate) fn needs_grad(&self) -> Vec<bool> {
+        let nodes = self.nodes.borrow();
+        let mut needs: Vec<bool> = Vec::with_capacity(nodes.len());
+        for node in nodes.iter() {
+            let node = node.borrow();
+            let flag = match node.func {
+                Function::None => node.requires_grad,
+                _ => node.requires_grad && node.deps.iter().any(|&d| needs[d]),
+            };
+            needs.push(flag);
+        }
+        needs
+    }
+
+    ///
+    /// One backward pass from several outputs, e.g. the loss heads of a multi-task model,
+    /// each seeded with its own initial gradient. Shared nodes receive the sum of every
+    /// root's contribution, as if the roots were added into a single loss. Leaf gradients
+    /// accumulate like `Tensor::backward`
+    ///
+    pub fn backward_multi<'g>(&'g self, roots: &[(Tensor<'d, 'g, T>, T)]) {
+        let last = roots
+            .iter()
+            .map(|(root, _)| *root)
+            .max_by_key(|root| root.index)
+            .expect("backward_multi needs at least one root");
+        for (root, _) in roots {
+            assert!(
+                std::ptr::eq(root.graph, self),
+                "backward_multi root v{} belongs to a different graph",
+                root.index
+            );
+        }
+
+        // The Wengert list order is valid for every root, so a single sweep down from the
+        // last one visits each node after all of its consumers
+        let seeds = roots
+            .iter()
+            .map(|(root, init)| (root.index, init.clone()))
+            .collect();
+        let needs_grad = self.needs_grad();
+        last.backward_masked(seeds, true, &needs_grad);
+    }
+
+    ///
+    /// Choose what backward leaves on nodes that feed the output but receive no gradient
+    /// contribution, e.g. the input an op declares non-differentiable: `None` by default, or
+    /// zeros of the value's shape when `zeros` is set. Frozen nodes never get a gradient
+    ///
+    pub fn set_grad_default(&self, zeros: bool) {
+        self.grad_zeros.set(zeros);
+    }
+
+    ///
+    /// Reuse gradient buffers across backward passes instead of allocating fresh ones.
+    /// Worthwhile for training loops that run backward repeatedly on the same graph.
+    ///
+    pub fn set_pooling(&self, enabled: bool) {
+        self.pooling.set(enabled);
+        if !enabled {
+            self.pool.borrow_mut().clear();
+        }
+    }
+
+    ///
+    /// Keep the old gradient buffer of node `index` for its next backward pass
+    ///
+    pub(crate) fn recycle(&self, index: usize, buffer: Raw<'d, T>) {
+        if self.pooling.get() {
+            let mut pool = self.pool.borrow_mut();
+            if pool.len() <= index {
+                pool.resize(index + 1, None);
+            }
+            pool[index] = Some(buffer);
+        }
+    }
+
+    ///
+    /// A copy of `src` to become the gradient of node `index`, written into that node's
+    /// spare buffer when one of the same shape is available
+    ///
+    pub(crate) fn pooled_copy(&self, index: usize, src: &T) -> Raw<'d, T> {
+        if self.pooling.get() {
+            let spare = self.pool.borrow_mut().get_mut(index).and_then(Option::take);
+            if let Some(buffer) = spare {
+                if unsafe { (*buffer.data).copy_from(src) } {
+                    return buffer;
+                }
+            }
+        }
+        Raw::new(src.clone())
+    }
+
+    ///
+    /// Re-evaluate every op node in place, e.g. after `Tensor::set_value` on the inputs of a
+    /// graph that is reused across iterations. Unlike building the graph again, no nodes are
+    /// pushed, so the node list keeps its length and allocation
+    ///
+    pub fn recompute(&self) {
+        let nodes = self.nodes.borrow();
+        for (i, node) in nodes.iter().enumerate() {
+            let mut node = node.borrow_mut();
+            let [d_0, d_1] = [node.deps[0], node.deps[1]];
+            let (op, start) = (node.func.name(), self.profile_start());
+            let value = match &mut node.func {
+                Function::None => continue,
+                Function::One(f) => {
+                    f.forward(nodes[d_0].borrow().value.expect("leaf has no value"))
+                }
+                Function::Two(f) => f.forward(
+                    nodes[d_0].borrow().value.expect("leaf has no value"),
+                    nodes[d_1].borrow().value.expect("leaf has no value"),
+                ),
+            };
+            self.profile_record(op, start, false);
+            self.run_forward_hooks(i, value.value());
+            node.store(value);
+            node.version = self.epoch.get();
+            node.cpu_value = None;
+        }
+    }
+
+    ///
+    /// Evaluate every op whose inputs all trace back to constant leaves, see
+    /// `Graph::constant`, and turn it into a constant leaf holding the result. Later forward
+    /// passes then skip that part of the graph entirely. Leaves that only have
+    /// `requires_grad(false)`, such as inputs, are not treated as constant
+    ///
+    /// Nodes are rewritten in place so that existing Tensors stay valid; the constants that
+    /// fed a folded op are left behind, unreferenced. Only fold once the constants are final,
+    /// since `set_value` on one no longer reaches the folded results
+    ///
+    pub fn fold_constants(&self) {
+        let nodes = self.nodes.borrow();
+        let mut constant = Vec::with_capacity(nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            let mut node = node.borrow_mut();
+            let [d_0, d_1] = [node.deps[0], node.deps[1]];
+            let foldable = match &node.func {
+                Function::None => node.constant && node.value.is_some(),
+                f if !f.is_pure() => false,
+                Function::One(_) => constant[d_0],
+                Function::Two(_) => constant[d_0] && constant[d_1],
+            };
+            constant.push(foldable);
+            if !foldable || matches!(node.func, Function::None) {
+                continue;
+            }
+
+            if node.value.is_none() || node.version == 0 || node.is_stale(&nodes) {
+                let value = match &mut node.func {
+                    Function::One(f) => f.forward(nodes[d_0].borrow().value.unwrap()),
+                    Function::Two(f) => f.forward(
+                        nodes[d_0].borrow().value.unwrap(),
+                        nodes[d_1].borrow().value.unwrap(),
+                    ),
+                    Function::None => unreachable!(),
+                };
+                node.store(value);
+                node.cpu_value = None;
+            }
+            node.func = Function::None;
+            node.deps = [i, i];
+            node.requires_grad = false;
+            node.constant = true;
+            node.version = self.epoch.get();
+            node.recompute = false;
+            node.ctx = [None, None];
+            node.grad = None;
+        }
+    }
+
+    ///
+    /// Merge structurally identical op nodes, the same op with the same parameters on the
+    /// same inputs, so that e.g. `x * y` written twice is computed once. Later nodes are
+    /// rewired to the first occurrence, and each duplicate becomes an `Alias` of it so
+    /// existing Tensors stay valid. Only ops with an `OpSpec` are compared
+    ///
+    pub fn dedup(&self) {
+        use crate::functions::Alias;
+        use crate::spec::OpKey;
+
+        let nodes = self.nodes.borrow();
+        let mut canonical: Vec<usize> = (0..nodes.len()).collect();
+        let mut seen: HashMap<OpKey, usize> = HashMap::new();
+        for (i, node) in nodes.iter().enumerate() {
+            let mut node = node.borrow_mut();
+            if matches!(node.func, Function::None) {
+                continue;
+            }
+            node.deps = [canonical[node.deps[0]], canonical[node.deps[1]]];
+            if !node.func.is_pure() {
+                continue;
+            }
+            let key = match crate::spec::op_spec(&node, false) {
+                Some(op) => op.key(),
+                None => continue,
+            };
+            match seen.get(&key) {
+                Some(&first) => {
+                    canonical[i] = first;
+                    node.func = Function::One(Alias.into());
+                    node.deps = [first, first];
+                    node.ctx = [None, None];
+                    node.version = 0;
+                }
+                None => {
+                    seen.insert(key, i);
+                }
+            }
+        }
+    }
+
+    ///
+    /// Free the values of the checkpointed nodes before `index`, returning whether any were
+    /// dropped
+    ///
+    /// Op contexts point at the values they read, so each buffer is only emptied, and the
+    /// recompute moves the new value back into it. Buffers shared with a node that keeps its
+    /// value, e.g. through an `Alias`, are left alone
+    ///
+    pub(crate) fn discard_recomputable(&self, index: usize) -> bool {
+        let nodes = self.nodes.borrow();
+        let drops =
+            |i: usize, node: &Node<'d, T>| i < index && node.recompute && node.value.is_some();
+        let kept: HashSet<*mut T> = nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, node)| {
+                let node = node.borrow();
+                match node.value {
+                    Some(value) if !drops(i, &node) => Some(value.data),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let mut freed = HashSet::new();
+        let mut dropped = false;
+        for (i, node) in nodes.iter().enumerate() {
+            let mut node = node.borrow_mut();
+            if !drops(i, &node) {
+                continue;
+            }
+            let value = node.value.take().unwrap();
+            node.cpu_value = None;
+            dropped = true;
+            if kept.contains(&value.data) {
+                continue;
+            }
+            if freed.insert(value.data) {
+                unsafe { std::ptr::drop_in_place(value.data) };
+            }
+            node.discarded = Some(value);
+        }
+        dropped
+    }
+
+    ///
+    /// Drop the gradients of every op node not marked with `Tensor::retain_grad`, keeping
+    /// only what an optimizer needs once backward is done
+    ///
+    pub fn free_intermediate_grads(&self) {
+        for (i, node) in self.nodes.borrow().iter().enumerate() {
+            let mut node = node.borrow_mut();
+            if matches!(node.func, Function::None) || node.retain_grad {
+                continue;
+            }
+            if let Some(grad) = node.grad.take() {
+                self.recycle(i, grad);
+            }
+        }
+    }
+
+    ///
+    /// Divide every leaf gradient by `scale` in place, undoing `Tensor::backward_scaled`
+    ///
+    pub fn unscale_grads(&self, scale: f32) {
+        for node in self.nodes.borrow().iter() {
+            let node = node.borrow();
+            if let (Function::None, Some(grad)) = (&node.func, &node.grad) {
+                unsafe {
+                    (*grad.data).scale(1.0 / scale);
+                }
+            }
+        }
+    }
+}
+
+impl<'d> Graph<'d, ndarray::ArrayD<f32>> {
+    ///
+    /// A leaf tensor of standard normal samples, see `rng::randn`
+    ///
+    pub fn randn<'g>(&'g self, shape: &[usize], seed: u64) -> Tensor<'d, 'g, ndarray::ArrayD<f32>> {
+        self.tensor(crate::rng::randn(shape, seed))
+    }
+
+    ///
+    /// A leaf tensor of samples uniform on `[low, high)`, see `rng::uniform`
+    ///
+    pub fn uniform<'g>(
+        &'g self,
+        shape: &[usize],
+        low: f32,
+        high: f32,
+        seed: u64,
+    ) -> Tensor<'d, 'g, ndarray::ArrayD<f32>> {
+        self.tensor(crate::rng::uniform(shape, low, high, seed))
+    }
+
+    ///
+    /// A leaf weight with Xavier uniform initialization, see `init::xavier_uniform`
+    ///
+    pub fn xavier<'g>(
+        &'g self,
+        shape: &[usize],
+        seed: u64,
+    ) -> Tensor<'d, 'g, ndarray::ArrayD<f32>> {
+        self.tensor(crate::init::xavier_uniform(shape, seed))
+    }
+
+    ///
+    /// A leaf weight with Kaiming normal initialization, see `init::kaiming_normal`
+    ///
+    pub fn kaiming<'g>(
+        &'g self,
+        shape: &[usize],
+        fan_mode: crate::init::FanMode,
+        seed: u64,
+    ) -> Tensor<'d, 'g, ndarray::ArrayD<f32>> {
+        self.tensor(crate::init::kaiming_normal(shape, fan_mode, seed))
+    }
+}
+
 impl<'d, T: TensorType<'d> + Clone> fmt::Debug for Graph<'d, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut node_string = String::new();
@@ -97,3 +1303,27 @@ impl<'d, T: TensorType<'d> + Clone> fmt::Debug for Graph<'d, T> {
         write!(f, "{}", node_string)
     }
 }
+
+///
+/// Prints the Wengert list one node per line, e.g. `v2 = Mul(v0, v1)  [2, 3]`
+///
+impl<'d, T: TensorType<'d> + Clone> fmt::Display for Graph<'d, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, node) in self.nodes.borrow().iter().enumerate() {
+            let node = node.borrow();
+            let call = match node.func {
+                Function::None => node.func.name().to_string(),
+                Function::One(_) => format!("{}(v{})", node.func.name(), node.deps[0]),
+                Function::Two(_) => {
+                    format!("{}(v{}, v{})", node.func.name(), node.deps[0], node.deps[1])
+                }
+            };
+            let shape = match &node.value {
+                Some(value) => format!("{:?}", value.value().shape()),
+                None => "(not computed)".to_string(),
+            };
+            writeln!(f, "v{} = {:<20} {}", i, call, shape)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use crate::rng;
+use ndarray::{Array, IxDyn};
+
+///
+/// Which fan the Kaiming initializer preserves the variance of
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanMode {
+    /// Preserve the variance of activations in the forward pass
+    FanIn,
+    /// Preserve the variance of gradients in the backward pass
+    FanOut,
+}
+
+///
+/// `(fan_in, fan_out)` for a weight used as `x @ w`: `shape[0]` inputs, `shape[1]` outputs,
+/// and any trailing axes a receptive field counted into both
+///
+pub fn fans(shape: &[usize]) -> (usize, usize) {
+    assert!(
+        shape.len() >= 2,
+        "weights need at least two axes, got {:?}",
+        shape
+    );
+    let receptive: usize = shape[2..].iter().product();
+    (shape[0] * receptive, shape[1] * receptive)
+}
+
+///
+/// Glorot/Xavier uniform initialization, variance `2 / (fan_in + fan_out)`
+///
+pub fn xavier_uniform(shape: &[usize], seed: u64) -> Array<f32, IxDyn> {
+    let (fan_in, fan_out) = fans(shape);
+    let bound = (6.0 / (fan_in + fan_out) as f32).sqrt();
+    rng::uniform(shape, -bound, bound, seed)
+}
+
+///
+/// He/Kaiming normal initialization for ReLU networks, variance `2 / fan`
+///
+pub fn kaiming_normal(shape: &[usize], fan_mode: FanMode, seed: u64) -> Array<f32, IxDyn> {
+    let (fan_in, fan_out) = fans(shape);
+    let fan = match fan_mode {
+        FanMode::FanIn => fan_in,
+        FanMode::FanOut => fan_out,
+    };
+    rng::randn(shape, seed) * (2.0 / fan as f32).sqrt()
+}
@@ -1,8 +1,2791 @@
+pub mod complex;
 pub mod functions;
 pub mod graph;
+pub mod init;
+pub mod nn;
+pub mod npy;
+pub mod onnx;
+pub mod optim;
+pub mod rng;
+pub mod shapes;
+pub mod spec;
 pub mod tensor;
 
-pub use graph::Graph;
+pub use graph::{Graph, GraphError, OpSpec};
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use ndarray::{arr0, arr1, arr2, Array, ArrayD, Axis};
+    use tensor::TensorType;
+
+    ///
+    /// Central finite differences of `sum(f(x))` with respect to `x`
+    ///
+    fn numeric_grad(x: &ArrayD<f32>, f: impl Fn(&ArrayD<f32>) -> ArrayD<f32>) -> ArrayD<f32> {
+        let eps = 1e-2;
+        let mut grad = ArrayD::zeros(x.shape());
+        for i in 0..x.len() {
+            let mut xp = x.clone();
+            let mut xm = x.clone();
+            xp.as_slice_mut().unwrap()[i] += eps;
+            xm.as_slice_mut().unwrap()[i] -= eps;
+            grad.as_slice_mut().unwrap()[i] = (f(&xp).sum() - f(&xm).sum()) / (2.0 * eps);
+        }
+        grad
+    }
+
+    fn assert_close(a: &ArrayD<f32>, b: &ArrayD<f32>, tol: f32) {
+        assert_eq!(a.shape(), b.shape());
+        assert!(
+            a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < tol),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn rounding_has_zero_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.4, -0.6, 2.5]).into_dyn());
+
+        let f = x.floor();
+        let c = x.ceil();
+        let r = x.round();
+        let z = (f + c) + r;
+
+        z.forward();
+        assert_eq!(f.value(), arr1(&[1.0, -1.0, 2.0]).into_dyn());
+        assert_eq!(c.value(), arr1(&[2.0, 0.0, 3.0]).into_dyn());
+        assert_eq!(r.value(), arr1(&[1.0, -1.0, 3.0]).into_dyn());
+
+        z.backward(Array::ones(3).into_dyn());
+        assert_eq!(x.grad(), Array::<f32, _>::zeros(3).into_dyn());
+    }
+
+    #[test]
+    fn straight_through_round() {
+        use functions::Rounding;
+
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.4, -0.6, 2.5]).into_dyn());
+        let y = graph.tensor(arr1(&[2.0, 3.0, 4.0]).into_dyn());
+
+        let z = x.straight_through(Rounding::Round) * y;
+
+        z.forward();
+        assert_eq!(z.value(), arr1(&[2.0, -3.0, 12.0]).into_dyn());
+
+        z.backward(Array::ones(3).into_dyn());
+        assert_eq!(x.grad(), arr1(&[2.0, 3.0, 4.0]).into_dyn());
+    }
+
+    #[test]
+    fn maximum_minimum_route_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 5.0, 3.0]).into_dyn());
+        let y = graph.tensor(arr1(&[4.0, 2.0, 6.0]).into_dyn());
+
+        let z = x.maximum(y);
+        z.forward();
+        assert_eq!(z.value(), arr1(&[4.0, 5.0, 6.0]).into_dyn());
+
+        z.backward(Array::ones(3).into_dyn());
+        assert_eq!(x.grad(), arr1(&[0.0, 1.0, 0.0]).into_dyn());
+        assert_eq!(y.grad(), arr1(&[1.0, 0.0, 1.0]).into_dyn());
+
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 5.0, 3.0]).into_dyn());
+        let y = graph.tensor(arr1(&[4.0, 2.0, 6.0]).into_dyn());
+
+        let z = x.minimum(y);
+        z.forward();
+        assert_eq!(z.value(), arr1(&[1.0, 2.0, 3.0]).into_dyn());
+
+        z.backward(Array::ones(3).into_dyn());
+        assert_eq!(x.grad(), arr1(&[1.0, 0.0, 1.0]).into_dyn());
+        assert_eq!(y.grad(), arr1(&[0.0, 1.0, 0.0]).into_dyn());
+    }
+
+    #[test]
+    fn maximum_minimum_with_infinite_operand() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, -5.0, f32::NEG_INFINITY]).into_dyn());
+        let low = graph.constant(arr1(&[f32::NEG_INFINITY, -1.0, -1.0]).into_dyn());
+        let high = graph.constant(arr1(&[f32::INFINITY, f32::INFINITY, 0.0]).into_dyn());
+
+        let lower = x.maximum(low);
+        let upper = x.minimum(high);
+        lower.forward();
+        upper.forward();
+        assert_eq!(lower.value(), arr1(&[1.0, -1.0, -1.0]).into_dyn());
+        assert_eq!(
+            upper.value(),
+            arr1(&[1.0, -5.0, f32::NEG_INFINITY]).into_dyn()
+        );
+    }
+
+    #[test]
+    fn einsum_matmul() {
+        let a = arr2(&[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn();
+        let b = arr2(&[[1.0, 0.5], [2.0, 1.0], [0.0, 3.0]]).into_dyn();
+
+        let graph = Graph::new();
+        let x = graph.tensor(a.clone());
+        let y = graph.tensor(b.clone());
+        let z = tensor::Tensor::einsum("ij, jk -> ik", x, y);
+        z.forward();
+        z.backward(Array::ones((2, 2)).into_dyn());
+
+        assert_eq!(z.value(), a.matmul(&b));
+        let f = |a: &ArrayD<f32>| a.matmul(&b);
+        assert_close(&x.grad(), &numeric_grad(&a, f), 1e-2);
+    }
+
+    #[test]
+    fn einsum_transpose() {
+        let a = arr2(&[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn();
+
+        let graph = Graph::new();
+        let x = graph.tensor(a.clone());
+        let z = tensor::Tensor::einsum("ij->ji", x, None);
+        z.forward();
+        z.backward(arr2(&[[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]).into_dyn());
+
+        assert_eq!(z.value(), a.t());
+        assert_eq!(
+            x.grad(),
+            arr2(&[[1.0, 3.0, 5.0], [2.0, 4.0, 6.0]]).into_dyn()
+        );
+
+        let t = x.t();
+        t.forward();
+        assert_eq!(t.value(), z.value());
+    }
+
+    #[test]
+    fn einsum_dot() {
+        let a = arr2(&[[1.0, 2.0], [3.0, 4.0]]).into_dyn();
+        let b = arr2(&[[5.0, 6.0], [7.0, 8.0]]).into_dyn();
+
+        let graph = Graph::new();
+        let x = graph.tensor(a.clone());
+        let y = graph.tensor(b.clone());
+        let z = tensor::Tensor::einsum("ij,ij->", x, y);
+        z.forward();
+        z.backward(arr0(1.0).into_dyn());
+
+        assert_eq!(z.value(), arr0(70.0).into_dyn());
+        assert_eq!(x.grad(), b);
+        assert_eq!(y.grad(), a);
+    }
+
+    #[test]
+    fn einsum_outer() {
+        let a = arr1(&[1.0, 2.0]).into_dyn();
+        let b = arr1(&[3.0, 4.0, 5.0]).into_dyn();
+
+        let graph = Graph::new();
+        let x = graph.tensor(a.clone());
+        let y = graph.tensor(b.clone());
+        let z = tensor::Tensor::einsum("i,j->ij", x, y);
+        z.forward();
+        z.backward(Array::ones((2, 3)).into_dyn());
+
+        let outer = arr2(&[[3.0, 4.0, 5.0], [6.0, 8.0, 10.0]]).into_dyn();
+        assert_eq!(z.value(), outer);
+
+        let f = |a: &ArrayD<f32>| {
+            let g = Graph::new();
+            let z = tensor::Tensor::einsum("i,j->ij", g.tensor(a.clone()), g.tensor(b.clone()));
+            z.forward();
+            z.value()
+        };
+        assert_close(&x.grad(), &numeric_grad(&a, f), 1e-2);
+        assert_eq!(y.grad(), arr1(&[3.0, 3.0, 3.0]).into_dyn());
+    }
+
+    #[test]
+    fn tile_sums_gradient_over_copies() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let z = x.tile(vec![3]);
+
+        z.forward();
+        assert_eq!(z.value(), arr1(&[1.0, 2.0, 1.0, 2.0, 1.0, 2.0]).into_dyn());
+
+        z.backward(arr1(&[1.0, 10.0, 2.0, 20.0, 3.0, 30.0]).into_dyn());
+        assert_eq!(x.grad(), arr1(&[6.0, 60.0]).into_dyn());
+    }
+
+    #[test]
+    fn broadcast_to_sums_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[1.0, 2.0, 3.0]]).into_dyn());
+        let z = x.broadcast_to(vec![4, 3]);
+
+        z.forward();
+        assert_eq!(
+            z.value(),
+            Array::from_shape_fn((4, 3), |(_, j)| j as f32 + 1.0).into_dyn()
+        );
+
+        z.backward(Array::from_shape_fn((4, 3), |(i, _)| i as f32).into_dyn());
+        assert_eq!(x.grad(), arr2(&[[6.0, 6.0, 6.0]]).into_dyn());
+    }
+
+    #[test]
+    fn slice_scatters_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(Array::from_shape_fn((3, 3), |(i, j)| (3 * i + j) as f32).into_dyn());
+        let z = x.slice(vec![(0, 2), (0, 2)]);
+
+        z.forward();
+        assert_eq!(z.value(), arr2(&[[0.0, 1.0], [3.0, 4.0]]).into_dyn());
+
+        z.backward(arr2(&[[1.0, 2.0], [3.0, 4.0]]).into_dyn());
+        let expected = arr2(&[[1.0, 2.0, 0.0], [3.0, 4.0, 0.0], [0.0, 0.0, 0.0]]);
+        assert_eq!(x.grad(), expected.into_dyn());
+    }
+
+    #[test]
+    fn gather_accumulates_duplicates() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]).into_dyn());
+        let z = x.gather(0, vec![0, 2, 0]);
+
+        z.forward();
+        assert_eq!(
+            z.value(),
+            arr2(&[[1.0, 2.0], [5.0, 6.0], [1.0, 2.0]]).into_dyn()
+        );
+
+        z.backward(arr2(&[[1.0, 1.0], [2.0, 2.0], [3.0, 3.0]]).into_dyn());
+        assert_eq!(
+            x.grad(),
+            arr2(&[[4.0, 4.0], [0.0, 0.0], [2.0, 2.0]]).into_dyn()
+        );
+    }
+
+    #[test]
+    fn embedding_lookup() {
+        let graph = Graph::new();
+        let weight =
+            graph.tensor(arr2(&[[0.0, 1.0], [2.0, 3.0], [4.0, 5.0], [6.0, 7.0]]).into_dyn());
+        let z = nn::embedding(&graph, weight, &[1, 1, 3]);
+
+        z.forward();
+        assert_eq!(
+            z.value(),
+            arr2(&[[2.0, 3.0], [2.0, 3.0], [6.0, 7.0]]).into_dyn()
+        );
+
+        z.backward(arr2(&[[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]).into_dyn());
+        let expected = arr2(&[[0.0, 0.0], [4.0, 6.0], [0.0, 0.0], [5.0, 6.0]]);
+        assert_eq!(weight.grad(), expected.into_dyn());
+    }
+
+    #[test]
+    fn batch_norm_training() {
+        let x0 = arr2(&[[1.0, 2.0], [3.0, 6.0], [5.0, 4.0], [7.0, 9.0]]).into_dyn();
+        let w = arr2(&[[1.0, -2.0], [0.5, 3.0], [-1.0, 1.0], [2.0, 0.0]]).into_dyn();
+
+        let bn = nn::BatchNorm::default();
+        let graph = Graph::new();
+        let x = graph.tensor(x0.clone());
+        let z = bn.forward(x);
+
+        z.forward();
+        let y = z.value();
+        let mean = y.mean_axis(ndarray::Axis(0)).unwrap();
+        let var = y.mapv(|v| v * v).mean_axis(ndarray::Axis(0)).unwrap();
+        assert_close(&mean, &Array::zeros(2).into_dyn(), 1e-5);
+        assert_close(&var, &Array::ones(2).into_dyn(), 1e-3);
+
+        assert_close(
+            &bn.running_mean().unwrap(),
+            &arr1(&[0.4, 0.525]).into_dyn(),
+            1e-5,
+        );
+
+        z.backward(w.clone());
+        let f = |x: &ArrayD<f32>| {
+            let g = Graph::new();
+            let z = nn::BatchNorm::default().forward(g.tensor(x.clone()));
+            z.forward();
+            z.value() * &w
+        };
+        assert_close(&x.grad(), &numeric_grad(&x0, f), 1e-2);
+    }
+
+    #[test]
+    fn batch_norm_eval() {
+        let bn = nn::BatchNorm::new(0.1, 0.0);
+        bn.set_running_stats(arr1(&[1.0, 2.0]).into_dyn(), arr1(&[4.0, 9.0]).into_dyn());
+        bn.eval();
+
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[3.0, 5.0], [1.0, -1.0]]).into_dyn());
+        let z = bn.forward(x);
+
+        z.forward();
+        assert_close(
+            &z.value(),
+            &arr2(&[[1.0, 1.0], [0.0, -1.0]]).into_dyn(),
+            1e-6,
+        );
+
+        z.backward(Array::ones((2, 2)).into_dyn());
+        assert_close(
+            &x.grad(),
+            &arr2(&[[0.5, 1.0 / 3.0], [0.5, 1.0 / 3.0]]).into_dyn(),
+            1e-6,
+        );
+        assert_eq!(bn.running_mean().unwrap(), arr1(&[1.0, 2.0]).into_dyn());
+    }
+
+    #[test]
+    fn frozen_operand_gets_no_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph
+            .tensor(arr1(&[3.0, 4.0]).into_dyn())
+            .requires_grad(false);
+
+        let z = x * y;
+        z.forward();
+        z.backward(Array::ones(2).into_dyn());
+
+        assert_eq!(x.grad(), arr1(&[3.0, 4.0]).into_dyn());
+        assert!(graph.nodes.borrow()[y.index].borrow().grad.is_none());
+    }
+
+    #[test]
+    fn backward_accumulate_vs_overwrite() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+
+        let z = (x + y) * x;
+        z.forward();
+
+        z.backward_with(Array::ones(2).into_dyn(), true);
+        z.backward_with(Array::ones(2).into_dyn(), true);
+        assert_eq!(x.grad(), arr1(&[10.0, 16.0]).into_dyn());
+        assert_eq!(y.grad(), arr1(&[2.0, 4.0]).into_dyn());
+
+        z.backward_with(Array::ones(2).into_dyn(), false);
+        assert_eq!(x.grad(), arr1(&[5.0, 8.0]).into_dyn());
+        assert_eq!(y.grad(), arr1(&[1.0, 2.0]).into_dyn());
+    }
+
+    #[test]
+    fn leaves_are_listed() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0]).into_dyn());
+        let y = graph.tensor(arr1(&[2.0]).into_dyn());
+        let w = x * y;
+        let z = graph.tensor(arr1(&[3.0]).into_dyn());
+        let _ = w + z;
+
+        let leaves: Vec<usize> = graph.leaves().iter().map(|t| t.index).collect();
+        assert_eq!(leaves, vec![x.index, y.index, z.index]);
+    }
+
+    #[test]
+    fn display_graph() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = graph.tensor(arr1(&[5.0, 6.0]).into_dyn());
+        let w = x * y + z;
+        w.forward();
+
+        let printed = format!("{}", graph);
+        assert!(printed.contains("v3 = Mul(v0, v1)"), "{}", printed);
+        assert!(printed.contains("v4 = Add(v3, v2)"), "{}", printed);
+        assert!(printed.contains("Leaf"), "{}", printed);
+        assert!(printed.contains("[2]"), "{}", printed);
+    }
+
+    #[test]
+    fn constant_never_receives_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let c = graph.constant(arr1(&[3.0, 4.0]).into_dyn());
+
+        let z = (x * c) + c;
+        z.forward();
+        z.backward(Array::ones(2).into_dyn());
+        z.backward(Array::ones(2).into_dyn());
+
+        assert_eq!(z.value(), arr1(&[6.0, 12.0]).into_dyn());
+        assert_eq!(x.grad(), arr1(&[6.0, 8.0]).into_dyn());
+        assert!(graph.nodes.borrow()[c.index].borrow().grad.is_none());
+    }
+
+    #[test]
+    fn softmax_cross_entropy_matches_unfused() {
+        let logits = arr2(&[[1.0, 2.0, 3.0], [1.0, -1.0, 0.5]]).into_dyn();
+        let targets = vec![2, 0];
+
+        // Unfused reference: -mean(log(softmax(x))[target])
+        let unfused = |x: &ArrayD<f32>| {
+            let e = x.mapv(f32::exp);
+            let mut loss = 0.0;
+            for (i, &t) in targets.iter().enumerate() {
+                let row = e.index_axis(ndarray::Axis(0), i);
+                loss -= (row[t] / row.sum()).ln();
+            }
+            arr0(loss / 2.0).into_dyn()
+        };
+
+        let graph = Graph::new();
+        let x = graph.tensor(logits.clone());
+        let z = x.softmax_cross_entropy(targets.clone());
+        z.forward();
+        z.backward(arr0(1.0).into_dyn());
+
+        assert_close(&z.value(), &unfused(&logits), 1e-5);
+        assert_close(&x.grad(), &numeric_grad(&logits, unfused), 1e-3);
+    }
+
+    #[test]
+    fn in_place_ops_match_allocating_ops() {
+        let a = arr2(&[[1.0, -2.0], [3.5, 4.0]]).into_dyn();
+        let b = arr2(&[[0.5, 2.0], [-1.0, 8.0]]).into_dyn();
+
+        let mut x = a.clone();
+        x.add_assign(&b);
+        assert_eq!(x, TensorType::add(&a, &b));
+
+        let mut x = a.clone();
+        x.sub_assign(&b);
+        assert_eq!(x, TensorType::sub(&a, &b));
+
+        let mut x = a.clone();
+        x.mul_assign(&b);
+        assert_eq!(x, TensorType::mul(&a, &b));
+
+        let mut x = a.clone();
+        x.div_assign(&b);
+        assert_eq!(x, TensorType::div(&a, &b));
+
+        let mut x = a.clone();
+        x.scale(-3.0);
+        assert_eq!(x, a.mul_scalar(-3.0));
+    }
+
+    #[test]
+    fn matmul_transposed_matches_explicit_transpose() {
+        let x = arr2(&[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn();
+        let y = arr2(&[[1.0, 0.5], [2.0, 1.0], [0.0, 3.0]]).into_dyn();
+
+        assert_eq!(
+            x.matmul_transposed(&x, false, true),
+            x.matmul(&TensorType::t(&x))
+        );
+        assert_eq!(
+            x.matmul_transposed(&x, true, false),
+            TensorType::t(&x).matmul(&x)
+        );
+        assert_eq!(
+            y.matmul_transposed(&x, true, true),
+            TensorType::t(&y).matmul(&TensorType::t(&x))
+        );
+    }
+
+    #[test]
+    fn hard_sigmoid_regions() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[-4.0, -1.5, 0.0, 3.0, 5.0]).into_dyn());
+        let z = x.hard_sigmoid();
+
+        z.forward();
+        assert_close(
+            &z.value(),
+            &arr1(&[0.0, 0.25, 0.5, 1.0, 1.0]).into_dyn(),
+            1e-6,
+        );
+
+        z.backward(Array::ones(5).into_dyn());
+        let s = 1.0 / 6.0;
+        assert_close(&x.grad(), &arr1(&[0.0, s, s, 0.0, 0.0]).into_dyn(), 1e-6);
+    }
+
+    #[test]
+    fn hard_tanh_regions() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[-2.0, -0.5, 0.25, 1.5]).into_dyn());
+        let z = x.hard_tanh();
+
+        z.forward();
+        assert_eq!(z.value(), arr1(&[-1.0, -0.5, 0.25, 1.0]).into_dyn());
+
+        z.backward(Array::ones(4).into_dyn());
+        assert_eq!(x.grad(), arr1(&[0.0, 1.0, 1.0, 0.0]).into_dyn());
+    }
+
+    #[test]
+    fn elu_continuity_and_gradient() {
+        let elu = |x: &ArrayD<f32>| {
+            let g = Graph::new();
+            let z = g.tensor(x.clone()).elu(0.7);
+            z.forward();
+            z.value()
+        };
+
+        // Continuous at 0 from both sides
+        let near_zero = elu(&arr1(&[-1e-4, 0.0, 1e-4]).into_dyn());
+        assert_close(&near_zero, &Array::zeros(3).into_dyn(), 1e-3);
+
+        let x0 = arr1(&[-2.0, -0.5, 0.5, 2.0]).into_dyn();
+        let graph = Graph::new();
+        let x = graph.tensor(x0.clone());
+        let z = x.elu(0.7);
+        z.forward();
+        z.backward(Array::ones(4).into_dyn());
+
+        assert_close(&x.grad(), &numeric_grad(&x0, elu), 1e-3);
+
+        // exp(100) overflows, which must not leak into the positive branch
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[100.0, -100.0]).into_dyn());
+        let z = x.elu(0.7);
+        z.forward();
+        z.backward(Array::ones(2).into_dyn());
+        assert_eq!(z.value(), arr1(&[100.0, -0.7]).into_dyn());
+        assert_close(&x.grad(), &arr1(&[1.0, 0.0]).into_dyn(), 1e-6);
+    }
+
+    #[test]
+    fn silu_gradient() {
+        let silu = |x: &ArrayD<f32>| {
+            let g = Graph::new();
+            let z = g.tensor(x.clone()).silu();
+            z.forward();
+            z.value()
+        };
+
+        let x0 = arr1(&[-3.0, -0.5, 0.0, 0.7, 2.5]).into_dyn();
+        let graph = Graph::new();
+        let x = graph.tensor(x0.clone());
+        let z = x.silu();
+        z.forward();
+        z.backward(Array::ones(5).into_dyn());
+
+        let sig = |v: f32| 1.0 / (1.0 + (-v).exp());
+        assert_close(&z.value(), &x0.mapv(|v| v * sig(v)), 1e-6);
+        assert_close(&x.grad(), &numeric_grad(&x0, silu), 1e-3);
+    }
+
+    #[test]
+    fn nll_loss_places_gradient_at_targets() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[-0.5, -1.0], [-2.0, -0.25]]).into_dyn());
+        let z = x.nll_loss(vec![0, 1]);
+
+        z.forward();
+        assert_close(&z.value(), &arr0(0.375).into_dyn(), 1e-6);
+
+        z.backward(arr0(1.0).into_dyn());
+        assert_eq!(x.grad(), arr2(&[[-0.5, 0.0], [0.0, -0.5]]).into_dyn());
+    }
+
+    #[test]
+    fn log_softmax_normalizes_rows() {
+        let x0 = arr2(&[[1.0, 2.0, 3.0], [-1.0, 0.0, 4.0]]).into_dyn();
+        let w = arr2(&[[1.0, 0.0, -1.0], [0.5, 2.0, 1.0]]).into_dyn();
+
+        let graph = Graph::new();
+        let x = graph.tensor(x0.clone());
+        let z = x.log_softmax(1);
+        z.forward();
+
+        let row_sums = z.value().mapv(f32::exp).sum_axis(ndarray::Axis(1));
+        assert_close(&row_sums, &Array::ones(2).into_dyn(), 1e-6);
+
+        z.backward(w.clone());
+        let f = |x: &ArrayD<f32>| {
+            let g = Graph::new();
+            let z = g.tensor(x.clone()).log_softmax(1);
+            z.forward();
+            z.value() * &w
+        };
+        assert_close(&x.grad(), &numeric_grad(&x0, f), 1e-3);
+    }
+
+    #[test]
+    fn bce_loss_probabilities() {
+        let graph = Graph::new();
+        let p = graph.tensor(arr1(&[0.8, 0.25]).into_dyn());
+        let t = graph.constant(arr1(&[1.0, 0.0]).into_dyn());
+        let z = p.bce_loss(t);
+
+        z.forward();
+        let expected = -(0.8f32.ln() + 0.75f32.ln()) / 2.0;
+        assert_close(&z.value(), &arr0(expected).into_dyn(), 1e-6);
+
+        z.backward(arr0(1.0).into_dyn());
+        let grad = arr1(&[-0.2 / (0.8 * 0.2 * 2.0), 0.25 / (0.25 * 0.75 * 2.0)]);
+        assert_close(&p.grad(), &grad.into_dyn(), 1e-5);
+    }
+
+    #[test]
+    fn bce_with_logits_matches_probabilities() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[2.0, -1.0]).into_dyn());
+        let t = graph.constant(arr1(&[1.0, 0.0]).into_dyn());
+        let z = x.bce_with_logits(t);
+
+        z.forward();
+        let sig = |v: f32| 1.0 / (1.0 + (-v).exp());
+        let (p0, p1) = (sig(2.0), sig(-1.0));
+        let expected = -(p0.ln() + (1.0 - p1).ln()) / 2.0;
+        assert_close(&z.value(), &arr0(expected).into_dyn(), 1e-6);
+
+        z.backward(arr0(1.0).into_dyn());
+        let grad = arr1(&[(p0 - 1.0) / 2.0, p1 / 2.0]);
+        assert_close(&x.grad(), &grad.into_dyn(), 1e-6);
+    }
+
+    #[test]
+    fn addmm_matches_composed() {
+        let bias_v = arr2(&[[0.5, -1.0], [2.0, 0.0]]).into_dyn();
+        let a_v = arr2(&[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn();
+        let b_v = arr2(&[[1.0, 0.5], [2.0, 1.0], [0.0, 3.0]]).into_dyn();
+
+        let graph = Graph::new();
+        let bias = graph.tensor(bias_v.clone());
+        let a = graph.tensor(a_v.clone());
+        let b = graph.tensor(b_v.clone());
+        let z = tensor::Tensor::addmm(bias, a, b);
+
+        z.forward();
+        assert_eq!(z.value(), &bias_v + &a_v.matmul(&b_v));
+
+        let ones = ArrayD::ones(vec![2, 2]);
+        z.backward(ones.clone());
+        assert_eq!(bias.grad(), ones);
+        assert_eq!(a.grad(), ones.matmul(&TensorType::t(&b_v)));
+        assert_eq!(b.grad(), TensorType::t(&a_v).matmul(&ones));
+    }
+
+    #[test]
+    fn matmul_backends_agree() {
+        use tensor::{matmul_backend, set_matmul_backend, MatmulBackend};
+
+        let x = Array::from_shape_fn((7, 5), |(i, j)| (i * 5 + j) as f32 * 0.1).into_dyn();
+        let y = Array::from_shape_fn((5, 3), |(i, j)| (i as f32 - j as f32) * 0.5).into_dyn();
+
+        assert_eq!(matmul_backend(), MatmulBackend::Ndarray);
+        let fast = x.matmul(&y);
+        let fast_t = x.matmul_transposed(&x, false, true);
+
+        set_matmul_backend(MatmulBackend::Naive);
+        let naive = x.matmul(&y);
+        let naive_t = x.matmul_transposed(&x, false, true);
+        set_matmul_backend(MatmulBackend::Ndarray);
+
+        assert_close(&fast, &naive, 1e-4);
+        assert_close(&fast_t, &naive_t, 1e-4);
+    }
+
+    #[test]
+    fn backward_scaled_doubles_grads() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = (x + y) * x;
+        z.forward();
+
+        z.backward_with(ArrayD::ones(vec![2]), false);
+        let (gx, gy) = (x.grad(), y.grad());
+
+        // Zero the leaf gradients so the scaled pass accumulates from scratch
+        z.backward_with(ArrayD::zeros(vec![2]), false);
+        z.backward_scaled(ArrayD::ones(vec![2]), 2.0);
+        assert_eq!(x.grad(), &gx * 2.0);
+        assert_eq!(y.grad(), &gy * 2.0);
+
+        graph.unscale_grads(2.0);
+        assert_eq!(x.grad(), gx);
+        assert_eq!(y.grad(), gy);
+    }
+
+    #[test]
+    fn try_value_and_grad_report_missing_passes() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let z = x * x;
+
+        assert_eq!(z.try_value(), Err(GraphError::NotComputed { index: 1 }));
+
+        z.forward();
+        assert_eq!(z.try_value(), Ok(arr1(&[1.0, 4.0]).into_dyn()));
+        assert_eq!(
+            x.try_grad(),
+            Err(GraphError::NoGradient {
+                index: 0,
+                forward_ran: true
+            })
+        );
+
+        z.backward(ArrayD::ones(vec![2]));
+        assert_eq!(x.try_grad(), Ok(arr1(&[2.0, 4.0]).into_dyn()));
+    }
+
+    #[test]
+    fn graph_values_snapshot() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = x * y;
+
+        assert_eq!(graph.values()[2], None);
+
+        z.forward();
+        let values = graph.values();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0], Some(arr1(&[1.0, 2.0]).into_dyn()));
+        assert_eq!(values[1], Some(arr1(&[3.0, 4.0]).into_dyn()));
+        assert_eq!(values[2], Some(arr1(&[3.0, 8.0]).into_dyn()));
+    }
+
+    #[test]
+    #[should_panic(expected = "matrix exponential requires a square matrix, got [2, 3]")]
+    fn expm_rejects_non_square() {
+        let graph = Graph::new();
+        let x = graph.tensor(ArrayD::zeros(vec![2, 3]));
+        x.expm();
+    }
+
+    #[test]
+    fn cumsum_reverses_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        let z = x.cumsum(0);
+
+        z.forward();
+        assert_eq!(z.value(), arr1(&[1.0, 3.0, 6.0]).into_dyn());
+
+        z.backward(ArrayD::ones(vec![3]));
+        assert_eq!(x.grad(), arr1(&[3.0, 2.0, 1.0]).into_dyn());
+    }
+
+    #[test]
+    fn stack_splits_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = x.stack(y);
+
+        z.forward();
+        assert_eq!(z.value(), arr2(&[[1.0, 2.0], [3.0, 4.0]]).into_dyn());
+
+        z.backward(arr2(&[[1.0, 2.0], [3.0, 4.0]]).into_dyn());
+        assert_eq!(x.grad(), arr1(&[1.0, 2.0]).into_dyn());
+        assert_eq!(y.grad(), arr1(&[3.0, 4.0]).into_dyn());
+    }
+
+    #[test]
+    fn split_halves_accumulate_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0, 4.0]).into_dyn());
+        let (a, b) = x.split(0, 2);
+        let z = a * b;
+        let spec = graph.spec(false).unwrap();
+
+        z.forward();
+        assert_eq!(a.value(), arr1(&[1.0, 2.0]).into_dyn());
+        assert_eq!(b.value(), arr1(&[3.0, 4.0]).into_dyn());
+
+        z.backward(ArrayD::ones(vec![2]));
+        assert_eq!(x.grad(), arr1(&[3.0, 4.0, 1.0, 2.0]).into_dyn());
+
+        // Forward leaves the ops as built, and the tail follows the input when it grows
+        x.set_value(arr1(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).into_dyn());
+        b.forward();
+        assert_eq!(b.value(), arr1(&[3.0, 4.0, 5.0, 6.0]).into_dyn());
+        assert_eq!(graph.spec(false).unwrap()[1..], spec[1..]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn slice_past_the_end_panics() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        x.slice(vec![(1, 5)]).forward();
+    }
+
+    #[test]
+    fn masked_fill_blocks_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0, 4.0]).into_dyn());
+        let mask = arr1(&[false, true, false, true]).into_dyn();
+        let z = x.masked_fill(mask, f32::NEG_INFINITY);
+
+        z.forward();
+        let expected = arr1(&[1.0, f32::NEG_INFINITY, 3.0, f32::NEG_INFINITY]);
+        assert_eq!(z.value(), expected.into_dyn());
+
+        z.backward(ArrayD::ones(vec![4]));
+        assert_eq!(x.grad(), arr1(&[1.0, 0.0, 1.0, 0.0]).into_dyn());
+    }
+
+    #[test]
+    fn rng_is_reproducible() {
+        assert_eq!(rng::randn(&[3, 4], 7), rng::randn(&[3, 4], 7));
+        assert_ne!(rng::randn(&[3, 4], 7), rng::randn(&[3, 4], 8));
+
+        let u = rng::uniform(&[1000], -2.0, 3.0, 1);
+        assert_eq!(u, rng::uniform(&[1000], -2.0, 3.0, 1));
+        assert!(u.iter().all(|&v| (-2.0..3.0).contains(&v)));
+
+        let graph = Graph::new();
+        let x = graph.randn(&[2, 2], 3);
+        assert_eq!(x.value(), rng::randn(&[2, 2], 3));
+        let y = graph.uniform(&[2, 2], 0.0, 1.0, 3);
+        assert_eq!(y.value(), rng::uniform(&[2, 2], 0.0, 1.0, 3));
+    }
+
+    #[test]
+    fn initializers_have_expected_variance() {
+        let variance = |a: &ArrayD<f32>| {
+            let mean = a.mean().unwrap();
+            a.mapv(|v| (v - mean).powi(2)).mean().unwrap()
+        };
+
+        let graph = Graph::new();
+        let w = graph.xavier(&[200, 300], 0).value();
+        assert!((variance(&w) - 2.0 / 500.0).abs() < 2e-4);
+
+        let w = graph.kaiming(&[200, 300], init::FanMode::FanIn, 0).value();
+        assert!((variance(&w) - 2.0 / 200.0).abs() < 5e-4);
+    }
+
+    #[test]
+    fn deep_clone_is_independent() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = x * y;
+        z.forward();
+        z.backward(ArrayD::ones(vec![2]));
+
+        let copy = graph.deep_clone();
+        let x2 = copy.tensor_at(x.index);
+        let z2 = copy.tensor_at(z.index);
+        assert_eq!(z2.value(), z.value());
+
+        z2.backward(arr1(&[10.0, 10.0]).into_dyn());
+        assert_eq!(x2.grad(), arr1(&[33.0, 44.0]).into_dyn());
+        assert_eq!(x.grad(), arr1(&[3.0, 4.0]).into_dyn());
+        assert_eq!(y.grad(), arr1(&[1.0, 2.0]).into_dyn());
+    }
+
+    #[test]
+    fn deep_clone_owns_op_state() {
+        use functions::{Function, OneValuedFn, TwoValuedFnEnum};
+        use tensor::Raw;
+
+        // Adds how often it has run
+        #[derive(Clone)]
+        struct AddCalls(f32);
+        impl<'d> OneValuedFn<'d, ArrayD<f32>> for AddCalls {
+            fn forward(&mut self, t_a: Raw<'d, ArrayD<f32>>) -> Raw<'d, ArrayD<f32>> {
+                self.0 += 1.0;
+                Raw::new(t_a.value().add_scalar(self.0))
+            }
+            fn backward(&self, grad: Raw<'d, ArrayD<f32>>) -> [Option<Raw<'d, ArrayD<f32>>>; 2] {
+                [Some(grad), None]
+            }
+            fn boxed_clone(&self) -> Option<Box<dyn OneValuedFn<'d, ArrayD<f32>> + 'd>> {
+                Some(Box::new(self.clone()))
+            }
+        }
+
+        let graph = Graph::new();
+        let layer = nn::BatchNorm::new(0.5, 1e-5);
+        let x = graph.tensor(arr2(&[[1.0], [3.0]]).into_dyn());
+        let y = layer.forward(x) * x;
+        let calls = graph.apply_one(x, Box::new(AddCalls(0.0)));
+        y.forward();
+        calls.forward();
+        let mean = layer.running_mean().unwrap();
+
+        let copy = graph.deep_clone();
+        match &copy.nodes.borrow()[y.index].borrow().func {
+            Function::Two(TwoValuedFnEnum::Mul(mul)) => assert!(mul.x_ctx.is_none()),
+            _ => unreachable!(),
+        }
+
+        // Running the copy leaves the layer's statistics and the original op alone
+        copy.tensor_at(x.index)
+            .set_value(arr2(&[[5.0], [9.0]]).into_dyn());
+        let y2 = copy.tensor_at(y.index);
+        y2.backward(ArrayD::ones(vec![2, 1]));
+        assert_eq!(layer.running_mean().unwrap(), mean);
+        assert_close(&y2.value(), &arr2(&[[-5.0], [9.0]]).into_dyn(), 1e-3);
+
+        let calls2 = copy.tensor_at(calls.index);
+        calls2.forward();
+        assert_eq!(calls2.value(), arr2(&[[7.0], [11.0]]).into_dyn());
+        calls.forward();
+        assert_eq!(calls.value(), arr2(&[[3.0], [5.0]]).into_dyn());
+    }
+
+    #[test]
+    fn set_value_recomputes_only_dependents() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let a = y.scale(3.0);
+        let z = x * a;
+
+        graph.enable_profiling();
+        z.forward();
+        let calls = |op| graph.profile_report()[op].calls;
+        assert_eq!((calls("Scale"), calls("Mul")), (1, 1));
+
+        z.forward();
+        assert_eq!((calls("Scale"), calls("Mul")), (1, 1));
+
+        x.set_value(arr1(&[2.0, 0.0]).into_dyn());
+        z.forward();
+        assert_eq!((calls("Scale"), calls("Mul")), (1, 2));
+        assert_eq!(z.value(), arr1(&[18.0, 0.0]).into_dyn());
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot multiply tensors of shapes [2, 3] and [2, 2]")]
+    fn mul_reports_shape_mismatch() {
+        let graph = Graph::new();
+        let x = graph.tensor(ArrayD::ones(vec![2, 3]));
+        let y = graph.tensor(ArrayD::ones(vec![2, 2]));
+        (x * y).forward();
+    }
+
+    #[test]
+    fn rsqrt_value_and_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr0(4.0).into_dyn());
+        let z = x.rsqrt();
+
+        z.forward();
+        assert_eq!(z.value(), arr0(0.5).into_dyn());
+
+        z.backward(arr0(1.0).into_dyn());
+        assert_eq!(x.grad(), arr0(-0.0625).into_dyn());
+    }
+
+    #[test]
+    fn rms_norm_scale_and_gradients() {
+        let x_v = arr2(&[[1.0, -2.0, 3.0], [0.5, 0.0, -1.5]]).into_dyn();
+        let w_v = arr1(&[1.0, 2.0, 0.5]).into_dyn();
+        let upstream = arr2(&[[1.0, 0.5, -1.0], [2.0, -1.0, 0.25]]).into_dyn();
+        let eps = 1e-5;
+
+        let rms_norm = |x: &ArrayD<f32>, w: &ArrayD<f32>| {
+            let ms = x.mapv(|v| v * v).mean_axis(ndarray::Axis(1)).unwrap();
+            let inv = ms
+                .mapv(|v| 1.0 / (v + eps).sqrt())
+                .insert_axis(ndarray::Axis(1));
+            (x * &inv * w) * &upstream
+        };
+
+        let graph = Graph::new();
+        let x = graph.tensor(x_v.clone());
+        let w = graph.tensor(w_v.clone());
+        let z = nn::rms_norm(&graph, x, w, eps);
+
+        z.forward();
+        let x_hat = z.value() / &w_v;
+        let rms = x_hat.mapv(|v| v * v).mean_axis(ndarray::Axis(1)).unwrap();
+        assert_close(&rms, &ArrayD::ones(vec![2]), 1e-4);
+
+        z.backward(upstream.clone());
+        let gx = numeric_grad(&x_v, |x| rms_norm(x, &w_v));
+        let gw = numeric_grad(&w_v, |w| rms_norm(&x_v, w));
+        assert_close(&x.grad(), &gx, 1e-2);
+        assert_close(&w.grad(), &gw, 1e-2);
+    }
+
+    #[test]
+    fn attention_matches_reference() {
+        let q_v = arr2(&[[1.0, 0.0], [0.5, -1.0]]).into_dyn();
+        let k_v = arr2(&[[0.2, 1.0], [-0.3, 0.4], [1.0, 1.0]]).into_dyn();
+        let v_v = arr2(&[[1.0, 2.0], [0.0, -1.0], [3.0, 0.5]]).into_dyn();
+
+        let reference = |q: &ArrayD<f32>, k: &ArrayD<f32>, v: &ArrayD<f32>| {
+            let scores = q.matmul(&TensorType::t(k)) / 2f32.sqrt();
+            let e = scores.mapv(f32::exp);
+            let p = &e / &e.sum_axis(ndarray::Axis(1)).insert_axis(ndarray::Axis(1));
+            p.matmul(v)
+        };
+
+        let graph = Graph::new();
+        let q = graph.tensor(q_v.clone());
+        let k = graph.tensor(k_v.clone());
+        let v = graph.tensor(v_v.clone());
+        let z = nn::scaled_dot_product_attention(q, k, v, None);
+
+        z.forward();
+        assert_close(&z.value(), &reference(&q_v, &k_v, &v_v), 1e-5);
+
+        z.backward(ArrayD::ones(vec![2, 2]));
+        let gq = numeric_grad(&q_v, |q| reference(q, &k_v, &v_v));
+        let gk = numeric_grad(&k_v, |k| reference(&q_v, k, &v_v));
+        let gv = numeric_grad(&v_v, |v| reference(&q_v, &k_v, v));
+        assert_close(&q.grad(), &gq, 1e-2);
+        assert_close(&k.grad(), &gk, 1e-2);
+        assert_close(&v.grad(), &gv, 1e-2);
+
+        let graph = Graph::new();
+        let mask = arr2(&[[false, true, true], [false, false, true]]).into_dyn();
+        let q = graph.tensor(q_v.clone()).scale(1.0);
+        let z =
+            nn::scaled_dot_product_attention(q, graph.tensor(k_v), graph.tensor(v_v), Some(mask));
+        // Building the graph runs nothing
+        assert!(!q.has_value());
+        z.forward();
+        assert_close(
+            &z.value().slice_ranges(&[(0, 1)]),
+            &arr2(&[[1.0, 2.0]]).into_dyn(),
+            1e-6,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "backward seed has shape [] but the output has shape [2, 2]")]
+    fn backward_rejects_mismatched_seed() {
+        let graph = Graph::new();
+        let x = graph.tensor(ArrayD::ones(vec![2, 2]));
+        let z = x * x;
+        z.forward();
+        z.backward(arr0(1.0).into_dyn());
+    }
+
+    #[test]
+    fn pooled_backward_matches_unpooled() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = (x * y) * x;
+        z.forward();
+
+        z.backward_with(ArrayD::ones(vec![2]), false);
+        let (gx, gy) = (x.grad(), y.grad());
+
+        graph.set_pooling(true);
+        for _ in 0..3 {
+            z.backward_with(ArrayD::ones(vec![2]), false);
+            assert_eq!(x.grad(), gx);
+            assert_eq!(y.grad(), gy);
+        }
+    }
+
+    #[test]
+    fn sum_to_reduces_broadcast_axes() {
+        let g = Array::from_shape_fn((4, 3), |(i, j)| (3 * i + j) as f32).into_dyn();
+        assert_eq!(g.sum_to(&[1, 3]), arr2(&[[18.0, 22.0, 26.0]]).into_dyn());
+        assert_eq!(g.sum_to(&[]), arr0(66.0).into_dyn());
+        assert_eq!(g.sum_to(&[4, 3]), g);
+
+        let graph = Graph::new();
+        let x = graph.tensor(ArrayD::ones(vec![4, 3]));
+        let b = graph.tensor(arr2(&[[1.0, 2.0, 3.0]]).into_dyn());
+        let z = x * b + b;
+        z.forward();
+        z.backward(g.clone());
+        assert_eq!(b.grad(), g.sum_to(&[1, 3]) * 2.0);
+    }
+
+    #[test]
+    fn spectral_norm_of_known_matrix() {
+        // Singular values 3 and 1
+        let w_v = arr2(&[[2.0, 1.0], [1.0, 2.0]]).into_dyn();
+
+        let graph = Graph::new();
+        let w = graph.tensor(w_v.clone());
+        let z = w.spectral_norm(20);
+        z.forward();
+        assert_close(&z.value(), &arr0(3.0).into_dyn(), 1e-4);
+
+        z.backward(arr0(1.0).into_dyn());
+        let f = |w: &ArrayD<f32>| {
+            let g = Graph::new();
+            let z = g.tensor(w.clone()).spectral_norm(20);
+            z.forward();
+            z.value()
+        };
+        assert_close(&w.grad(), &numeric_grad(&w_v, f), 1e-2);
+        assert_close(&w.grad(), &ArrayD::from_elem(vec![2, 2], 0.5), 1e-4);
+    }
+
+    #[test]
+    fn backward_to_prunes_irrelevant_branch() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let w = graph.tensor(arr1(&[5.0, 6.0]).into_dyn());
+        let branch = y * w;
+        let z = x * x + branch;
+        z.forward();
+
+        z.backward_to(ArrayD::ones(vec![2]), &[x.index]);
+        assert_eq!(x.grad(), arr1(&[2.0, 4.0]).into_dyn());
+
+        let nodes = graph.nodes.borrow();
+        let ctx = nodes[branch.index].borrow().ctx;
+        assert!(ctx.iter().all(Option::is_none));
+        assert!(nodes[y.index].borrow().grad.is_none());
+        assert!(nodes[w.index].borrow().grad.is_none());
+    }
+
+    #[test]
+    fn grad_summary_of_mul_chain() {
+        let graph = Graph::new();
+        let w = graph.tensor(arr1(&[0.5, -0.5]).into_dyn());
+        let mut z = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        for _ in 0..10 {
+            z = z * w;
+        }
+        z.forward();
+        z.backward(ArrayD::ones(vec![2]));
+
+        let summary = graph.grad_summary();
+        assert_eq!(summary.len(), graph.len());
+        assert!(summary
+            .iter()
+            .all(|&(_, mean, max)| mean.is_finite() && max.is_finite() && mean <= max));
+        // The input gradient shrinks by 0.5 per multiply
+        let (_, mean, _) = summary[1];
+        assert_close(
+            &arr0(mean).into_dyn(),
+            &arr0(0.5f32.powi(10)).into_dyn(),
+            1e-9,
+        );
+    }
+
+    #[test]
+    fn fro_norm_value_and_gradient() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = x.fro_norm();
+        z.forward();
+        assert_eq!(z.value(), arr0(5.0).into_dyn());
+        z.backward(arr0(1.0).into_dyn());
+        assert_close(&x.grad(), &arr1(&[0.6, 0.8]).into_dyn(), 1e-6);
+
+        let graph = Graph::new();
+        let x = graph.tensor(ArrayD::zeros(vec![2]));
+        let z = x.fro_norm();
+        z.forward();
+        z.backward(arr0(1.0).into_dyn());
+        assert_eq!(x.grad(), ArrayD::<f32>::zeros(vec![2]));
+    }
+
+    #[test]
+    fn kron_product_and_gradient() {
+        let a_v = arr2(&[[1.0, 2.0], [3.0, 4.0]]).into_dyn();
+        let b_v = arr2(&[[0.0, 5.0], [6.0, 7.0]]).into_dyn();
+        let upstream = Array::from_shape_fn((4, 4), |(i, j)| (i * 4 + j) as f32 * 0.1).into_dyn();
+
+        let graph = Graph::new();
+        let a = graph.tensor(a_v.clone());
+        let b = graph.tensor(b_v.clone());
+        let z = a.kron(b);
+        z.forward();
+
+        let expected = arr2(&[
+            [0.0, 5.0, 0.0, 10.0],
+            [6.0, 7.0, 12.0, 14.0],
+            [0.0, 15.0, 0.0, 20.0],
+            [18.0, 21.0, 24.0, 28.0],
+        ]);
+        assert_eq!(z.value(), expected.into_dyn());
+
+        z.backward(upstream.clone());
+        let kron = |a: &ArrayD<f32>, b: &ArrayD<f32>| {
+            let g = Graph::new();
+            let z = g.tensor(a.clone()).kron(g.tensor(b.clone()));
+            z.forward();
+            z.value() * &upstream
+        };
+        assert_close(&a.grad(), &numeric_grad(&a_v, |a| kron(a, &b_v)), 1e-2);
+        assert_close(&b.grad(), &numeric_grad(&b_v, |b| kron(&a_v, b)), 1e-2);
+    }
+
+    #[test]
+    fn commutator_and_anticommutator() {
+        let a_v = arr2(&[[1.0, 2.0], [0.5, -1.0]]).into_dyn();
+        let b_v = arr2(&[[0.0, 1.0], [3.0, 2.0]]).into_dyn();
+        let upstream = arr2(&[[1.0, -2.0], [0.5, 3.0]]).into_dyn();
+
+        let graph = Graph::new();
+        let a = graph.tensor(a_v.clone());
+        let z = a.commutator(a);
+        z.forward();
+        assert_eq!(z.value(), ArrayD::<f32>::zeros(vec![2, 2]));
+
+        for &anti in &[false, true] {
+            let graph = Graph::new();
+            let a = graph.tensor(a_v.clone());
+            let b = graph.tensor(b_v.clone());
+            let z = if anti {
+                a.anticommutator(b)
+            } else {
+                a.commutator(b)
+            };
+            z.forward();
+            z.backward(upstream.clone());
+
+            let bracket = |a: &ArrayD<f32>, b: &ArrayD<f32>| {
+                let (ab, ba) = (a.matmul(b), b.matmul(a));
+                if anti {
+                    ab + ba
+                } else {
+                    ab - ba
+                }
+            };
+            let f = |a: &ArrayD<f32>, b: &ArrayD<f32>| bracket(a, b) * &upstream;
+            assert_eq!(z.value(), bracket(&a_v, &b_v));
+            assert_close(&a.grad(), &numeric_grad(&a_v, |a| f(a, &b_v)), 1e-2);
+            assert_close(&b.grad(), &numeric_grad(&b_v, |b| f(&a_v, b)), 1e-2);
+        }
+    }
+
+    #[test]
+    fn expm_so3_rotation_and_gradient() {
+        let theta = 0.7f32;
+        let generator = arr2(&[[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 0.0]]).into_dyn();
+        let k_v = &generator * theta;
+        let upstream = Array::from_shape_fn((3, 3), |(i, j)| (i as f32 - j as f32) * 0.3 + 0.1);
+        let upstream = upstream.into_dyn();
+
+        let graph = Graph::new();
+        let k = graph.tensor(k_v.clone());
+        let z = k.expm_so3();
+        z.forward();
+
+        let (s, c) = theta.sin_cos();
+        let rotation = arr2(&[[c, -s, 0.0], [s, c, 0.0], [0.0, 0.0, 1.0]]).into_dyn();
+        assert_close(&z.value(), &rotation, 1e-6);
+
+        z.backward(upstream.clone());
+        let rodrigues = |k: &ArrayD<f32>| {
+            let theta = (k.mapv(|x| x * x).sum() / 2.0).sqrt();
+            let (a, b) = (theta.sin() / theta, (1.0 - theta.cos()) / (theta * theta));
+            let r = ArrayD::<f32>::eye_like(k) + k * a + k.matmul(k) * b;
+            r * &upstream
+        };
+        assert_close(&k.grad(), &numeric_grad(&k_v, rodrigues), 1e-2);
+    }
+
+    #[test]
+    fn quat_exp_log_round_trip() {
+        let v_v = arr1(&[0.1, -0.2, 0.05]).into_dyn();
+        let weights = arr1(&[1.0, -2.0, 0.5, 3.0]).into_dyn();
+
+        let graph = Graph::new();
+        let v = graph.tensor(v_v.clone());
+        let q = v.quat_exp();
+        let back = q.quat_log();
+        back.forward();
+
+        let norm = q.value().mapv(|x| x * x).sum();
+        assert_close(&arr0(norm).into_dyn(), &arr0(1.0).into_dyn(), 1e-6);
+        assert_close(&back.value(), &v_v, 1e-6);
+
+        let exp = |v: &ArrayD<f32>| {
+            let g = Graph::new();
+            let q = g.tensor(v.clone()).quat_exp();
+            q.forward();
+            q.value() * &weights
+        };
+        q.backward(weights.clone());
+        assert_close(&v.grad(), &numeric_grad(&v_v, exp), 1e-2);
+
+        let q_v = q.value();
+        let log = |q: &ArrayD<f32>| {
+            let g = Graph::new();
+            let v = g.tensor(q.clone()).quat_log();
+            v.forward();
+            v.value() * &arr1(&[1.0, 2.0, -1.0])
+        };
+        let graph = Graph::new();
+        let q = graph.tensor(q_v.clone());
+        let z = q.quat_log();
+        z.forward();
+        z.backward(arr1(&[1.0, 2.0, -1.0]).into_dyn());
+        assert_close(&q.grad(), &numeric_grad(&q_v, log), 2e-2);
+
+        // Close to pi the quaternion's w is near -1 and its vector part is tiny
+        let v_v = arr1(&[0.0, 0.0, 3.14155]).into_dyn();
+        let graph = Graph::new();
+        let back = graph.tensor(v_v.clone()).quat_exp().quat_log();
+        back.forward();
+        assert_close(&back.value(), &v_v, 1e-4);
+    }
+
+    #[test]
+    fn custom_ops_are_differentiable() {
+        use functions::{OneValuedFn, TwoValuedFn};
+        use tensor::Raw;
+
+        struct Double;
+        impl<'d> OneValuedFn<'d, ArrayD<f32>> for Double {
+            fn forward(&mut self, t_a: Raw<'d, ArrayD<f32>>) -> Raw<'d, ArrayD<f32>> {
+                Raw::new(t_a.value().mul_scalar(2.0))
+            }
+            fn backward(&self, grad: Raw<'d, ArrayD<f32>>) -> [Option<Raw<'d, ArrayD<f32>>>; 2] {
+                [Some(Raw::new(grad.value().mul_scalar(2.0))), None]
+            }
+        }
+
+        struct Difference;
+        impl<'d> TwoValuedFn<'d, ArrayD<f32>> for Difference {
+            fn forward(
+                &mut self,
+                t_a: Raw<'d, ArrayD<f32>>,
+                t_b: Raw<'d, ArrayD<f32>>,
+            ) -> Raw<'d, ArrayD<f32>> {
+                Raw::new(t_a.value().sub(t_b.value()))
+            }
+            fn backward(&self, grad: Raw<'d, ArrayD<f32>>) -> [Option<Raw<'d, ArrayD<f32>>>; 2] {
+                let neg = grad.value().mul_scalar(-1.0);
+                [Some(grad), Some(Raw::new(neg))]
+            }
+        }
+
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 5.0]).into_dyn());
+        let d = graph.apply_one(x, Box::new(Double));
+        let z = graph.apply_two(d, y, Box::new(Difference)) * x;
+
+        z.forward();
+        assert_eq!(z.value(), arr1(&[-1.0, -2.0]).into_dyn());
+        assert_eq!(graph.nodes.borrow()[d.index].borrow().func.name(), "Double");
+
+        z.backward(ArrayD::ones(vec![2]));
+        // d/dx (2x - y) x = 4x - y
+        assert_eq!(x.grad(), arr1(&[1.0, 3.0]).into_dyn());
+        assert_eq!(y.grad(), arr1(&[-1.0, -2.0]).into_dyn());
+    }
+
+    #[test]
+    fn custom_ops_rerun_on_every_forward() {
+        use functions::OneValuedFn;
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use tensor::Raw;
+
+        // Adds the number of times it has run, so each pass sees different state
+        struct AddCalls(Rc<Cell<f32>>);
+        impl<'d> OneValuedFn<'d, ArrayD<f32>> for AddCalls {
+            fn forward(&mut self, t_a: Raw<'d, ArrayD<f32>>) -> Raw<'d, ArrayD<f32>> {
+                self.0.set(self.0.get() + 1.0);
+                Raw::new(t_a.value().add_scalar(self.0.get()))
+            }
+            fn backward(&self, grad: Raw<'d, ArrayD<f32>>) -> [Option<Raw<'d, ArrayD<f32>>>; 2] {
+                [Some(grad), None]
+            }
+        }
+
+        let calls = Rc::new(Cell::new(0.0));
+        let graph = Graph::new();
+        let c = graph.constant(arr1(&[1.0, 2.0]).into_dyn());
+        let z = graph.apply_one(c, Box::new(AddCalls(calls.clone())));
+        z.forward();
+        assert_eq!(z.value(), arr1(&[2.0, 3.0]).into_dyn());
+        z.forward();
+        assert_eq!(z.value(), arr1(&[3.0, 4.0]).into_dyn());
+
+        graph.fold_constants();
+        z.forward();
+        assert_eq!(calls.get(), 3.0);
+    }
+
+    #[test]
+    fn tensor_shape_and_ndim() {
+        let graph = Graph::new();
+        let x = graph.tensor(ArrayD::zeros(vec![2, 3]));
+        assert_eq!(x.shape(), vec![2, 3]);
+        assert_eq!(x.ndim(), 2);
+
+        let z = x.matmul(graph.tensor(ArrayD::zeros(vec![3, 4])));
+        z.forward();
+        assert_eq!(z.shape(), vec![2, 4]);
+    }
+
+    #[test]
+    fn comparisons_produce_masks() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 2.0, 1.0]).into_dyn());
+        let masks = [x.gt(y), x.lt(y), x.ge(y), x.le(y), x.eq(y)];
+        let expected = [
+            [0.0, 0.0, 1.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 1.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        for (m, e) in masks.iter().zip(expected.iter()) {
+            m.forward();
+            assert_eq!(m.value(), arr1(e).into_dyn());
+        }
+
+        masks[0].backward(ArrayD::ones(vec![3]));
+        assert_eq!(x.grad(), ArrayD::<f32>::zeros(vec![3]));
+        assert_eq!(y.grad(), ArrayD::<f32>::zeros(vec![3]));
+    }
+
+    #[test]
+    fn sum_and_mean_axis_keepdims() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn());
+        let kept = x.sum_axis(0, true);
+        let dropped = x.mean_axis(1, false);
+
+        kept.forward();
+        dropped.forward();
+        assert_eq!(kept.value(), arr2(&[[5.0, 7.0, 9.0]]).into_dyn());
+        assert_eq!(dropped.value(), arr1(&[2.0, 5.0]).into_dyn());
+
+        kept.backward(arr2(&[[1.0, 2.0, 3.0]]).into_dyn());
+        assert_eq!(
+            x.grad(),
+            arr2(&[[1.0, 2.0, 3.0], [1.0, 2.0, 3.0]]).into_dyn()
+        );
+
+        dropped.backward_with(arr1(&[3.0, 6.0]).into_dyn(), false);
+        assert_eq!(
+            x.grad(),
+            arr2(&[[1.0, 1.0, 1.0], [2.0, 2.0, 2.0]]).into_dyn()
+        );
+    }
+
+    #[test]
+    fn flatten_restores_gradient_shape() {
+        let graph = Graph::new();
+        let x = graph.tensor(ArrayD::from_shape_fn(vec![2, 3, 4], |i| {
+            (i[0] * 12 + i[1] * 4 + i[2]) as f32
+        }));
+        let y = x.flatten(1);
+
+        y.forward();
+        assert_eq!(y.shape(), vec![2, 12]);
+        assert_eq!(y.value().as_slice().unwrap(), x.value().as_slice().unwrap());
+
+        let seed = ArrayD::from_shape_fn(vec![2, 12], |i| (i[0] * 12 + i[1]) as f32);
+        y.backward(seed.clone());
+        assert_eq!(x.grad().shape(), &[2, 3, 4]);
+        assert_eq!(x.grad().as_slice().unwrap(), seed.as_slice().unwrap());
+    }
+
+    #[test]
+    fn squeeze_unsqueeze_round_trip() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        let row = x.unsqueeze(0);
+        let back = row.squeeze(0);
+
+        back.forward();
+        assert_eq!(row.shape(), vec![1, 3]);
+        assert_eq!(back.value(), x.value());
+
+        back.backward(arr1(&[4.0, 5.0, 6.0]).into_dyn());
+        assert_eq!(row.grad(), arr2(&[[4.0, 5.0, 6.0]]).into_dyn());
+        assert_eq!(x.grad(), arr1(&[4.0, 5.0, 6.0]).into_dyn());
+    }
+
+    #[test]
+    fn pad_gradient_comes_from_interior() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[1.0, 2.0], [3.0, 4.0]]).into_dyn());
+        let y = x.pad(vec![(1, 1), (1, 1)], -1.0);
+
+        y.forward();
+        assert_eq!(
+            y.value(),
+            arr2(&[
+                [-1.0, -1.0, -1.0, -1.0],
+                [-1.0, 1.0, 2.0, -1.0],
+                [-1.0, 3.0, 4.0, -1.0],
+                [-1.0, -1.0, -1.0, -1.0]
+            ])
+            .into_dyn()
+        );
+
+        let seed = Array::from_shape_fn((4, 4), |(i, j)| (i * 4 + j) as f32).into_dyn();
+        y.backward(seed);
+        assert_eq!(x.grad(), arr2(&[[5.0, 6.0], [9.0, 10.0]]).into_dyn());
+    }
+
+    #[test]
+    fn backward_collect_returns_leaf_grads() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = (x * y) * x;
+
+        z.forward();
+        let grads = z.backward_collect(arr1(&[1.0, 1.0]).into_dyn());
+
+        let mut keys: Vec<usize> = grads.keys().copied().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![x.index, y.index]);
+        assert_eq!(grads[&x.index], arr1(&[6.0, 16.0]).into_dyn());
+        assert_eq!(grads[&y.index], arr1(&[1.0, 4.0]).into_dyn());
+    }
+
+    #[test]
+    fn op_count_and_flops() {
+        let graph = Graph::new();
+        let x = graph.tensor(Array::ones((2, 3)).into_dyn());
+        let w1 = graph.tensor(Array::ones((3, 4)).into_dyn());
+        let w2 = graph.tensor(Array::ones((4, 5)).into_dyn());
+        let b = graph.tensor(Array::ones((2, 5)).into_dyn());
+        let y = x.matmul(w1).matmul(w2) + b;
+
+        let counts = graph.op_count();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["MatMul"], 2);
+        assert_eq!(counts["Add"], 1);
+
+        assert_eq!(graph.flops(), 0);
+        y.forward();
+        assert_eq!(graph.flops(), 2 * 3 * 4 + 2 * 4 * 5 + 2 * 5);
+
+        let flops = |z: tensor::Tensor<ArrayD<f32>>| {
+            let graph = z.graph;
+            z.forward();
+            graph.flops()
+        };
+        let graph = Graph::new();
+        let a = graph.tensor(Array::ones((2, 3, 4)).into_dyn());
+        let b = graph.tensor(Array::ones((4, 3, 5)).into_dyn());
+        assert_eq!(flops(a.tensordot(b, (vec![1, 2], vec![1, 0]))), 2 * 5 * 12);
+
+        let graph = Graph::new();
+        let a = graph.tensor(Array::ones((2, 3)).into_dyn());
+        let b = graph.tensor(Array::ones((3, 4)).into_dyn());
+        assert_eq!(flops(tensor::Tensor::einsum("ij,jk->ik", a, b)), 2 * 3 * 4);
+
+        let graph = Graph::new();
+        let a = graph.tensor(Array::ones((2, 3)).into_dyn());
+        let b = graph.tensor(Array::ones((4, 5)).into_dyn());
+        assert_eq!(flops(a.kron(b)), 8 * 15);
+
+        let graph = Graph::new();
+        let a = graph.tensor(Array::ones((3, 3)).into_dyn());
+        assert_eq!(flops(a.commutator(a)), 2 * 27);
+
+        let graph = Graph::new();
+        let a = graph.tensor(Array::zeros((5, 3, 3)).into_dyn());
+        assert_eq!(flops(a.expm()), 5 * 27);
+    }
+
+    #[test]
+    fn softmax_temperature() {
+        let logits = arr1(&[1.0, 2.0, 4.0]).into_dyn();
+        let seed = arr1(&[1.0, 0.0, 0.0]).into_dyn();
+
+        let graph = Graph::new();
+        let x = graph.tensor(logits.clone());
+        let sharp = x.softmax(0);
+        let flat = x.softmax_t(0, 4.0);
+        let spread =
+            |p: ArrayD<f32>| p.fold(0.0f32, |m, &v| m.max(v)) - p.fold(1.0f32, |m, &v| m.min(v));
+
+        flat.forward();
+        sharp.forward();
+        assert!(spread(flat.value()) < spread(sharp.value()));
+
+        // softmax_t(x, t) == softmax(x / t), so its gradient is the plain one scaled by 1/t
+        flat.backward_with(seed.clone(), false);
+        let grad = x.grad();
+        let scaled = graph.tensor(logits.mapv(|v| v / 4.0));
+        let reference = scaled.softmax(0);
+        reference.forward();
+        reference.backward_with(seed, false);
+        assert_close(&grad, &scaled.grad().mapv(|g| g / 4.0), 1e-6);
+
+        let log_flat = x.log_softmax_t(0, 4.0);
+        log_flat.forward();
+        assert_close(&log_flat.value(), &flat.value().mapv(f32::ln), 1e-6);
+    }
+
+    #[test]
+    fn checkpoint_recomputes_dropped_values() {
+        let run = |checkpoint: bool| {
+            let graph = Graph::new();
+            let x = graph.tensor(arr1(&[0.5, -1.0, 2.0]).into_dyn());
+            let w = graph.tensor(arr1(&[1.5, 0.5, -0.5]).into_dyn());
+
+            let mut h = x;
+            let mut interior = Vec::new();
+            for layer in 0..8 {
+                h = (h * w).silu();
+                interior.push(h.index);
+                if checkpoint && layer % 4 == 3 {
+                    h = h.checkpoint();
+                }
+            }
+
+            h.forward();
+            let values = graph.values();
+            let dropped: Vec<bool> = interior.iter().map(|&i| values[i].is_none()).collect();
+            h.backward(Array::ones(3).into_dyn());
+            (x.grad(), w.grad(), dropped)
+        };
+
+        let (gx, gw, kept) = run(false);
+        let (cx, cw, dropped) = run(true);
+        assert_close(&gx, &cx, 1e-6);
+        assert_close(&gw, &cw, 1e-6);
+
+        assert!(kept.iter().all(|&d| !d));
+        // Checkpoint outputs are kept, everything else inside the segments is dropped
+        let expected: Vec<bool> = (0..8).map(|layer| layer % 4 != 3).collect();
+        assert_eq!(dropped, expected);
+    }
+
+    #[test]
+    fn checkpoint_runs_batch_norm_once() {
+        let run = |checkpoint: bool| {
+            let graph = Graph::new();
+            let x = graph.tensor(arr2(&[[1.0, -2.0], [3.0, 0.5], [0.0, 1.5]]).into_dyn());
+            let w = graph.tensor(arr2(&[[0.5, 1.0], [-1.0, 2.0]]).into_dyn());
+            let (before, inside) = (nn::BatchNorm::new(0.5, 1e-5), nn::BatchNorm::new(0.5, 1e-5));
+
+            let mut y = before.forward(x);
+            if checkpoint {
+                y = y.checkpoint();
+            }
+            // The second batch norm sits inside the segment, between dropped ops
+            let mut h = inside.forward(y.matmul(w).silu()).silu();
+            if checkpoint {
+                h = h.checkpoint();
+            }
+            let z = h.silu();
+
+            graph.enable_profiling();
+            z.forward();
+            z.backward(ArrayD::ones(vec![3, 2]));
+            let calls = graph.profile_report()["BatchNorm"].calls;
+            let means = (
+                before.running_mean().unwrap(),
+                inside.running_mean().unwrap(),
+            );
+            (means, calls, w.grad())
+        };
+
+        let (means, calls, grad) = run(false);
+        let (checkpointed_means, checkpointed_calls, checkpointed_grad) = run(true);
+        assert_eq!(calls, 2);
+        assert_eq!(checkpointed_calls, 2);
+        assert_eq!(checkpointed_means, means);
+        assert_close(&checkpointed_grad, &grad, 1e-6);
+
+        // One momentum step from zero towards the batch mean [4/3, 0]
+        assert_close(&means.0, &arr1(&[2.0 / 3.0, 0.0]).into_dyn(), 1e-6);
+    }
+
+    #[test]
+    fn topo_order_respects_dependencies() {
+        let graph = Graph::new();
+        let z = graph.tensor(arr1(&[1.0]).into_dyn());
+        let unused = graph.tensor(arr1(&[2.0]).into_dyn());
+        let x = graph.tensor(arr1(&[3.0]).into_dyn());
+        let y = graph.tensor(arr1(&[4.0]).into_dyn());
+        let xy = x * y;
+        let out = xy + z;
+        let _ = unused * x;
+
+        let order = graph.topo_order(out.index);
+        let pos = |i: usize| order.iter().position(|&o| o == i).unwrap();
+        assert_eq!(order.len(), 5);
+        assert!(!order.contains(&unused.index));
+        assert!(pos(x.index) < pos(xy.index) && pos(y.index) < pos(xy.index));
+        assert!(pos(xy.index) < pos(out.index) && pos(z.index) < pos(out.index));
+        assert_eq!(graph.topo_order(x.index), vec![x.index]);
+    }
+
+    #[test]
+    fn clip_grad_value_truncates() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        let w = graph.tensor(arr1(&[0.5, -4.0, 2.0]).into_dyn());
+        let y = x * w;
+
+        y.forward();
+        y.backward(arr1(&[1.0, 1.0, 1.0]).into_dyn());
+        optim::clip_grad_value(&[x, w], 1.5);
+
+        assert_eq!(x.grad(), arr1(&[0.5, -1.5, 1.5]).into_dyn());
+        assert_eq!(w.grad(), arr1(&[1.0, 1.5, 1.5]).into_dyn());
+    }
+
+    #[test]
+    fn retain_grad_survives_freeing() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let w = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let hidden = (x * w).retain_grad();
+        let other = hidden * w;
+        let y = other * x;
+
+        y.forward();
+        y.backward(arr1(&[1.0, 1.0]).into_dyn());
+        graph.free_intermediate_grads();
+
+        assert_eq!(hidden.grad(), arr1(&[3.0, 8.0]).into_dyn());
+        assert!(other.try_grad().is_err());
+        assert_eq!(x.grad(), arr1(&[18.0, 64.0]).into_dyn());
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn device_value_is_copied_once() {
+        let d = futures::executor::block_on(ndarray::WgpuDevice::new()).expect("No GPU");
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn().into_wgpu(&d));
+        let y = x * x;
+        y.forward();
+
+        let before = tensor::device_copies();
+        let first = y.value();
+        let second = y.value();
+        assert_eq!(first, second);
+        assert_eq!(tensor::device_copies() - before, 1);
+
+        x.set_value(arr1(&[3.0, 4.0]).into_dyn().into_wgpu(&d));
+        y.forward();
+        assert_eq!(y.value(), arr1(&[9.0, 16.0]).into_dyn());
+        assert_eq!(tensor::device_copies() - before, 2);
+    }
+
+    #[test]
+    fn complex_abs2_through_matmul() {
+        use complex::{Complex32, ComplexArray};
+
+        let c = |re: f32, im: f32| Complex32::new(re, im);
+        let u: ComplexArray = Array::from_shape_vec(
+            (2, 2),
+            vec![c(0.6, 0.8), c(0.0, 1.0), c(1.0, -1.0), c(0.5, 0.0)],
+        )
+        .unwrap()
+        .into_dyn()
+        .into();
+        let phi: ComplexArray = Array::from_shape_vec((2, 1), vec![c(1.0, 2.0), c(-0.5, 0.3)])
+            .unwrap()
+            .into_dyn()
+            .into();
+
+        let loss = |phi: &ComplexArray| {
+            let psi = TensorType::matmul(&u, phi);
+            psi.0.iter().map(|z| z.norm_sqr()).sum::<f32>()
+        };
+
+        let graph = Graph::new();
+        let u_t = graph.constant(u.clone());
+        let phi_t = graph.tensor(phi.clone());
+        let l = u_t
+            .matmul(phi_t)
+            .abs2()
+            .sum_axis(0, false)
+            .sum_axis(0, false);
+
+        l.forward();
+        assert!((l.value_complex().0[[]].re - loss(&phi)).abs() < 1e-4);
+        l.backward(ComplexArray(Array::from_elem(vec![], c(1.0, 0.0))));
+
+        // dL/dx - i dL/dy, compared against finite differences on both parts
+        let (re, im) = phi.parts();
+        let grad = phi_t.grad_complex().0;
+        let eps = 1e-2;
+        for k in 0..2 {
+            let bump = |part: &ArrayD<f32>, delta: f32| {
+                let mut part = part.clone();
+                part[[k, 0]] += delta;
+                part
+            };
+            let at = |re: ArrayD<f32>, im: ArrayD<f32>| loss(&ComplexArray::from_parts(&re, &im));
+            let d_re =
+                (at(bump(&re, eps), im.clone()) - at(bump(&re, -eps), im.clone())) / (2.0 * eps);
+            let d_im =
+                (at(re.clone(), bump(&im, eps)) - at(re.clone(), bump(&im, -eps))) / (2.0 * eps);
+            assert!(
+                (grad[[k, 0]].re - d_re).abs() < 1e-2,
+                "{} vs {}",
+                grad[[k, 0]].re,
+                d_re
+            );
+            assert!(
+                (grad[[k, 0]].im + d_im).abs() < 1e-2,
+                "{} vs {}",
+                grad[[k, 0]].im,
+                -d_im
+            );
+        }
+    }
+
+    #[test]
+    fn conj_t_is_an_involution() {
+        use complex::{Complex32, ComplexArray};
+
+        let c = |re: f32, im: f32| Complex32::new(re, im);
+        let a: ComplexArray = Array::from_shape_vec(
+            (2, 3),
+            vec![
+                c(1.0, 2.0),
+                c(0.0, -1.0),
+                c(3.0, 0.0),
+                c(-2.0, 0.5),
+                c(1.0, 1.0),
+                c(0.0, 4.0),
+            ],
+        )
+        .unwrap()
+        .into_dyn()
+        .into();
+
+        let graph = Graph::new();
+        let x = graph.tensor(a.clone());
+        let adj = x.conj_t();
+        let back = adj.conj_t();
+
+        back.forward();
+        assert_eq!(back.value_complex(), a);
+        assert_eq!(adj.value_complex().0[[2, 1]], c(0.0, -4.0));
+        assert_eq!(adj.value_complex().0[[0, 1]], c(-2.0, -0.5));
+
+        // Two conjugations cancel in the gradient as well
+        back.backward(a.clone());
+        assert_eq!(x.grad_complex(), a);
+
+        // Real tensors have nothing to conjugate
+        let real = Graph::new();
+        let m = real.tensor(arr2(&[[1.0, 2.0], [3.0, 4.0]]).into_dyn());
+        let mt = m.conj_t();
+        mt.forward();
+        assert_eq!(mt.value(), arr2(&[[1.0, 3.0], [2.0, 4.0]]).into_dyn());
+    }
+
+    #[test]
+    fn run_builds_graph_from_op_specs() {
+        let x = arr1(&[1.0, 2.0]).into_dyn();
+        let y = arr1(&[3.0, 4.0]).into_dyn();
+        let z = arr1(&[0.5, -1.0]).into_dyn();
+
+        let by_hand = Graph::new();
+        let (hx, hy, hz) = (
+            by_hand.tensor(x.clone()),
+            by_hand.tensor(y.clone()),
+            by_hand.tensor(z.clone()),
+        );
+        let expected = hx * hy + hz;
+        expected.forward();
+        expected.backward(arr1(&[1.0, 1.0]).into_dyn());
+
+        let graph = Graph::new();
+        let (tx, ty, tz) = (graph.tensor(x), graph.tensor(y), graph.tensor(z));
+        let out = graph.run(&[OpSpec::Mul(tx.index, ty.index), OpSpec::Add(3, tz.index)]);
+        out.forward();
+        out.backward(arr1(&[1.0, 1.0]).into_dyn());
+
+        assert_eq!(graph.len(), 5);
+        assert_eq!(out.value(), expected.value());
+        assert_eq!(tx.grad(), arr1(&[3.0, 4.0]).into_dyn());
+        assert_eq!(format!("{}", graph), format!("{}", by_hand));
+    }
+
+    #[test]
+    fn spec_round_trip() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[0.5, 0.0], [0.0, -1.0]]).into_dyn());
+        let w = graph.constant(arr2(&[[2.0, 1.0], [0.0, 1.0]]).into_dyn());
+        let y = x.expm().matmul(w).softmax_t(1, 2.0);
+        y.forward();
+
+        let spec = graph.spec(true).unwrap();
+        let copy = Graph::from_spec(&spec);
+        assert_eq!(copy.spec(true).unwrap(), spec);
+        assert_eq!(copy.op_count(), graph.op_count());
+
+        let out = copy.tensor_at(y.index);
+        out.forward();
+        assert_eq!(out.value(), y.value());
+
+        // Leaves come back as zeros when values are left out
+        let shapes_only = Graph::from_spec(&graph.spec(false).unwrap());
+        assert_eq!(shapes_only.values()[0], Some(ArrayD::zeros(vec![2, 2])));
+
+        struct Opaque;
+        impl<'d> functions::OneValuedFn<'d, ArrayD<f32>> for Opaque {
+            fn forward(
+                &mut self,
+                t_a: tensor::Raw<'d, ArrayD<f32>>,
+            ) -> tensor::Raw<'d, ArrayD<f32>> {
+                t_a
+            }
+            fn backward(
+                &self,
+                grad: tensor::Raw<'d, ArrayD<f32>>,
+            ) -> [Option<tensor::Raw<'d, ArrayD<f32>>>; 2] {
+                [Some(grad), None]
+            }
+        }
+        let unsupported = graph.apply_one(x, Box::new(Opaque));
+        assert_eq!(
+            graph.spec(false),
+            Err(GraphError::Unsupported {
+                index: unsupported.index,
+                op: "Opaque"
+            })
+        );
+    }
+
+    #[test]
+    fn spec_round_trip_keeps_op_parameters() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[0.5, -1.5, 2.0], [1.0, 0.25, -0.5]]).into_dyn());
+        let bn = nn::BatchNorm::new(0.5, 1e-5);
+        let normed = bn.forward(x);
+        let y = normed
+            .floor()
+            .pad(vec![(1, 0), (0, 2)], 3.0)
+            .slice(vec![(0, 2), (1, 4)])
+            .compare(x.ceil(), functions::Comparison::Ge)
+            .sum_axes(vec![0, 1]);
+        y.forward();
+
+        let spec = graph.spec(true).unwrap();
+        let copy = Graph::from_spec(&spec);
+        assert_eq!(copy.spec(true).unwrap(), spec);
+
+        let out = copy.tensor_at(y.index);
+        out.forward();
+        assert_eq!(out.value(), y.value());
+
+        // Running statistics travel with the spec
+        bn.eval();
+        y.forward();
+        let spec = graph.spec(true).unwrap();
+        let copy = Graph::from_spec(&spec);
+        let out = copy.tensor_at(normed.index);
+        out.forward();
+        assert_eq!(out.value(), normed.value());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn graph_json_round_trip() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[0.5, 0.0], [0.0, -1.0]]).into_dyn());
+        let y = x.expm();
+
+        let json = serde_json::to_string(&graph).unwrap();
+        let copy: Graph<ArrayD<f32>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.spec(true).unwrap(), graph.spec(true).unwrap());
+
+        y.forward();
+        let out = copy.tensor_at(y.index);
+        out.forward();
+        assert_eq!(out.value(), y.value());
+    }
+
+    #[test]
+    fn onnx_export_matmul_add_relu() {
+        use tract_onnx::prelude::{tract_ndarray, tvec, Framework, InferenceModelExt, IntoTensor};
+
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[1.0, -2.0]]).into_dyn());
+        let w = graph.tensor(arr2(&[[1.0, 0.5], [2.0, -1.0]]).into_dyn());
+        let b = graph.tensor(arr2(&[[0.5, 0.5]]).into_dyn());
+        let zero = graph.constant(arr2(&[[0.0, 0.0]]).into_dyn());
+        let y = x.matmul(w) + b;
+        let relu = y.maximum(zero);
+        relu.forward();
+
+        // Decode with an independent ONNX runtime and run the model on the same input
+        let bytes = graph.to_onnx(&[x.index], &[relu.index]).unwrap();
+        let model = tract_onnx::onnx().model_for_read(&mut &bytes[..]).unwrap();
+        let ops: Vec<String> = model.nodes().iter().map(|n| n.op.name().into()).collect();
+        // The input, the three initializers, then MatMul, Add and Max
+        assert_eq!(
+            ops,
+            vec![
+                "Source",
+                "Const",
+                "Const",
+                "Const",
+                "MatMulInference",
+                "Add",
+                "MaxNary"
+            ]
+        );
+        assert_eq!(model.input_outlets().unwrap().len(), 1);
+
+        let model = model.into_optimized().unwrap().into_runnable().unwrap();
+        let input = tract_ndarray::arr2(&[[1.0f32, -2.0]]).into_tensor();
+        let out = model.run(tvec!(input.into())).unwrap();
+        assert_eq!(out[0].shape(), &[1, 2]);
+        assert_eq!(
+            out[0].as_slice::<f32>().unwrap(),
+            relu.value().as_slice().unwrap()
+        );
+
+        let unsupported = relu.silu();
+        assert_eq!(
+            graph.to_onnx(&[x.index], &[unsupported.index]),
+            Err(GraphError::Unsupported {
+                index: unsupported.index,
+                op: "Silu"
+            })
+        );
+    }
+
+    #[test]
+    fn npy_round_trip() {
+        let path = std::env::temp_dir().join(format!("rust_grad_npy_{}.npy", std::process::id()));
+
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[1.0, -2.5, 3.0], [0.0, 4.25, -6.0]]).into_dyn());
+        let y = x * x;
+        y.forward();
+        y.save_npy(&path).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..6], b"\x93NUMPY");
+        assert_eq!(
+            (10 + u16::from_le_bytes([bytes[8], bytes[9]]) as usize) % 64,
+            0
+        );
+
+        let loaded = graph.tensor_from_npy(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.value(), y.value());
+        assert_eq!(loaded.shape(), vec![2, 3]);
+
+        assert!(graph.tensor_from_npy(&path).is_err());
+    }
+
+    #[test]
+    fn recompute_reuses_graph() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr2(&[[0.1, 0.0], [0.0, 0.2]]).into_dyn());
+        let mut z = x.expm();
+        for _ in 0..3 {
+            z = z.expm();
+        }
+        z.forward();
+        let len = graph.nodes.borrow().len();
+
+        x.set_value(arr2(&[[0.3, 0.1], [0.0, -0.2]]).into_dyn());
+        graph.recompute();
+        assert_eq!(graph.nodes.borrow().len(), len);
+
+        let fresh = Graph::new();
+        let x = fresh.tensor(arr2(&[[0.3, 0.1], [0.0, -0.2]]).into_dyn());
+        let mut expected = x.expm();
+        for _ in 0..3 {
+            expected = expected.expm();
+        }
+        expected.forward();
+        assert_eq!(z.value(), expected.value());
+    }
+
+    #[test]
+    fn div_eps_stays_finite() {
+        let x = arr1(&[1.0, -2.0, 3.0]).into_dyn();
+        let d = arr1(&[0.0, 0.5, 1e-30]).into_dyn();
+
+        let plain = x.div(&d);
+        assert!(plain[0].is_infinite());
+
+        let safe = x.div_eps(&d, 1e-5);
+        assert!(safe.iter().all(|v| v.is_finite()));
+        assert!((safe[0] - 1e5).abs() < 1.0);
+        assert!((safe[1] + 2.0 / (0.5 + 1e-5)).abs() < 1e-6);
+
+        // An all-zero row has zero mean square, which the norm must survive
+        let graph = Graph::new();
+        let row = graph.tensor(Array::zeros((1, 3)).into_dyn());
+        let w = graph.tensor(Array::ones(3).into_dyn());
+        let z = nn::rms_norm(&graph, row, w, 1e-5);
+        z.forward();
+        assert!(z.value().iter().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn dot_with_grad_edge_labels() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = x * y;
+        z.forward();
+
+        let plain = graph.to_dot();
+        assert!(plain.starts_with("digraph {"));
+        assert!(plain.contains("v0 -> v2;"));
+        assert!(plain.contains("v1 -> v2;"));
+
+        z.backward(Array::ones(2).into_dyn());
+        let dot = graph.to_dot_with_grads();
+        // dz/dx = y and dz/dy = x
+        assert!(dot.contains("v0 -> v2 [label=\"5.0000\"];"), "{}", dot);
+        assert!(dot.contains("v1 -> v2 [label=\"2.2361\"];"), "{}", dot);
+        assert!(dot.contains("v2 [label=\"v2 = Mul\\n[2]\"];"), "{}", dot);
+    }
+
+    #[test]
+    fn abs_max_routes_gradient_by_sign() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[-5.0, 3.0, 2.0]).into_dyn());
+        let m = x.abs_max();
+        m.forward();
+        assert_eq!(m.value(), arr0(5.0).into_dyn());
+
+        m.backward(arr0(1.0).into_dyn());
+        assert_eq!(x.grad(), arr1(&[-1.0, 0.0, 0.0]).into_dyn());
+
+        let y = graph.tensor(arr2(&[[1.0, -2.0], [4.0, -4.0]]).into_dyn());
+        let m = y.abs_max();
+        m.forward();
+        m.backward(arr0(2.0).into_dyn());
+        assert_eq!(m.value(), arr0(4.0).into_dyn());
+        assert_eq!(y.grad(), arr2(&[[0.0, 0.0], [2.0, 0.0]]).into_dyn());
+    }
+
+    #[test]
+    fn profiler_reports_op_times() {
+        let graph = Graph::new();
+        let x = graph.tensor(Array::ones((16, 16)).into_dyn());
+        let y = x.matmul(x) + x;
+        y.forward();
+        assert!(graph.profile_report().is_empty());
+
+        graph.enable_profiling();
+        x.set_value(Array::ones((16, 16)).into_dyn() * 2.0);
+        y.forward();
+        y.backward(Array::ones((16, 16)).into_dyn());
+
+        let report = graph.profile_report();
+        assert_eq!(report.len(), 2);
+        for op in ["MatMul", "Add"].iter() {
+            let entry = report[op];
+            assert_eq!(entry.calls, 1);
+            assert!(entry.forward > std::time::Duration::from_secs(0));
+            assert!(entry.backward > std::time::Duration::from_secs(0));
+        }
+    }
+
+    #[test]
+    fn shared_parameter_sums_gradients() {
+        let graph = Graph::new();
+        let w = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let a = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let b = graph.tensor(arr1(&[5.0, 6.0]).into_dyn());
+        let c = graph.tensor(arr1(&[7.0, 8.0]).into_dyn());
+
+        let tied = w.share();
+        assert_eq!(tied.index, w.index);
+        let z = w * a + w * b + tied * c;
+        z.forward();
+        z.backward(Array::ones(2).into_dyn());
+
+        assert_eq!(w.grad(), arr1(&[15.0, 18.0]).into_dyn());
+        assert_eq!(graph.leaves().len(), 4);
+    }
+
+    #[test]
+    fn forward_hook_sees_mul_output() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        let y = graph.tensor(arr1(&[4.0, 5.0, 6.0]).into_dyn());
+        let prod = x * y;
+        let z = prod + x;
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let sink = seen.clone();
+        graph.register_forward_hook(
+            prod.index,
+            Box::new(move |v| sink.borrow_mut().push(v.clone())),
+        );
+        z.forward();
+        assert_eq!(*seen.borrow(), vec![arr1(&[4.0, 10.0, 18.0]).into_dyn()]);
+
+        // Cached values are not recomputed, so the hook stays quiet
+        z.forward();
+        assert_eq!(seen.borrow().len(), 1);
+        x.set_value(arr1(&[0.0, 1.0, 2.0]).into_dyn());
+        z.forward();
+        assert_eq!(seen.borrow()[1], arr1(&[0.0, 5.0, 12.0]).into_dyn());
+    }
+
+    #[test]
+    fn backward_hook_reverses_gradient() {
+        let run = |reverse: bool| {
+            let graph = Graph::new();
+            let x = graph.tensor(arr1(&[1.0, -2.0]).into_dyn());
+            let w = graph.tensor(arr1(&[3.0, 0.5]).into_dyn());
+            let h = x * w;
+            let z = h * h;
+            if reverse {
+                graph.register_backward_hook(h.index, Box::new(|g| g.mapv_inplace(|v| -v)));
+            }
+            z.forward();
+            z.backward(Array::ones(2).into_dyn());
+            (x.grad(), w.grad())
+        };
+
+        let (x_grad, w_grad) = run(false);
+        let (x_rev, w_rev) = run(true);
+        assert_eq!(x_grad, arr1(&[18.0, -1.0]).into_dyn());
+        assert_eq!(x_rev, -x_grad);
+        assert_eq!(w_rev, -w_grad);
+    }
+
+    #[test]
+    fn grad_reverse_scales_by_negative_lambda() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, -2.0, 3.0]).into_dyn());
+        let r = x.grad_reverse(0.5);
+        let z = r * r;
+        z.forward();
+        assert_eq!(r.value(), x.value());
+
+        z.backward(Array::ones(3).into_dyn());
+        // d(x^2)/dx = 2x, reversed and scaled by 0.5
+        assert_eq!(x.grad(), arr1(&[-1.0, 2.0, -3.0]).into_dyn());
+    }
+
+    #[test]
+    fn init_grad_zeros_then_accumulate() {
+        let graph = Graph::new();
+        let w = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let x = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let z = w * x;
+        z.forward();
+
+        w.init_grad_zeros();
+        assert_eq!(w.grad(), arr1(&[0.0, 0.0]).into_dyn());
+
+        for batch in [[3.0, 4.0], [-1.0, 5.0]].iter() {
+            x.set_value(arr1(batch).into_dyn());
+            z.forward();
+            z.backward(Array::ones(2).into_dyn());
+        }
+        assert_eq!(w.grad(), arr1(&[2.0, 9.0]).into_dyn());
+
+        w.init_grad_zeros();
+        assert_eq!(w.grad(), arr1(&[0.0, 0.0]).into_dyn());
+    }
+
+    #[test]
+    fn tensordot_matches_matmul_and_gradients() {
+        let a_val = arr2(&[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn();
+        let b_val = Array::from_shape_fn((3, 4), |(i, j)| (i as f32) - 0.5 * j as f32).into_dyn();
+
+        let graph = Graph::new();
+        let a = graph.tensor(a_val.clone());
+        let b = graph.tensor(b_val.clone());
+        let td = a.tensordot(b, (vec![1], vec![0]));
+        let mm = a.matmul(b);
+        td.forward();
+        mm.forward();
+        assert_eq!(td.value(), mm.value());
+
+        td.backward(Array::ones((2, 4)).into_dyn());
+        let (da, db) = (a.grad(), b.grad());
+        mm.backward_with(Array::ones((2, 4)).into_dyn(), false);
+        assert_eq!(da, a.grad());
+        assert_eq!(db, b.grad());
+
+        // Contract two axes, in swapped order, of a rank-3 tensor with a matrix
+        let x_val =
+            Array::from_shape_fn((2, 3, 4), |(i, j, k)| (i + 2 * j) as f32 - 0.3 * k as f32)
+                .into_dyn();
+        let w_val = Array::from_shape_fn((4, 3), |(k, j)| 0.1 * (k * 3 + j) as f32).into_dyn();
+        let contract = |x: &ArrayD<f32>, w: &ArrayD<f32>| {
+            Array::from_shape_fn(2, |i| {
+                let mut acc = 0.0;
+                for j in 0..3 {
+                    for k in 0..4 {
+                        acc += x[[i, j, k]] * w[[k, j]];
+                    }
+                }
+                acc
+            })
+            .into_dyn()
+        };
+
+        let graph = Graph::new();
+        let x = graph.tensor(x_val.clone());
+        let w = graph.tensor(w_val.clone());
+        let z = x.tensordot(w, (vec![2, 1], vec![0, 1]));
+        z.forward();
+        assert_close(&z.value(), &contract(&x_val, &w_val), 1e-4);
+
+        z.backward(Array::ones(2).into_dyn());
+        assert_close(
+            &x.grad(),
+            &numeric_grad(&x_val, |x| contract(x, &w_val)),
+            1e-2,
+        );
+        assert_close(
+            &w.grad(),
+            &numeric_grad(&w_val, |w| contract(&x_val, w)),
+            1e-2,
+        );
+    }
+
+    #[test]
+    fn leaf_grad_norms_of_two_leaves() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let w = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let unused = graph.tensor(arr1(&[1.0]).into_dyn());
+        let z = x * w;
+        z.forward();
+        assert!(graph.leaf_grad_norms().is_empty());
+
+        z.backward(Array::ones(2).into_dyn());
+        // dz/dx = w and dz/dw = x; the product node and the unused leaf are not reported
+        let norms = graph.leaf_grad_norms();
+        assert_eq!(norms.len(), 2);
+        assert_eq!(norms[0], (x.index, 5.0f32.sqrt()));
+        assert_eq!(norms[1], (w.index, 5.0));
+        assert!(norms.iter().all(|&(i, _)| i != unused.index));
+    }
+
+    #[test]
+    fn fold_constants_collapses_constant_subgraph() {
+        let graph = Graph::new();
+        let a = graph.constant(arr1(&[1.0, 2.0]).into_dyn());
+        let b = graph.constant(arr1(&[3.0, 4.0]).into_dyn());
+        let x = graph.tensor(arr1(&[0.5, -1.0]).into_dyn());
+        let ab = a * b;
+        let scaled = ab.scale(2.0);
+        let z = scaled * x;
+
+        graph.fold_constants();
+        let ops = graph.op_count();
+        assert_eq!(ops.get("Mul"), Some(&1));
+        assert_eq!(ops.get("Scale"), None);
+        {
+            let nodes = graph.nodes.borrow();
+            let folded = nodes[scaled.index].borrow();
+            assert!(matches!(folded.func, functions::Function::None));
+            assert!(!folded.requires_grad);
+            assert!(folded.constant);
+        }
+        assert_eq!(scaled.value(), arr1(&[6.0, 16.0]).into_dyn());
+
+        z.forward();
+        z.backward(Array::ones(2).into_dyn());
+        assert_eq!(z.value(), arr1(&[3.0, -16.0]).into_dyn());
+        assert_eq!(x.grad(), arr1(&[6.0, 16.0]).into_dyn());
+    }
+
+    #[test]
+    fn fold_constants_keeps_inputs_without_gradient() {
+        let graph = Graph::new();
+        let a = graph.constant(arr1(&[1.0, 2.0]).into_dyn());
+        let input = graph
+            .tensor(arr1(&[3.0, 4.0]).into_dyn())
+            .requires_grad(false);
+        let z = a * input;
+
+        graph.fold_constants();
+        assert_eq!(graph.op_count().get("Mul"), Some(&1));
+
+        // The input can still be swapped for the next sample
+        input.set_value(arr1(&[5.0, 6.0]).into_dyn());
+        z.forward();
+        assert_eq!(z.value(), arr1(&[5.0, 12.0]).into_dyn());
+    }
+
+    #[test]
+    fn dedup_merges_repeated_products() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let y = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let first = x * y;
+        let second = x * y;
+        let z = first + second;
+        let other = x.scale(2.0) + x.scale(3.0);
+        let same = x.scale(0.5) + x.scale(0.5);
+
+        graph.dedup();
+        {
+            let nodes = graph.nodes.borrow();
+            assert_eq!(nodes[z.index].borrow().deps, [first.index, first.index]);
+            // Different factors are different ops
+            let other = nodes[other.index].borrow();
+            assert_ne!(other.deps[0], other.deps[1]);
+            let same = nodes[same.index].borrow();
+            assert_eq!(same.deps[0], same.deps[1]);
+        }
+        assert_eq!(graph.op_count().get("Mul"), Some(&1));
+        assert_eq!(graph.op_count().get("Alias"), Some(&2));
+
+        z.forward();
+        z.backward(Array::ones(2).into_dyn());
+        assert_eq!(z.value(), arr1(&[6.0, 16.0]).into_dyn());
+        assert_eq!(x.grad(), arr1(&[6.0, 8.0]).into_dyn());
+        assert_eq!(second.value(), first.value());
+
+        // The alias shares the first product's buffer rather than copying it
+        let nodes = graph.nodes.borrow();
+        let value = |i: usize| nodes[i].borrow().value.unwrap().data;
+        assert_eq!(value(second.index), value(first.index));
+    }
+
+    #[test]
+    fn versioned_forward_skips_unchanged_subgraphs() {
+        let graph = Graph::new();
+        let a = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let b = graph.tensor(arr1(&[3.0, 4.0]).into_dyn());
+        let left = a.silu().scale(2.0);
+        let right = b * b;
+        let z = left + right;
+
+        graph.enable_profiling();
+        z.forward();
+        z.forward();
+        let calls = |op| graph.profile_report()[op].calls;
+        assert_eq!((calls("Silu"), calls("Mul"), calls("Add")), (1, 1, 1));
+
+        // Only the branch reading `b` and what follows it runs again
+        b.set_value(arr1(&[0.0, 1.0]).into_dyn());
+        z.forward();
+        assert_eq!(
+            (calls("Silu"), calls("Scale"), calls("Mul"), calls("Add")),
+            (1, 1, 2, 2)
+        );
+        assert_eq!(right.value(), arr1(&[0.0, 1.0]).into_dyn());
+
+        // A second change to the same leaf is seen as well
+        b.set_value(arr1(&[2.0, 2.0]).into_dyn());
+        z.forward();
+        assert_eq!((calls("Silu"), calls("Mul")), (1, 3));
+        assert_eq!(right.value(), arr1(&[4.0, 4.0]).into_dyn());
+    }
+
+    #[test]
+    fn item_of_scalar_loss() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        let loss = x.fro_norm();
+        loss.forward();
+        assert_eq!(loss.item(), 14.0f32.sqrt());
+
+        let single = graph.tensor(arr2(&[[2.5]]).into_dyn());
+        assert_eq!(single.item(), 2.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "item() needs a single-element tensor, but v0 has shape [3]")]
+    fn item_of_vector_panics() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        x.item();
+    }
+
+    #[test]
+    fn backward_multi_sums_heads() {
+        let run = |multi: bool| {
+            let graph = Graph::new();
+            let x = graph.tensor(arr2(&[[1.0, -2.0]]).into_dyn());
+            let w = graph.tensor(arr2(&[[0.5, 1.0], [2.0, -1.0]]).into_dyn());
+            let trunk = x.matmul(w);
+            let head_a = trunk * trunk;
+            let head_b = trunk.scale(3.0);
+            head_a.forward();
+            head_b.forward();
+
+            let seed_a = arr2(&[[1.0, 1.0]]).into_dyn();
+            let seed_b = arr2(&[[2.0, -1.0]]).into_dyn();
+            if multi {
+                graph.backward_multi(&[(head_b, seed_b), (head_a, seed_a)]);
+            } else {
+                // The same seeds folded into one loss, differentiated in one pass
+                let loss = head_a * graph.constant(seed_a) + head_b * graph.constant(seed_b);
+                loss.forward();
+                loss.backward(Array::ones((1, 2)).into_dyn());
+            }
+            (trunk.grad(), w.grad(), x.grad())
+        };
+
+        let (trunk, w, x) = run(true);
+        // trunk = [-3.5, 3], so d(head_a)/dtrunk = 2 * trunk and d(head_b)/dtrunk = 3
+        assert_eq!(trunk, arr2(&[[-7.0 + 6.0, 6.0 - 3.0]]).into_dyn());
+        let (trunk_ref, w_ref, x_ref) = run(false);
+        assert_eq!(trunk, trunk_ref);
+        assert_eq!(w, w_ref);
+        assert_eq!(x, x_ref);
+    }
+
+    #[test]
+    fn to_leaf_cuts_the_graph() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+        let h = x * x;
+        h.forward();
+
+        let state = h.to_leaf();
+        assert_eq!(state.value(), h.value());
+        {
+            let nodes = graph.nodes.borrow();
+            let node = nodes[state.index].borrow();
+            assert!(matches!(node.func, functions::Function::None));
+            assert_eq!(node.deps, [state.index, state.index]);
+        }
+
+        let z = state.scale(3.0);
+        z.forward();
+        z.backward(Array::ones(2).into_dyn());
+        assert_eq!(state.grad(), arr1(&[3.0, 3.0]).into_dyn());
+        // Nothing flows back past the cut
+        assert!(graph.nodes.borrow()[x.index].borrow().grad.is_none());
+        assert!(graph.nodes.borrow()[h.index].borrow().grad.is_none());
+    }
+
+    #[test]
+    fn grad_default_zeros_on_dead_path() {
+        use functions::TwoValuedFn;
+        use tensor::Raw;
+
+        // Passes `a` through and declares `b` non-differentiable
+        struct First;
+        impl<'d> TwoValuedFn<'d, ArrayD<f32>> for First {
+            fn forward(
+                &mut self,
+                t_a: Raw<'d, ArrayD<f32>>,
+                _t_b: Raw<'d, ArrayD<f32>>,
+            ) -> Raw<'d, ArrayD<f32>> {
+                Raw::new(t_a.value().clone())
+            }
+            fn backward(&self, grad: Raw<'d, ArrayD<f32>>) -> [Option<Raw<'d, ArrayD<f32>>>; 2] {
+                [Some(grad), None]
+            }
+        }
+
+        let run = |zeros: bool| {
+            let graph = Graph::new();
+            graph.set_grad_default(zeros);
+            let x = graph.tensor(arr1(&[1.0, 2.0]).into_dyn());
+            let y = graph.tensor(arr1(&[3.0, 4.0, 5.0]).into_dyn());
+            let frozen = graph.constant(arr1(&[1.0, 1.0, 1.0]).into_dyn());
+            let unused = graph.tensor(arr1(&[0.0]).into_dyn());
+            let dead = y * frozen;
+            let z = graph.apply_two(x, dead, Box::new(First));
+            z.forward();
+            z.backward(Array::ones(2).into_dyn());
+
+            let grad_of = |t: tensor::Tensor<ArrayD<f32>>| {
+                graph.nodes.borrow()[t.index]
+                    .borrow()
+                    .grad
+                    .map(|g| g.value().clone())
+            };
+            assert_eq!(x.grad(), arr1(&[1.0, 1.0]).into_dyn());
+            assert_eq!(grad_of(frozen), None);
+            assert_eq!(grad_of(unused), None);
+            if zeros {
+                assert_eq!(y.grad(), Array::<f32, _>::zeros(3).into_dyn());
+            }
+            (grad_of(dead), grad_of(y))
+        };
+
+        assert_eq!(run(false), (None, None));
+        let zeros = Some(arr1(&[0.0, 0.0, 0.0]).into_dyn());
+        assert_eq!(run(true), (zeros.clone(), zeros));
+    }
+
+    #[test]
+    fn infer_shapes_reports_bad_matmul() {
+        let graph = Graph::new();
+        let x = graph.tensor(Array::zeros((2, 3)).into_dyn());
+        let w = graph.tensor(Array::zeros((3, 4)).into_dyn());
+        let b = graph.tensor(Array::zeros(4).into_dyn());
+        let h = x.matmul(w) + b;
+        let s = h.sum_axis(1, true);
+        assert_eq!(
+            graph.infer_shapes(),
+            Ok(vec![
+                vec![2, 3],
+                vec![3, 4],
+                vec![4],
+                vec![2, 4],
+                vec![2, 4],
+                vec![2, 1]
+            ])
+        );
+        assert!(graph.nodes.borrow()[s.index].borrow().value.is_none());
+
+        let v = graph.tensor(Array::zeros((5, 2)).into_dyn());
+        let bad = h.matmul(v);
+        assert_eq!(
+            graph.infer_shapes(),
+            Err(format!(
+                "v{} (MatMul): matmul inner dimensions differ: [2, 4] @ [5, 2]",
+                bad.index
+            ))
+        );
+    }
+
+    #[test]
+    fn rotation_to_axis_angle_inverts_expm_so3() {
+        let hat = |w: [f32; 3]| {
+            arr2(&[[0.0, -w[2], w[1]], [w[2], 0.0, -w[0]], [-w[1], w[0], 0.0]]).into_dyn()
+        };
+        let seed = arr1(&[1.0, 2.0, -1.0]).into_dyn();
+
+        for &w in [[0.3, -0.2, 0.5], [1e-4, 2e-4, -1e-4], [1.2, 0.9, -1.5]].iter() {
+            let graph = Graph::new();
+            let k = graph.tensor(hat(w));
+            let r = k.expm_so3();
+            let back = r.rotation_to_axis_angle();
+            back.forward();
+            assert_close(&back.value(), &arr1(&w).into_dyn(), 1e-4);
+
+            let r_v = r.value();
+            let log = |m: &ArrayD<f32>| {
+                let g = Graph::new();
+                let w = g.tensor(m.clone()).rotation_to_axis_angle();
+                w.forward();
+                w.value() * &seed
+            };
+            let r_leaf = graph.tensor(r_v.clone());
+            let z = r_leaf.rotation_to_axis_angle();
+            z.forward();
+            z.backward(seed.clone());
+            assert_close(&r_leaf.grad(), &numeric_grad(&r_v, log), 2e-2);
+        }
+    }
+
+    #[test]
+    fn expm_batch_matches_each_slice() {
+        let batch = vec![
+            arr2(&[[1.0, 0.0], [0.0, 2.0]]).into_dyn(),
+            arr2(&[[-1.0, 0.0], [0.0, 0.5]]).into_dyn(),
+        ];
+        let seeds = vec![
+            arr2(&[[1.0, 0.5], [-0.5, 2.0]]).into_dyn(),
+            arr2(&[[0.3, 1.0], [0.0, -1.0]]).into_dyn(),
+        ];
+        let join = |xs: &[ArrayD<f32>]| {
+            let views: Vec<_> = xs.iter().map(|x| x.view()).collect();
+            ndarray::stack(Axis(0), &views).unwrap()
+        };
+
+        let graph = Graph::new();
+        let x = graph.tensor(join(&batch));
+        let z = x.expm();
+        z.forward();
+        z.backward(join(&seeds));
+
+        for (i, (a, seed)) in batch.iter().zip(&seeds).enumerate() {
+            let single = Graph::new();
+            let y = single.tensor(a.clone());
+            let w = y.expm();
+            w.forward();
+            w.backward(seed.clone());
+
+            assert_close(
+                &z.value().index_axis(Axis(0), i).to_owned(),
+                &w.value(),
+                1e-6,
+            );
+            assert_close(&x.grad().index_axis(Axis(0), i).to_owned(), &y.grad(), 1e-6);
+        }
+        assert_close(
+            &z.value().index_axis(Axis(0), 1).to_owned(),
+            &arr2(&[[(-1.0f32).exp(), 0.0], [0.0, 0.5f32.exp()]]).into_dyn(),
+            1e-6,
+        );
+    }
+
+    #[test]
+    fn matmul_repeated_backward_is_consistent() {
+        let x_v = arr2(&[[1.0, 2.0, 3.0], [-1.0, 0.5, 2.0]]).into_dyn();
+        let y_v = arr2(&[[0.5, 1.0], [2.0, -1.0], [1.0, 3.0]]).into_dyn();
+        let seed = arr2(&[[1.0, -2.0], [0.5, 1.0]]).into_dyn();
+
+        let graph = Graph::new();
+        let x = graph.tensor(x_v.clone());
+        let y = graph.tensor(y_v.clone());
+        let z = x.matmul(y);
+        z.forward();
+
+        z.backward_with(seed.clone(), false);
+        let (dx, dy) = (x.grad(), y.grad());
+        assert_close(&dx, &seed.matmul(&TensorType::t(&y_v)), 1e-6);
+        assert_close(&dy, &TensorType::t(&x_v).matmul(&seed), 1e-6);
+
+        z.backward_with(seed.clone(), false);
+        assert_eq!(x.grad(), dx);
+        assert_eq!(y.grad(), dy);
+
+        // A new forward must use the new operands
+        let x_new = &x_v * 2.0;
+        x.set_value(x_new.clone());
+        z.forward();
+        z.backward_with(seed.clone(), false);
+        assert_close(&y.grad(), &TensorType::t(&x_new).matmul(&seed), 1e-6);
+    }
+
+    #[test]
+    fn has_value_and_has_grad() {
+        let graph = Graph::new();
+        let x = graph.tensor(arr1(&[1.0, -2.0]).into_dyn());
+        let z = x.silu();
+        assert!(x.has_value());
+        assert!(!z.has_value());
+
+        z.forward();
+        assert!(z.has_value());
+        assert!(!x.has_grad());
+
+        z.backward(ArrayD::ones(vec![2]));
+        assert!(x.has_grad());
+        assert!(z.has_grad());
+    }
+
+    #[test]
+    fn sum_and_mean_over_several_axes() {
+        let x_v = Array::from_shape_fn((2, 3, 4), |(i, j, k)| (i * 12 + j * 4 + k) as f32);
+        let x_v = x_v.into_dyn();
+
+        let graph = Graph::new();
+        let x = graph.tensor(x_v.clone());
+        let z = x.sum_axes(vec![0, 2]);
+        z.forward();
+        assert_eq!(z.shape(), vec![3]);
+        assert_eq!(z.value(), arr1(&[60.0, 92.0, 124.0]).into_dyn());
+
+        z.backward(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        let expected = Array::from_shape_fn((2, 3, 4), |(_, j, _)| (j + 1) as f32);
+        assert_eq!(x.grad(), expected.into_dyn());
+
+        let graph = Graph::new();
+        let x = graph.tensor(x_v);
+        let z = x.mean_axes(vec![2, 0]);
+        z.forward();
+        assert_eq!(z.value(), arr1(&[7.5, 11.5, 15.5]).into_dyn());
+
+        z.backward(ArrayD::ones(vec![3]));
+        assert_eq!(x.grad(), ArrayD::from_elem(vec![2, 3, 4], 0.125));
+    }
+}
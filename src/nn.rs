@@ -0,0 +1,165 @@
+use crate::functions::{self, BatchNormStats, Function};
+use crate::graph::Graph;
+use crate::tensor::{Raw, Tensor, TensorType};
+use ndarray::ArrayD;
+use std::cell::Cell;
+use std::rc::Rc;
+
+///
+/// Look up rows of an embedding table
+///
+/// `weight` has shape [vocab, dim] and the result has shape [indices.len(), dim].
+/// Gradients from repeated indices accumulate into the same row of `weight`.
+///
+pub fn embedding<'d, 'g, T: TensorType<'d> + Clone>(
+    graph: &'g Graph<'d, T>,
+    weight: Tensor<'d, 'g, T>,
+    indices: &[usize],
+) -> Tensor<'d, 'g, T> {
+    assert_eq!(
+        graph as *const Graph<T>, weight.graph as *const Graph<T>,
+        "Embedding weight belongs to a different graph"
+    );
+    weight.gather(0, indices.to_vec())
+}
+
+///
+/// RMS normalization over the last axis, `x * rsqrt(mean(x^2) + eps) * weight`
+///
+/// `weight` has the size of the last axis of `x`. Computed in a single node.
+///
+pub fn rms_norm<'d, 'g, T: TensorType<'d> + Clone>(
+    graph: &'g Graph<'d, T>,
+    x: Tensor<'d, 'g, T>,
+    weight: Tensor<'d, 'g, T>,
+    eps: f32,
+) -> Tensor<'d, 'g, T> {
+    assert_eq!(
+        graph as *const Graph<T>, x.graph as *const Graph<T>,
+        "RMSNorm input belongs to a different graph"
+    );
+    assert_eq!(
+        graph as *const Graph<T>, weight.graph as *const Graph<T>,
+        "RMSNorm weight belongs to a different graph"
+    );
+    let func = Function::Two(
+        functions::RmsNorm {
+            eps,
+            x_hat: None,
+            inv_rms: None,
+            weight: None,
+        }
+        .into(),
+    );
+    graph.push([x.index, weight.index], func)
+}
+
+///
+/// Scaled dot-product attention, `softmax(q @ k^T / sqrt(d) + mask) @ v`
+///
+/// `q` is [n, d], `k` is [m, d] and `v` is [m, d_v]. Scores where `mask` is true are set to
+/// `-inf` before the softmax, e.g. to hide future positions.
+///
+pub fn scaled_dot_product_attention<'d, 'g, T: TensorType<'d> + Clone>(
+    q: Tensor<'d, 'g, T>,
+    k: Tensor<'d, 'g, T>,
+    v: Tensor<'d, 'g, T>,
+    mask: Option<ArrayD<bool>>,
+) -> Tensor<'d, 'g, T> {
+    // The width of q is only known once forward has run, so scale it there
+    let func = Function::One(functions::WidthScale { factor: None }.into());
+    let q = q.graph.push([q.index, q.index], func);
+
+    let mut scores = q.matmul(k.t());
+    if let Some(mask) = mask {
+        scores = scores.masked_fill(mask, f32::NEG_INFINITY);
+    }
+    scores.softmax(1).matmul(v)
+}
+
+///
+/// Batch normalization over the first axis, without a learned affine transform
+///
+/// The layer owns the running mean/variance, which are shared with every node it creates,
+/// so switching between `train()` and `eval()` affects subsequent forward passes.
+///
+pub struct BatchNorm<'d, T: 'd + TensorType<'d>> {
+    stats: Rc<BatchNormStats<'d, T>>,
+}
+
+impl<'d, T: TensorType<'d> + Clone> BatchNorm<'d, T> {
+    ///
+    /// Create a layer in training mode
+    ///
+    pub fn new(momentum: f32, eps: f32) -> Self {
+        BatchNorm {
+            stats: Rc::new(BatchNormStats {
+                training: Cell::new(true),
+                momentum,
+                eps,
+                running_mean: Cell::new(None),
+                running_var: Cell::new(None),
+            }),
+        }
+    }
+
+    pub fn train(&self) {
+        self.stats.training.set(true);
+    }
+
+    pub fn eval(&self) {
+        self.stats.training.set(false);
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.stats.training.get()
+    }
+
+    ///
+    /// Replace the running statistics, e.g. when loading a trained model
+    ///
+    pub fn set_running_stats(&self, mean: T, var: T) {
+        self.stats.running_mean.set(Some(Raw::new(mean)));
+        self.stats.running_var.set(Some(Raw::new(var)));
+    }
+
+    ///
+    /// Returns a CPU copy of the running mean, if any batch has been seen
+    ///
+    pub fn running_mean(&self) -> Option<ArrayD<f32>> {
+        self.stats
+            .running_mean
+            .get()
+            .map(|m| m.value().get_value_cpu())
+    }
+
+    ///
+    /// Returns a CPU copy of the running variance, if any batch has been seen
+    ///
+    pub fn running_var(&self) -> Option<ArrayD<f32>> {
+        self.stats
+            .running_var
+            .get()
+            .map(|v| v.value().get_value_cpu())
+    }
+
+    pub fn forward<'g>(&self, x: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        let func = Function::One(
+            functions::BatchNorm {
+                stats: self.stats.clone(),
+                x_hat: None,
+                inv_std: None,
+                batch_stats: true,
+                initial_stats: None,
+            }
+            .into(),
+        );
+        x.graph.push([x.index, x.index], func)
+    }
+}
+
+impl<'d, T: TensorType<'d> + Clone> Default for BatchNorm<'d, T> {
+    fn default() -> Self {
+        Self::new(0.1, 1e-5)
+    }
+}
@@ -0,0 +1,146 @@
+use crate::graph::Graph;
+use crate::tensor::{Tensor, TensorType};
+use ndarray::{ArrayD, IxDyn};
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::Path;
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+///
+/// Write a `.npy` file (format version 1.0) holding little-endian `f32` data in C order
+///
+pub fn write_npy<P: AsRef<Path>>(path: P, array: &ArrayD<f32>) -> io::Result<()> {
+    let shape = match array.shape() {
+        [] => "()".to_string(),
+        [n] => format!("({},)", n),
+        dims => format!(
+            "({})",
+            dims.iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    // Pad with spaces so that the data starts on a 64 byte boundary, ending in a newline
+    let unpadded = MAGIC.len() + 2 + 2 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut out = Vec::with_capacity(MAGIC.len() + 4 + header.len() + 4 * array.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&[1, 0]);
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    for x in array.iter() {
+        out.extend_from_slice(&x.to_le_bytes());
+    }
+    fs::write(path, out)
+}
+
+///
+/// The value of `key` in the header dictionary, e.g. `'<f4'` for `descr`
+///
+fn header_field<'h>(header: &'h str, key: &str) -> io::Result<&'h str> {
+    let pattern = format!("'{}':", key);
+    let start = header
+        .find(&pattern)
+        .ok_or_else(|| invalid(format!("npy header has no {}", key)))?
+        + pattern.len();
+    let rest = header[start..].trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find(',')
+    }
+    .ok_or_else(|| invalid(format!("malformed {} in npy header", key)))?;
+    Ok(rest[..end].trim())
+}
+
+///
+/// Read a `.npy` file of little-endian `f4` or `f8` data, converting to `f32`
+///
+pub fn read_npy<P: AsRef<Path>>(path: P) -> io::Result<ArrayD<f32>> {
+    let bytes = fs::read(path)?;
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(invalid("not an npy file".to_string()));
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        v => return Err(invalid(format!("unsupported npy version {}", v))),
+    };
+    let data_start = header_start + header_len;
+    let header = std::str::from_utf8(&bytes[header_start..data_start])
+        .map_err(|_| invalid("npy header is not text".to_string()))?;
+
+    let descr = header_field(header, "descr")?;
+    let fortran = header_field(header, "fortran_order")? == "True";
+    let shape: Vec<usize> = header_field(header, "shape")?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| {
+            d.parse()
+                .map_err(|_| invalid(format!("bad dimension {}", d)))
+        })
+        .collect::<io::Result<_>>()?;
+
+    let data = &bytes[data_start..];
+    let values: Vec<f32> = match descr.trim_matches('\'') {
+        "<f4" => data
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+        "<f8" => data
+            .chunks_exact(8)
+            .map(|c| {
+                let mut b = [0; 8];
+                b.copy_from_slice(c);
+                f64::from_le_bytes(b) as f32
+            })
+            .collect(),
+        other => return Err(invalid(format!("unsupported npy dtype {}", other))),
+    };
+
+    let array = if fortran {
+        let reversed: Vec<usize> = shape.iter().rev().cloned().collect();
+        ArrayD::from_shape_vec(IxDyn(&reversed), values).map(|a| a.reversed_axes())
+    } else {
+        ArrayD::from_shape_vec(IxDyn(&shape), values)
+    };
+    array.map_err(|_| invalid(format!("npy data does not fill shape {:?}", shape)))
+}
+
+impl<'d> Graph<'d, ArrayD<f32>> {
+    ///
+    /// A leaf tensor holding the contents of a `.npy` file, e.g. weights saved from numpy
+    ///
+    pub fn tensor_from_npy<'g, P: AsRef<Path>>(
+        &'g self,
+        path: P,
+    ) -> io::Result<Tensor<'d, 'g, ArrayD<f32>>> {
+        Ok(self.tensor(read_npy(path)?))
+    }
+}
+
+impl<'d, 'g, T: TensorType<'d> + Clone> Tensor<'d, 'g, T> {
+    ///
+    /// Save the value as a `.npy` file that numpy can load
+    ///
+    pub fn save_npy<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_npy(path, &self.value())
+    }
+}
@@ -0,0 +1,206 @@
+use crate::functions::{Function, OneValuedFnEnum, TwoValuedFnEnum};
+use crate::graph::{Graph, GraphError};
+use crate::tensor::TensorType;
+
+/// Opset the exported operators follow; `Softmax` normalizes over a single axis from 13 on
+const OPSET: i64 = 13;
+const IR_VERSION: i64 = 7;
+/// `TensorProto.DataType.FLOAT`
+const FLOAT: i64 = 1;
+
+///
+/// A protobuf message under construction, written field by field in the wire format
+///
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(u64::from(field << 3 | wire_type));
+    }
+    fn int(&mut self, field: u32, value: i64) -> &mut Self {
+        self.key(field, 0);
+        self.varint(value as u64);
+        self
+    }
+    fn float(&mut self, field: u32, value: f32) -> &mut Self {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+    fn bytes(&mut self, field: u32, bytes: &[u8]) -> &mut Self {
+        self.key(field, 2);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+        self
+    }
+    fn string(&mut self, field: u32, value: &str) -> &mut Self {
+        self.bytes(field, value.as_bytes())
+    }
+    fn message(&mut self, field: u32, message: Message) -> &mut Self {
+        self.bytes(field, &message.0)
+    }
+}
+
+fn name(index: usize) -> String {
+    format!("v{}", index)
+}
+
+///
+/// `ValueInfoProto` for a float tensor, with the shape when it is known
+///
+fn value_info(index: usize, shape: Option<Vec<usize>>) -> Message {
+    let mut tensor_type = Message::default();
+    tensor_type.int(1, FLOAT);
+    if let Some(shape) = shape {
+        let mut dims = Message::default();
+        for d in shape {
+            let mut dim = Message::default();
+            dim.int(1, d as i64);
+            dims.message(1, dim);
+        }
+        tensor_type.message(2, dims);
+    }
+    let mut type_proto = Message::default();
+    type_proto.message(1, tensor_type);
+
+    let mut info = Message::default();
+    info.string(1, &name(index)).message(2, type_proto);
+    info
+}
+
+fn float_attribute(name: &str, value: f32) -> Message {
+    let mut attr = Message::default();
+    // AttributeType.FLOAT
+    attr.string(1, name).float(2, value).int(20, 1);
+    attr
+}
+
+fn int_attribute(name: &str, value: i64) -> Message {
+    let mut attr = Message::default();
+    // AttributeType.INT
+    attr.string(1, name).int(3, value).int(20, 2);
+    attr
+}
+
+impl<'d, T: TensorType<'d> + Clone> Graph<'d, T> {
+    ///
+    /// Export the subgraph computing `outputs` as a serialized ONNX `ModelProto`, so that a
+    /// trained model can run in other runtimes. Leaves listed in `inputs` become graph
+    /// inputs; every other leaf is stored as an initializer with its current value
+    ///
+    /// Supported ops: Add, Mul, MatMul, Maximum, Minimum, Transpose, Softmax (at temperature
+    /// one), Elu and HardSigmoid. Nodes are named `v{index}` after their graph index
+    ///
+    pub fn to_onnx(&self, inputs: &[usize], outputs: &[usize]) -> Result<Vec<u8>, GraphError> {
+        let mut needed = vec![false; self.len()];
+        for &out in outputs {
+            for i in self.topo_order(out) {
+                needed[i] = true;
+            }
+        }
+
+        let nodes = self.nodes.borrow();
+        let mut graph = Message::default();
+        let mut initializers = Vec::new();
+        for (index, node) in nodes.iter().enumerate().filter(|(i, _)| needed[*i]) {
+            let node = node.borrow();
+            let [a, b] = node.deps;
+            let unsupported = GraphError::Unsupported {
+                index,
+                op: node.func.name(),
+            };
+
+            let mut attributes = Vec::new();
+            let (op_type, operands): (&str, &[usize]) = match &node.func {
+                Function::None => {
+                    if !inputs.contains(&index) {
+                        initializers.push(index);
+                    }
+                    continue;
+                }
+                Function::One(f) => match f {
+                    OneValuedFnEnum::Transpose(_) => ("Transpose", &[a]),
+                    OneValuedFnEnum::Softmax(f) if f.temperature == 1.0 => {
+                        attributes.push(int_attribute("axis", f.axis as i64));
+                        ("Softmax", &[a])
+                    }
+                    OneValuedFnEnum::Elu(f) => {
+                        attributes.push(float_attribute("alpha", f.alpha));
+                        ("Elu", &[a])
+                    }
+                    OneValuedFnEnum::HardSigmoid(_) => {
+                        attributes.push(float_attribute("alpha", 1.0 / 6.0));
+                        attributes.push(float_attribute("beta", 0.5));
+                        ("HardSigmoid", &[a])
+                    }
+                    _ => return Err(unsupported),
+                },
+                Function::Two(f) => match f {
+                    TwoValuedFnEnum::Add(_) => ("Add", &[a, b]),
+                    TwoValuedFnEnum::Mul(_) => ("Mul", &[a, b]),
+                    TwoValuedFnEnum::MatMul(_) => ("MatMul", &[a, b]),
+                    TwoValuedFnEnum::Maximum(_) => ("Max", &[a, b]),
+                    TwoValuedFnEnum::Minimum(_) => ("Min", &[a, b]),
+                    _ => return Err(unsupported),
+                },
+            };
+
+            let mut proto = Message::default();
+            for &operand in operands {
+                proto.string(1, &name(operand));
+            }
+            proto
+                .string(2, &name(index))
+                .string(3, &format!("{}_{}", op_type, index))
+                .string(4, op_type);
+            for attr in attributes {
+                proto.message(5, attr);
+            }
+            graph.message(1, proto);
+        }
+        graph.string(2, "rust-grad");
+
+        let value_of = |index: usize| nodes[index].borrow().value.map(|v| v.value());
+        for index in initializers {
+            let value = value_of(index).expect("leaves always hold a value");
+            let mut tensor = Message::default();
+            for d in value.shape() {
+                tensor.int(1, d as i64);
+            }
+            tensor.int(2, FLOAT);
+            let data: Vec<u8> = value
+                .get_value_cpu()
+                .iter()
+                .flat_map(|x| x.to_le_bytes())
+                .collect();
+            // Packed float_data
+            tensor.bytes(4, &data).string(8, &name(index));
+            graph.message(5, tensor);
+        }
+        for &index in inputs {
+            graph.message(11, value_info(index, value_of(index).map(|v| v.shape())));
+        }
+        for &index in outputs {
+            graph.message(12, value_info(index, value_of(index).map(|v| v.shape())));
+        }
+
+        let mut opset = Message::default();
+        opset.string(1, "").int(2, OPSET);
+
+        let mut model = Message::default();
+        model
+            .int(1, IR_VERSION)
+            .string(2, "rust-grad")
+            .message(7, graph)
+            .message(8, opset);
+        Ok(model.0)
+    }
+}
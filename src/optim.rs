@@ -0,0 +1,22 @@
+use crate::tensor::{Tensor, TensorType};
+
+///
+/// Clamp every gradient element of `params` into `[-clip, clip]` in place, a simpler
+/// stabilizer than rescaling by the norm. Call after backward and before stepping
+///
+pub fn clip_grad_value<'d, T: 'd + TensorType<'d> + Clone>(
+    params: &[Tensor<'d, '_, T>],
+    clip: f32,
+) {
+    assert!(clip >= 0.0, "clip must be non-negative, got {}", clip);
+    for param in params {
+        let nodes = param.graph.nodes.borrow();
+        let node = nodes[param.index].borrow();
+        if let Some(grad) = node.grad {
+            let clipped = grad.value().clamp(-clip, clip);
+            unsafe {
+                (*grad.data).copy_from(&clipped);
+            }
+        }
+    }
+}
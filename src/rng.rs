@@ -0,0 +1,57 @@
+use ndarray::{Array, IxDyn};
+
+///
+/// SplitMix64 pseudo-random generator for reproducible initialization. Small and
+/// dependency free; fine for weights, not for cryptography
+///
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    ///
+    /// Uniform in `[0, 1)`
+    ///
+    pub fn next_f32(&mut self) -> f32 {
+        // The top 24 bits fill an f32 mantissa exactly
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    ///
+    /// Standard normal sample via the Box-Muller transform
+    ///
+    pub fn next_normal(&mut self) -> f32 {
+        let u1 = 1.0 - self.next_f32();
+        let u2 = self.next_f32();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+    }
+}
+
+///
+/// Samples from the standard normal distribution
+///
+pub fn randn(shape: &[usize], seed: u64) -> Array<f32, IxDyn> {
+    let mut rng = Rng::new(seed);
+    Array::from_shape_simple_fn(shape, || rng.next_normal())
+}
+
+///
+/// Samples from the uniform distribution on `[low, high)`
+///
+pub fn uniform(shape: &[usize], low: f32, high: f32, seed: u64) -> Array<f32, IxDyn> {
+    let mut rng = Rng::new(seed);
+    Array::from_shape_simple_fn(shape, || low + (high - low) * rng.next_f32())
+}
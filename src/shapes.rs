@@ -0,0 +1,343 @@
+use crate::functions::{Function, OneValuedFnEnum, TwoValuedFnEnum};
+use crate::graph::Graph;
+use crate::tensor::TensorType;
+
+///
+/// The shape of `a` and `b` broadcast together, `None` if they are incompatible
+///
+fn broadcast(a: &[usize], b: &[usize]) -> Option<Vec<usize>> {
+    let n = a.len().max(b.len());
+    let pad = |s: &[usize]| {
+        let mut padded = vec![1; n - s.len()];
+        padded.extend_from_slice(s);
+        padded
+    };
+    let (a, b) = (pad(a), pad(b));
+    a.iter()
+        .zip(&b)
+        .map(|(&x, &y)| match (x, y) {
+            _ if x == y => Some(x),
+            (1, _) => Some(y),
+            (_, 1) => Some(x),
+            _ => None,
+        })
+        .collect()
+}
+
+fn check(ok: bool, msg: impl FnOnce() -> String) -> Result<(), String> {
+    if ok {
+        Ok(())
+    } else {
+        Err(msg())
+    }
+}
+
+fn one_shape<'d, T: TensorType<'d> + Clone>(
+    f: &OneValuedFnEnum<'d, T>,
+    a: &[usize],
+) -> Result<Vec<usize>, String> {
+    use OneValuedFnEnum::*;
+
+    let axis_in_range = |axis: usize| {
+        check(axis < a.len(), || {
+            format!("axis {} is out of range for shape {:?}", axis, a)
+        })
+    };
+    let square = |what: &str| {
+        check(a.len() == 2 && a[0] == a[1], || {
+            format!("{} expects a square matrix, got {:?}", what, a)
+        })
+    };
+    let vector = |what: &str, n: usize| {
+        check(a == [n], || {
+            format!("{} expects a {}-vector, got {:?}", what, n, a)
+        })
+    };
+
+    Ok(match f {
+        ExpM(_) => {
+            if a.len() == 3 {
+                check(a[1] == a[2], || {
+                    format!("expm expects a batch of square matrices, got {:?}", a)
+                })?;
+            } else {
+                square("expm")?;
+            }
+            a.to_vec()
+        }
+        ExpmSo3(_) => {
+            square("expm_so3")?;
+            a.to_vec()
+        }
+        Transpose(_) | ConjTranspose(_) => a.iter().rev().cloned().collect(),
+        Tile(f) => {
+            check(f.reps.len() == a.len(), || {
+                format!("tile has {} repetitions for shape {:?}", f.reps.len(), a)
+            })?;
+            a.iter().zip(&f.reps).map(|(n, r)| n * r).collect()
+        }
+        BroadcastTo(f) => {
+            let out = broadcast(a, &f.shape);
+            check(out.as_deref() == Some(&f.shape[..]), || {
+                format!("cannot broadcast {:?} to {:?}", a, f.shape)
+            })?;
+            f.shape.clone()
+        }
+        Slice(f) => {
+            check(f.ranges.len() <= a.len(), || {
+                format!("{} ranges for shape {:?}", f.ranges.len(), a)
+            })?;
+            let mut out = a.to_vec();
+            for (n, &(start, end)) in out.iter_mut().zip(&f.ranges) {
+                let end = if end == usize::MAX { *n } else { end };
+                check(end <= *n, || {
+                    format!("slice {}..{} is past {}", start, end, n)
+                })?;
+                check(start <= end, || {
+                    format!("slice {}..{} is empty or reversed", start, end)
+                })?;
+                *n = end - start;
+            }
+            out
+        }
+        Gather(f) => {
+            axis_in_range(f.axis)?;
+            let n = a[f.axis];
+            check(f.indices.iter().all(|&i| i < n), || {
+                format!("gather index out of range for axis {} of {:?}", f.axis, a)
+            })?;
+            let mut out = a.to_vec();
+            out[f.axis] = f.indices.len();
+            out
+        }
+        SumAxis(f) => {
+            for &axis in &f.axes {
+                axis_in_range(axis)?;
+            }
+            let kept = f.kept_shape(a);
+            if f.keepdims {
+                kept
+            } else {
+                (0..a.len())
+                    .filter(|i| !f.axes.contains(i))
+                    .map(|i| a[i])
+                    .collect()
+            }
+        }
+        CumSum(f) => {
+            axis_in_range(f.axis)?;
+            a.to_vec()
+        }
+        Softmax(f) => {
+            axis_in_range(f.axis)?;
+            a.to_vec()
+        }
+        LogSoftmax(f) => {
+            axis_in_range(f.axis)?;
+            a.to_vec()
+        }
+        Reshape(f) => {
+            if let crate::functions::ReshapeTo::Squeeze(axis) = f.to {
+                axis_in_range(axis)?;
+                check(a[axis] == 1, || {
+                    format!("cannot squeeze axis {} of shape {:?}", axis, a)
+                })?;
+            }
+            let out = f.to.resolve(a);
+            check(
+                out.iter().product::<usize>() == a.iter().product::<usize>(),
+                || format!("cannot reshape {:?} to {:?}", a, out),
+            )?;
+            out
+        }
+        Pad(f) => {
+            check(f.pads.len() == a.len(), || {
+                format!("{} pads for shape {:?}", f.pads.len(), a)
+            })?;
+            a.iter()
+                .zip(&f.pads)
+                .map(|(n, (before, after))| before + n + after)
+                .collect()
+        }
+        MaskedFill(f) => {
+            check(f.mask.shape() == a, || {
+                format!("mask of shape {:?} for shape {:?}", f.mask.shape(), a)
+            })?;
+            a.to_vec()
+        }
+        SoftmaxCrossEntropy(f) => {
+            check(a.len() == 2 && a[0] == f.targets.len(), || {
+                format!("{} targets for logits of shape {:?}", f.targets.len(), a)
+            })?;
+            vec![]
+        }
+        NllLoss(f) => {
+            check(a.len() == 2 && a[0] == f.targets.len(), || {
+                format!(
+                    "{} targets for log-probabilities of shape {:?}",
+                    f.targets.len(),
+                    a
+                )
+            })?;
+            vec![]
+        }
+        SpectralNorm(_) => {
+            check(a.len() == 2, || {
+                format!("spectral_norm expects a matrix, got {:?}", a)
+            })?;
+            vec![]
+        }
+        FroNorm(_) | AbsMax(_) => vec![],
+        QuatExp(_) => {
+            vector("quat_exp", 3)?;
+            vec![4]
+        }
+        QuatLog(_) => {
+            vector("quat_log", 4)?;
+            vec![3]
+        }
+        RotationLog(_) => {
+            check(a == [3, 3], || {
+                format!("rotation_log expects a 3x3 matrix, got {:?}", a)
+            })?;
+            vec![3]
+        }
+        BatchNorm(_) => {
+            check(!a.is_empty(), || {
+                "batch_norm needs a batch axis".to_string()
+            })?;
+            a.to_vec()
+        }
+        CustomOne(_) => return Err("custom ops have no shape rule".to_string()),
+        Floor(_) | Ceil(_) | Round(_) | StraightThrough(_) | HardSigmoid(_) | HardTanh(_)
+        | Elu(_) | Silu(_) | Rsqrt(_) | Scale(_) | WidthScale(_) | Abs2(_) | GradReverse(_)
+        | Alias(_) => a.to_vec(),
+    })
+}
+
+fn two_shape<'d, T: TensorType<'d> + Clone>(
+    f: &TwoValuedFnEnum<'d, T>,
+    a: &[usize],
+    b: &[usize],
+) -> Result<Vec<usize>, String> {
+    use TwoValuedFnEnum::*;
+
+    let same = |what: &str| {
+        check(a == b, || {
+            format!("{} expects equal shapes, got {:?} and {:?}", what, a, b)
+        })
+    };
+    let matrices = |what: &str| {
+        check(a.len() == 2 && b.len() == 2, || {
+            format!("{} expects matrices, got {:?} and {:?}", what, a, b)
+        })
+    };
+
+    Ok(match f {
+        Add(_) | Mul(_) | Maximum(_) | Minimum(_) | Compare(_) => {
+            broadcast(a, b).ok_or_else(|| format!("cannot broadcast {:?} with {:?}", a, b))?
+        }
+        MatMul(_) => {
+            matrices("matmul")?;
+            check(a[1] == b[0], || {
+                format!("matmul inner dimensions differ: {:?} @ {:?}", a, b)
+            })?;
+            vec![a[0], b[1]]
+        }
+        Dot(_) => {
+            same("dot")?;
+            vec![]
+        }
+        Outer(_) => {
+            check(a.len() == 1 && b.len() == 1, || {
+                format!("outer expects vectors, got {:?} and {:?}", a, b)
+            })?;
+            vec![a[0], b[0]]
+        }
+        BceLoss(_) | BceWithLogits(_) => {
+            same("binary cross-entropy")?;
+            vec![]
+        }
+        Stack(_) => {
+            same("stack")?;
+            std::iter::once(2).chain(a.iter().cloned()).collect()
+        }
+        RmsNorm(_) => {
+            check(!a.is_empty() && b == [a[a.len() - 1]], || {
+                format!("rms_norm weight {:?} does not match input {:?}", b, a)
+            })?;
+            a.to_vec()
+        }
+        Kron(_) => {
+            matrices("kron")?;
+            vec![a[0] * b[0], a[1] * b[1]]
+        }
+        Commutator(_) => {
+            same("commutator")?;
+            check(a.len() == 2 && a[0] == a[1], || {
+                format!("commutator expects square matrices, got {:?}", a)
+            })?;
+            a.to_vec()
+        }
+        TensorDot(f) => {
+            let (axes_a, axes_b) = &f.axes;
+            for (&i, &j) in axes_a.iter().zip(axes_b) {
+                check(i < a.len() && j < b.len() && a[i] == b[j], || {
+                    format!(
+                        "tensordot cannot contract axis {} of {:?} with axis {} of {:?}",
+                        i, a, j, b
+                    )
+                })?;
+            }
+            let free = |s: &[usize], axes: &[usize]| {
+                (0..s.len())
+                    .filter(|i| !axes.contains(i))
+                    .map(|i| s[i])
+                    .collect::<Vec<_>>()
+            };
+            let mut out = free(a, axes_a);
+            out.extend(free(b, axes_b));
+            out
+        }
+        CustomTwo(_) => return Err("custom ops have no shape rule".to_string()),
+    })
+}
+
+impl<'d, T: TensorType<'d> + Clone> Graph<'d, T> {
+    ///
+    /// The shape of every node, propagated from the leaf shapes through each op's shape rule
+    /// without evaluating anything, so mismatches surface before a potentially expensive
+    /// forward. The error names the first offending node, e.g.
+    /// `v2 (MatMul): matmul inner dimensions differ: [2, 3] @ [4, 5]`
+    ///
+    /// Custom ops have no shape rule; their shape is taken from an already computed value
+    ///
+    pub fn infer_shapes(&self) -> Result<Vec<Vec<usize>>, String> {
+        let nodes = self.nodes.borrow();
+        let mut shapes: Vec<Vec<usize>> = Vec::with_capacity(nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            let node = node.borrow();
+            let [d_0, d_1] = node.deps;
+            let shape = match &node.func {
+                Function::None => Ok(node.value.as_ref().unwrap().value().shape()),
+                Function::One(f) => one_shape(f, &shapes[d_0]),
+                Function::Two(f) => two_shape(f, &shapes[d_0], &shapes[d_1]),
+            };
+            let shape = match (shape, node.value) {
+                (Ok(shape), _) => shape,
+                (Err(_), Some(value))
+                    if matches!(
+                        node.func,
+                        Function::One(OneValuedFnEnum::CustomOne(_))
+                            | Function::Two(TwoValuedFnEnum::CustomTwo(_))
+                    ) =>
+                {
+                    value.value().shape()
+                }
+                (Err(msg), _) => return Err(format!("v{} ({}): {}", i, node.func.name(), msg)),
+            };
+            shapes.push(shape);
+        }
+        Ok(shapes)
+    }
+}
@@ -0,0 +1,430 @@
+use crate::functions::{Function, OneValuedFnEnum, TwoValuedFnEnum};
+use crate::graph::{Graph, GraphError, Node, OpSpec};
+use crate::tensor::{Raw, TensorType};
+use ndarray::{ArrayD, IxDyn};
+use std::mem::Discriminant;
+
+///
+/// One node of a graph description: a leaf, or an op on earlier nodes. The node's index is
+/// its position in the list
+///
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NodeSpec {
+    /// Without a value the leaf is rebuilt as zeros of the given shape
+    Leaf {
+        shape: Vec<usize>,
+        requires_grad: bool,
+        value: Option<Vec<f32>>,
+    },
+    Op(OpSpec),
+}
+
+impl<'d, T: TensorType<'d> + Clone> Graph<'d, T> {
+    ///
+    /// Describe the graph as plain data, e.g. to persist a model architecture. Leaf values
+    /// and batch norm running statistics are included when `with_values` is set; op
+    /// contexts are never stored and are recomputed by the next forward. Only custom ops
+    /// are unsupported
+    ///
+    pub fn spec(&self, with_values: bool) -> Result<Vec<NodeSpec>, GraphError> {
+        let nodes = self.nodes.borrow();
+        let mut spec = Vec::with_capacity(nodes.len());
+        for (index, node) in nodes.iter().enumerate() {
+            let node = node.borrow();
+            if let Function::None = node.func {
+                let value = node.value.as_ref().unwrap().value();
+                let data = value.get_value_cpu();
+                spec.push(NodeSpec::Leaf {
+                    shape: value.shape(),
+                    requires_grad: node.requires_grad,
+                    value: if with_values {
+                        Some(data.iter().cloned().collect())
+                    } else {
+                        None
+                    },
+                });
+                continue;
+            }
+            let op = op_spec(&node, with_values).ok_or(GraphError::Unsupported {
+                index,
+                op: node.func.name(),
+            })?;
+            spec.push(NodeSpec::Op(op));
+        }
+        Ok(spec)
+    }
+}
+
+///
+/// A hashable identity of an op, e.g. to find duplicates. Floats compare by bit pattern, so
+/// keys are equal exactly when the ops compute the same thing
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct OpKey(Discriminant<OpSpec>, Vec<u64>);
+
+impl OpSpec {
+    pub(crate) fn key(&self) -> OpKey {
+        use OpSpec::*;
+        let bits = |x: &f32| u64::from(x.to_bits());
+        // Lists are prefixed with their length so that neighbouring ones cannot run together
+        let list = |xs: &[usize]| -> Vec<u64> {
+            std::iter::once(xs.len())
+                .chain(xs.iter().cloned())
+                .map(|x| x as u64)
+                .collect()
+        };
+        let pairs = |xs: &[(usize, usize)]| -> Vec<u64> {
+            std::iter::once(xs.len())
+                .chain(xs.iter().flat_map(|&(lo, hi)| vec![lo, hi]))
+                .map(|x| x as u64)
+                .collect()
+        };
+        let words: Vec<u64> = match self {
+            ExpM(a) | Silu(a) | Floor(a) | Ceil(a) | Round(a) | Transpose(a) | ConjTranspose(a)
+            | HardSigmoid(a) | HardTanh(a) | Rsqrt(a) | FroNorm(a) | AbsMax(a) | ExpmSo3(a)
+            | QuatExp(a) | QuatLog(a) | RotationLog(a) | Abs2(a) | WidthScale(a) | Alias(a) => {
+                vec![*a as u64]
+            }
+            Add(a, b)
+            | Mul(a, b)
+            | MatMul(a, b)
+            | Maximum(a, b)
+            | Minimum(a, b)
+            | Dot(a, b)
+            | Outer(a, b)
+            | Stack(a, b)
+            | Kron(a, b)
+            | Commutator(a, b)
+            | Anticommutator(a, b)
+            | BceLoss(a, b)
+            | BceWithLogits(a, b) => vec![*a as u64, *b as u64],
+            Softmax {
+                input,
+                axis,
+                temperature,
+            }
+            | LogSoftmax {
+                input,
+                axis,
+                temperature,
+            } => vec![*input as u64, *axis as u64, bits(temperature)],
+            Scale { input, factor: x }
+            | Elu { input, alpha: x }
+            | GradReverse { input, lambda: x } => {
+                vec![*input as u64, bits(x)]
+            }
+            Reshape { input, shape: xs }
+            | SumAxes { input, axes: xs }
+            | MeanAxes { input, axes: xs }
+            | Tile { input, reps: xs }
+            | BroadcastTo { input, shape: xs }
+            | SoftmaxCrossEntropy { input, targets: xs }
+            | NllLoss { input, targets: xs } => {
+                std::iter::once(*input as u64).chain(list(xs)).collect()
+            }
+            SumAxis {
+                input,
+                axis,
+                keepdims,
+            }
+            | MeanAxis {
+                input,
+                axis,
+                keepdims,
+            } => vec![*input as u64, *axis as u64, *keepdims as u64],
+            CumSum { input, axis } | SpectralNorm { input, iters: axis } => {
+                vec![*input as u64, *axis as u64]
+            }
+            Gather {
+                input,
+                axis,
+                indices,
+            } => vec![*input as u64, *axis as u64]
+                .into_iter()
+                .chain(list(indices))
+                .collect(),
+            TensorDot { a, b, axes } => vec![*a as u64, *b as u64]
+                .into_iter()
+                .chain(list(&axes.0))
+                .chain(list(&axes.1))
+                .collect(),
+            Compare { a, b, cmp } => vec![*a as u64, *b as u64, *cmp as u64],
+            RmsNorm { input, weight, eps } => vec![*input as u64, *weight as u64, bits(eps)],
+            StraightThrough { input, op } => vec![*input as u64, *op as u64],
+            Slice { input, ranges } => std::iter::once(*input as u64)
+                .chain(pairs(ranges))
+                .collect(),
+            Pad { input, pads, value } => vec![*input as u64, bits(value)]
+                .into_iter()
+                .chain(pairs(pads))
+                .collect(),
+            // The mask's length follows from its shape
+            MaskedFill {
+                input,
+                mask_shape,
+                mask,
+                value,
+            } => vec![*input as u64, bits(value)]
+                .into_iter()
+                .chain(list(mask_shape))
+                .chain(mask.iter().map(|&m| m as u64))
+                .collect(),
+            BatchNorm {
+                input,
+                momentum,
+                eps,
+                training,
+                running,
+            } => {
+                let mut words = vec![*input as u64, bits(momentum), bits(eps), *training as u64];
+                if let Some((mean, var)) = running {
+                    words.push(mean.len() as u64);
+                    words.extend(mean.iter().chain(var).map(bits));
+                }
+                words
+            }
+        };
+        OpKey(std::mem::discriminant(self), words)
+    }
+}
+
+///
+/// The `OpSpec` of an op node, `None` for leaves and custom ops. State kept by the op, such
+/// as batch norm running statistics, is included when `with_values` is set
+///
+pub(crate) fn op_spec<'d, T: TensorType<'d> + Clone>(
+    node: &Node<'d, T>,
+    with_values: bool,
+) -> Option<OpSpec> {
+    let [a, b] = node.deps;
+    let cpu = |stat: Option<Raw<'d, T>>| {
+        stat.map(|s| s.value().get_value_cpu().iter().cloned().collect())
+    };
+    match &node.func {
+        Function::None => None,
+        Function::One(f) => match f {
+            OneValuedFnEnum::ExpM(_) => Some(OpSpec::ExpM(a)),
+            OneValuedFnEnum::Silu(_) => Some(OpSpec::Silu(a)),
+            OneValuedFnEnum::Softmax(f) => Some(OpSpec::Softmax {
+                input: a,
+                axis: f.axis,
+                temperature: f.temperature,
+            }),
+            OneValuedFnEnum::Scale(f) => Some(OpSpec::Scale {
+                input: a,
+                factor: f.factor,
+            }),
+            OneValuedFnEnum::Reshape(f) => {
+                // Shapes relative to the input are only known once forward has run
+                let shape = match (&f.to, &f.in_shape) {
+                    (crate::functions::ReshapeTo::Shape(shape), _) => Some(shape.clone()),
+                    (to, Some(in_shape)) => Some(to.resolve(in_shape)),
+                    (_, None) => None,
+                };
+                shape.map(|shape| OpSpec::Reshape { input: a, shape })
+            }
+            OneValuedFnEnum::SumAxis(f) if f.axes.len() == 1 => Some(if f.mean {
+                OpSpec::MeanAxis {
+                    input: a,
+                    axis: f.axes[0],
+                    keepdims: f.keepdims,
+                }
+            } else {
+                OpSpec::SumAxis {
+                    input: a,
+                    axis: f.axes[0],
+                    keepdims: f.keepdims,
+                }
+            }),
+            // Several axes are only ever reduced without keeping them
+            OneValuedFnEnum::SumAxis(f) => Some(if f.mean {
+                OpSpec::MeanAxes {
+                    input: a,
+                    axes: f.axes.clone(),
+                }
+            } else {
+                OpSpec::SumAxes {
+                    input: a,
+                    axes: f.axes.clone(),
+                }
+            }),
+            OneValuedFnEnum::Floor(_) => Some(OpSpec::Floor(a)),
+            OneValuedFnEnum::Ceil(_) => Some(OpSpec::Ceil(a)),
+            OneValuedFnEnum::Round(_) => Some(OpSpec::Round(a)),
+            OneValuedFnEnum::Transpose(_) => Some(OpSpec::Transpose(a)),
+            OneValuedFnEnum::ConjTranspose(_) => Some(OpSpec::ConjTranspose(a)),
+            OneValuedFnEnum::HardSigmoid(_) => Some(OpSpec::HardSigmoid(a)),
+            OneValuedFnEnum::HardTanh(_) => Some(OpSpec::HardTanh(a)),
+            OneValuedFnEnum::Rsqrt(_) => Some(OpSpec::Rsqrt(a)),
+            OneValuedFnEnum::FroNorm(_) => Some(OpSpec::FroNorm(a)),
+            OneValuedFnEnum::AbsMax(_) => Some(OpSpec::AbsMax(a)),
+            OneValuedFnEnum::ExpmSo3(_) => Some(OpSpec::ExpmSo3(a)),
+            OneValuedFnEnum::QuatExp(_) => Some(OpSpec::QuatExp(a)),
+            OneValuedFnEnum::QuatLog(_) => Some(OpSpec::QuatLog(a)),
+            OneValuedFnEnum::RotationLog(_) => Some(OpSpec::RotationLog(a)),
+            OneValuedFnEnum::Abs2(_) => Some(OpSpec::Abs2(a)),
+            OneValuedFnEnum::WidthScale(_) => Some(OpSpec::WidthScale(a)),
+            OneValuedFnEnum::Alias(_) => Some(OpSpec::Alias(a)),
+            OneValuedFnEnum::StraightThrough(f) => {
+                Some(OpSpec::StraightThrough { input: a, op: f.op })
+            }
+            OneValuedFnEnum::Tile(f) => Some(OpSpec::Tile {
+                input: a,
+                reps: f.reps.clone(),
+            }),
+            OneValuedFnEnum::BroadcastTo(f) => Some(OpSpec::BroadcastTo {
+                input: a,
+                shape: f.shape.clone(),
+            }),
+            OneValuedFnEnum::Slice(f) => Some(OpSpec::Slice {
+                input: a,
+                ranges: f.ranges.clone(),
+            }),
+            OneValuedFnEnum::Gather(f) => Some(OpSpec::Gather {
+                input: a,
+                axis: f.axis,
+                indices: f.indices.clone(),
+            }),
+            OneValuedFnEnum::Pad(f) => Some(OpSpec::Pad {
+                input: a,
+                pads: f.pads.clone(),
+                value: f.value,
+            }),
+            OneValuedFnEnum::CumSum(f) => Some(OpSpec::CumSum {
+                input: a,
+                axis: f.axis,
+            }),
+            OneValuedFnEnum::MaskedFill(f) => Some(OpSpec::MaskedFill {
+                input: a,
+                mask_shape: f.mask.shape().to_vec(),
+                mask: f.mask.iter().cloned().collect(),
+                value: f.value,
+            }),
+            OneValuedFnEnum::LogSoftmax(f) => Some(OpSpec::LogSoftmax {
+                input: a,
+                axis: f.axis,
+                temperature: f.temperature,
+            }),
+            OneValuedFnEnum::SoftmaxCrossEntropy(f) => Some(OpSpec::SoftmaxCrossEntropy {
+                input: a,
+                targets: f.targets.clone(),
+            }),
+            OneValuedFnEnum::NllLoss(f) => Some(OpSpec::NllLoss {
+                input: a,
+                targets: f.targets.clone(),
+            }),
+            OneValuedFnEnum::Elu(f) => Some(OpSpec::Elu {
+                input: a,
+                alpha: f.alpha,
+            }),
+            OneValuedFnEnum::GradReverse(f) => Some(OpSpec::GradReverse {
+                input: a,
+                lambda: f.lambda,
+            }),
+            OneValuedFnEnum::SpectralNorm(f) => Some(OpSpec::SpectralNorm {
+                input: a,
+                iters: f.iters,
+            }),
+            OneValuedFnEnum::BatchNorm(f) => {
+                let stats = &f.stats;
+                // Stats loaded from a spec are only uploaded by the first forward
+                let running = match (cpu(stats.running_mean.get()), cpu(stats.running_var.get())) {
+                    _ if !with_values => None,
+                    (Some(mean), Some(var)) => Some((mean, var)),
+                    _ => f.initial_stats.clone(),
+                };
+                Some(OpSpec::BatchNorm {
+                    input: a,
+                    momentum: stats.momentum,
+                    eps: stats.eps,
+                    training: stats.training.get(),
+                    running,
+                })
+            }
+            OneValuedFnEnum::CustomOne(_) => None,
+        },
+        Function::Two(f) => match f {
+            TwoValuedFnEnum::Add(_) => Some(OpSpec::Add(a, b)),
+            TwoValuedFnEnum::Mul(_) => Some(OpSpec::Mul(a, b)),
+            TwoValuedFnEnum::MatMul(_) => Some(OpSpec::MatMul(a, b)),
+            TwoValuedFnEnum::Maximum(_) => Some(OpSpec::Maximum(a, b)),
+            TwoValuedFnEnum::Minimum(_) => Some(OpSpec::Minimum(a, b)),
+            TwoValuedFnEnum::Dot(_) => Some(OpSpec::Dot(a, b)),
+            TwoValuedFnEnum::Outer(_) => Some(OpSpec::Outer(a, b)),
+            TwoValuedFnEnum::Stack(_) => Some(OpSpec::Stack(a, b)),
+            TwoValuedFnEnum::Kron(_) => Some(OpSpec::Kron(a, b)),
+            TwoValuedFnEnum::Commutator(f) if f.anti => Some(OpSpec::Anticommutator(a, b)),
+            TwoValuedFnEnum::Commutator(_) => Some(OpSpec::Commutator(a, b)),
+            TwoValuedFnEnum::BceLoss(_) => Some(OpSpec::BceLoss(a, b)),
+            TwoValuedFnEnum::BceWithLogits(_) => Some(OpSpec::BceWithLogits(a, b)),
+            TwoValuedFnEnum::TensorDot(f) => Some(OpSpec::TensorDot {
+                a,
+                b,
+                axes: f.axes.clone(),
+            }),
+            TwoValuedFnEnum::Compare(f) => Some(OpSpec::Compare { a, b, cmp: f.cmp }),
+            TwoValuedFnEnum::RmsNorm(f) => Some(OpSpec::RmsNorm {
+                input: a,
+                weight: b,
+                eps: f.eps,
+            }),
+            TwoValuedFnEnum::CustomTwo(_) => None,
+        },
+    }
+}
+
+impl<'d> Graph<'d, ArrayD<f32>> {
+    ///
+    /// Rebuild a graph from `Graph::spec`. Node indices match the original
+    ///
+    pub fn from_spec(spec: &[NodeSpec]) -> Self {
+        let graph = Graph::new();
+        for node in spec {
+            match node {
+                NodeSpec::Leaf {
+                    shape,
+                    requires_grad,
+                    value,
+                } => {
+                    let value = match value {
+                        Some(data) => ArrayD::from_shape_vec(IxDyn(shape), data.clone())
+                            .unwrap_or_else(|_| {
+                                panic!(
+                                    "leaf v{} has {} values but shape {:?}",
+                                    graph.len(),
+                                    data.len(),
+                                    shape
+                                )
+                            }),
+                        None => ArrayD::zeros(IxDyn(shape)),
+                    };
+                    graph.tensor(value).requires_grad(*requires_grad);
+                }
+                NodeSpec::Op(op) => {
+                    graph.run(std::slice::from_ref(op));
+                }
+            }
+        }
+        graph
+    }
+}
+
+///
+/// Serializes as the `Graph::spec` list, leaf values included
+///
+#[cfg(feature = "serde")]
+impl<'d, T: TensorType<'d> + Clone> serde::Serialize for Graph<'d, T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let spec = self.spec(true).map_err(serde::ser::Error::custom)?;
+        serde::Serialize::serialize(&spec, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, 'd> serde::Deserialize<'de> for Graph<'d, ArrayD<f32>> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let spec: Vec<NodeSpec> = serde::Deserialize::deserialize(deserializer)?;
+        Ok(Graph::from_spec(&spec))
+    }
+}
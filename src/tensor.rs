@@ -1,14 +1,47 @@
-use crate::functions::{Function, OneValuedFn, TwoValuedFn};
-use crate::graph::{Graph, Node};
-use std::cell::RefCell;
+use crate::functions::{Comparison, Function, OneValuedFn, Rounding, TwoValuedFn};
+use crate::graph::{Graph, GraphError, Node};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use ndarray::{Array, Ix2, IxDyn, WgpuArray};
+use ndarray::{Array, Dimension, Ix2, IxDyn, WgpuArray};
+
+///
+/// Implementation used for CPU matrix products
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatmulBackend {
+    /// A plain triple loop, useful as a reference
+    Naive,
+    /// `ndarray`'s `dot`, which calls into BLAS when the `blas` feature is enabled
+    Ndarray,
+}
+
+thread_local! {
+    static MATMUL_BACKEND: Cell<MatmulBackend> = const { Cell::new(MatmulBackend::Ndarray) };
+}
+
+///
+/// Select the CPU matmul implementation for the current thread
+///
+pub fn set_matmul_backend(backend: MatmulBackend) {
+    MATMUL_BACKEND.with(|b| b.set(backend));
+}
+
+///
+/// The CPU matmul implementation in use on the current thread
+///
+pub fn matmul_backend() -> MatmulBackend {
+    MATMUL_BACKEND.with(|b| b.get())
+}
 
 ///
 /// The base trait for Tensor objects
 ///
 pub trait TensorType<'d> {
+    /// Whether the data lives off the CPU, so that `Tensor::value` caches its CPU copy
+    const ON_DEVICE: bool = false;
     fn get_value_cpu(&self) -> Array<f32, IxDyn>;
     fn tensor(&self) -> &Self;
     fn add(&self, other: &Self) -> Self;
@@ -16,19 +49,198 @@ pub trait TensorType<'d> {
     fn mul(&self, other: &Self) -> Self;
     fn div(&self, other: &Self) -> Self;
     fn matmul(&self, other: &Self) -> Self;
+    ///
+    /// Matrix product with either operand optionally transposed, e.g. `x^T @ y`.
+    /// Backends which support strided views override this to avoid copying the transpose.
+    ///
+    fn matmul_transposed(&self, other: &Self, transpose_self: bool, transpose_other: bool) -> Self
+    where
+        Self: Sized,
+    {
+        match (transpose_self, transpose_other) {
+            (false, false) => self.matmul(other),
+            (true, false) => self.t().matmul(other),
+            (false, true) => self.matmul(&other.t()),
+            (true, true) => self.t().matmul(&other.t()),
+        }
+    }
+    ///
+    /// Sum a broadcast result back down to `shape`: leading axes are summed away and axes
+    /// of length 1 in `shape` are summed with the length kept. Used by broadcasting
+    /// backward passes.
+    ///
+    fn sum_to(&'d self, shape: &[usize]) -> Self
+    where
+        Self: Sized,
+    {
+        let own = self.shape();
+        let lead = own.len() - shape.len();
+        let axes: Vec<usize> = (0..own.len())
+            .filter(|&i| i < lead || (shape[i - lead] == 1 && own[i] != 1))
+            .collect();
+
+        let summed = Raw::new(self.sum_axes(&axes));
+        summed.value().reshape(shape)
+    }
     fn t(&self) -> Self;
+    ///
+    /// Conjugate transpose (Hermitian adjoint). Real tensors have nothing to conjugate, so
+    /// this is `t()` unless a complex type overrides it
+    ///
+    fn conj_t(&'d self) -> Self
+    where
+        Self: Sized,
+    {
+        self.t()
+    }
     fn expm(&self) -> Self;
+    fn floor(&'d self) -> Self;
+    fn ceil(&'d self) -> Self;
+    fn round(&'d self) -> Self;
+    fn ge(&'d self, other: &Self) -> Self;
+    fn shape(&self) -> Vec<usize>;
+    fn sum(&'d self) -> Self;
+    fn reshape(&'d self, shape: &[usize]) -> Self;
+    fn sum_axes(&'d self, axes: &[usize]) -> Self;
+    fn tile(&'d self, reps: &[usize]) -> Self;
+    fn broadcast_to(&'d self, shape: &[usize]) -> Self;
+    fn slice_ranges(&'d self, ranges: &[(usize, usize)]) -> Self;
+    fn scatter_ranges(&'d self, shape: &[usize], ranges: &[(usize, usize)]) -> Self;
+    fn gather(&'d self, axis: usize, indices: &[usize]) -> Self;
+    fn scatter_add(&'d self, shape: &[usize], axis: usize, indices: &[usize]) -> Self;
+    fn sqrt(&'d self) -> Self;
+    fn mul_scalar(&'d self, val: f32) -> Self;
+    fn add_scalar(&'d self, val: f32) -> Self;
+    fn log_softmax(&'d self, axis: usize) -> Self;
+    fn softmax(&'d self, axis: usize) -> Self;
+    fn one_hot(&'d self, targets: &[usize]) -> Self;
+    fn clamp(&'d self, min: f32, max: f32) -> Self;
+    fn in_range(&'d self, min: f32, max: f32) -> Self;
+    fn sigmoid(&'d self) -> Self;
+    fn ln(&'d self) -> Self;
+    fn softplus(&'d self) -> Self;
+    fn cumsum(&'d self, axis: usize) -> Self;
+    ///
+    /// Stack `self` and `rest`, all of the same shape, along a new leading axis
+    ///
+    fn stack(&'d self, rest: &[&Self]) -> Self;
+    fn masked_fill(&'d self, mask: &Array<bool, IxDyn>, value: f32) -> Self;
+    ///
+    /// 0/1 mask of where `cmp` holds between `self` and `other`
+    ///
+    fn compare(&'d self, other: &Self, cmp: Comparison) -> Self;
+    ///
+    /// `self` where `mask` is nonzero and `other` elsewhere, picking rather than blending so
+    /// that infinities in the unpicked operand do not turn into NaN
+    ///
+    fn select(&'d self, mask: &Self, other: &Self) -> Self;
+    fn pad(&'d self, pads: &[(usize, usize)], value: f32) -> Self;
+    ///
+    /// Complex conjugate, the identity for real tensors
+    ///
+    fn conj(&'d self) -> Self;
+    ///
+    /// Squared magnitude `z * conj(z)`, i.e. `x^2` for real tensors
+    ///
+    fn abs2(&'d self) -> Self;
+    ///
+    /// `self / (other + eps)` without materializing the shifted denominator as its own value,
+    /// e.g. for normalizations that must stay finite on constant inputs
+    ///
+    fn div_eps(&self, other: &'d Self, eps: f32) -> Self;
+    ///
+    /// Reorder the axes so that axis `i` of the result is axis `axes[i]` of the input
+    ///
+    fn permute(&'d self, axes: &[usize]) -> Self;
+    ///
+    /// Real data computed on the CPU, placed on the same device as `self`
+    ///
+    fn upload_like(&'d self, data: Array<f32, IxDyn>) -> Self;
     fn val_like(&'d self, val: f32) -> Self;
     fn ones_like(&'d self) -> Self;
     fn eye_like(&'d self) -> Self;
+
+    // Element-wise operations which update the left operand in place, avoiding an
+    // allocation when the result is consumed immediately (e.g. gradient accumulation).
+    // The defaults go through the allocating ops; backends with in-place kernels override them
+
+    fn add_assign(&mut self, other: &Self)
+    where
+        Self: Sized,
+    {
+        *self = TensorType::add(self, other);
+    }
+    fn sub_assign(&mut self, other: &Self)
+    where
+        Self: Sized,
+    {
+        *self = TensorType::sub(self, other);
+    }
+    fn mul_assign(&mut self, other: &Self)
+    where
+        Self: Sized,
+    {
+        *self = TensorType::mul(self, other);
+    }
+    fn div_assign(&mut self, other: &Self)
+    where
+        Self: Sized,
+    {
+        *self = TensorType::div(self, other);
+    }
+    fn scale(&mut self, val: f32)
+    where
+        Self: Sized + Clone + 'd,
+    {
+        // mul_scalar borrows for 'd, so the current value needs a home that lives that long
+        let this = Raw::new(self.clone());
+        *self = this.value().mul_scalar(val);
+    }
+    /// Overwrite with the contents of `other`. Returns false, leaving `self` untouched, if
+    /// the shapes differ
+    fn copy_from(&mut self, other: &Self) -> bool
+    where
+        Self: Sized + Clone,
+    {
+        if self.shape() != other.shape() {
+            return false;
+        }
+        *self = other.clone();
+        true
+    }
 }
 impl<'d> TensorType<'d> for Array<f32, IxDyn> {
+    fn add_assign(&mut self, other: &Self) {
+        *self += other;
+    }
+    fn sub_assign(&mut self, other: &Self) {
+        *self -= other;
+    }
+    fn mul_assign(&mut self, other: &Self) {
+        *self *= other;
+    }
+    fn div_assign(&mut self, other: &Self) {
+        *self /= other;
+    }
+    fn scale(&mut self, val: f32) {
+        self.mapv_inplace(|x| x * val);
+    }
+    fn copy_from(&mut self, other: &Self) -> bool {
+        if self.shape() != other.shape() {
+            return false;
+        }
+        self.assign(other);
+        true
+    }
     fn get_value_cpu(&self) -> Array<f32, IxDyn> {
         self.clone()
     }
     fn tensor(&self) -> &Self {
         self
     }
+    fn upload_like(&'d self, data: Array<f32, IxDyn>) -> Self {
+        data
+    }
     fn val_like(&'d self, val: f32) -> Self {
         let shape = self.shape();
         Array::ones(shape) * val
@@ -54,17 +266,35 @@ impl<'d> TensorType<'d> for Array<f32, IxDyn> {
         self / other
     }
     fn matmul(&self, other: &Self) -> Self {
-        //TODO: Remove cloning (maybe by passing Raw<T>
-        let x = self
-            .clone()
+        self.matmul_transposed(other, false, false)
+    }
+    fn matmul_transposed(&self, other: &Self, transpose_self: bool, transpose_other: bool) -> Self {
+        // Transposing a view only swaps strides, so no data is copied
+        let mut x = self
+            .view()
             .into_dimensionality::<Ix2>()
             .expect("Not a 2x2 matrix");
-        let y = other
-            .clone()
+        let mut y = other
+            .view()
             .into_dimensionality::<Ix2>()
             .expect("Not a 2x2 matrix");
+        if transpose_self {
+            x = x.reversed_axes();
+        }
+        if transpose_other {
+            y = y.reversed_axes();
+        }
 
-        (x.dot(&y)).into_dyn()
+        match matmul_backend() {
+            MatmulBackend::Ndarray => (x.dot(&y)).into_dyn(),
+            MatmulBackend::Naive => {
+                let (m, k) = x.dim();
+                let (k2, n) = y.dim();
+                assert_eq!(k, k2, "Inner dimensions do not match");
+                Array::from_shape_fn((m, n), |(i, j)| (0..k).map(|l| x[[i, l]] * y[[l, j]]).sum())
+                    .into_dyn()
+            }
+        }
     }
     fn t(&self) -> Self {
         self.clone().reversed_axes()
@@ -73,14 +303,216 @@ impl<'d> TensorType<'d> for Array<f32, IxDyn> {
         self.mapv(|x| x.exp())
         //Array::from_iter(self.iter().map(|x| x.exp()))
     }
+    fn floor(&'d self) -> Self {
+        self.mapv(f32::floor)
+    }
+    fn ceil(&'d self) -> Self {
+        self.mapv(f32::ceil)
+    }
+    fn round(&'d self) -> Self {
+        self.mapv(f32::round)
+    }
+    fn ge(&'d self, other: &Self) -> Self {
+        self.compare(other, Comparison::Ge)
+    }
+    fn shape(&self) -> Vec<usize> {
+        self.shape().to_vec()
+    }
+    fn sum(&'d self) -> Self {
+        Array::from_elem(IxDyn(&[]), self.sum())
+    }
+    fn reshape(&'d self, shape: &[usize]) -> Self {
+        // Copy in logical order so transposed (non-contiguous) arrays reshape correctly
+        Array::from_shape_vec(IxDyn(shape), self.iter().cloned().collect())
+            .expect("Incompatible shape for reshape")
+    }
+    fn sum_axes(&'d self, axes: &[usize]) -> Self {
+        let mut axes = axes.to_vec();
+        axes.sort_unstable();
+        axes.iter()
+            .rev()
+            .fold(self.clone(), |acc, &ax| acc.sum_axis(ndarray::Axis(ax)))
+    }
+    fn tile(&'d self, reps: &[usize]) -> Self {
+        let shape = self.shape();
+        assert_eq!(
+            reps.len(),
+            shape.len(),
+            "tile expects one repetition count per axis"
+        );
+        let out_shape: Vec<usize> = shape.iter().zip(reps).map(|(n, r)| n * r).collect();
+        Array::from_shape_fn(IxDyn(&out_shape), |idx| {
+            let src: Vec<usize> = idx.slice().iter().zip(shape).map(|(i, n)| i % n).collect();
+            self[IxDyn(&src)]
+        })
+    }
+    fn broadcast_to(&'d self, shape: &[usize]) -> Self {
+        self.broadcast(IxDyn(shape))
+            .unwrap_or_else(|| panic!("Cannot broadcast {:?} to {:?}", self.shape(), shape))
+            .to_owned()
+    }
+    fn slice_ranges(&'d self, ranges: &[(usize, usize)]) -> Self {
+        let mut view = self.view();
+        for (ax, &(start, end)) in ranges.iter().enumerate() {
+            view.slice_axis_inplace(ndarray::Axis(ax), (start..end).into());
+        }
+        view.to_owned()
+    }
+    fn scatter_ranges(&'d self, shape: &[usize], ranges: &[(usize, usize)]) -> Self {
+        let mut out = Array::zeros(IxDyn(shape));
+        {
+            let mut view = out.view_mut();
+            for (ax, &(start, end)) in ranges.iter().enumerate() {
+                view.slice_axis_inplace(ndarray::Axis(ax), (start..end).into());
+            }
+            view.assign(self);
+        }
+        out
+    }
+    fn gather(&'d self, axis: usize, indices: &[usize]) -> Self {
+        self.select(ndarray::Axis(axis), indices)
+    }
+    fn scatter_add(&'d self, shape: &[usize], axis: usize, indices: &[usize]) -> Self {
+        let axis = ndarray::Axis(axis);
+        let mut out = Array::zeros(IxDyn(shape));
+        for (k, &idx) in indices.iter().enumerate() {
+            out.index_axis_mut(axis, idx)
+                .zip_mut_with(&self.index_axis(axis, k), |a, &b| *a += b);
+        }
+        out
+    }
+    fn sqrt(&'d self) -> Self {
+        self.mapv(f32::sqrt)
+    }
+    fn mul_scalar(&'d self, val: f32) -> Self {
+        self * val
+    }
+    fn add_scalar(&'d self, val: f32) -> Self {
+        self + val
+    }
+    fn log_softmax(&'d self, axis: usize) -> Self {
+        // Shift by the max so that exp never overflows
+        let axis = ndarray::Axis(axis);
+        let max = self
+            .fold_axis(axis, f32::NEG_INFINITY, |&a, &b| a.max(b))
+            .insert_axis(axis);
+        let shifted = self - &max;
+        let lse = shifted
+            .mapv(f32::exp)
+            .sum_axis(axis)
+            .mapv(f32::ln)
+            .insert_axis(axis);
+        shifted - &lse
+    }
+    fn softmax(&'d self, axis: usize) -> Self {
+        self.log_softmax(axis).mapv(f32::exp)
+    }
+    fn one_hot(&'d self, targets: &[usize]) -> Self {
+        let mut out = Array::zeros(self.raw_dim());
+        for (i, &t) in targets.iter().enumerate() {
+            out[[i, t]] = 1.0;
+        }
+        out
+    }
+    fn clamp(&'d self, min: f32, max: f32) -> Self {
+        self.mapv(|x| x.max(min).min(max))
+    }
+    fn in_range(&'d self, min: f32, max: f32) -> Self {
+        self.mapv(|x| if x > min && x < max { 1.0 } else { 0.0 })
+    }
+    fn sigmoid(&'d self) -> Self {
+        self.mapv(|x| 1.0 / (1.0 + (-x).exp()))
+    }
+    fn ln(&'d self) -> Self {
+        self.mapv(f32::ln)
+    }
+    fn softplus(&'d self) -> Self {
+        // ln(1 + e^x) rewritten so that exp never overflows
+        self.mapv(|x| x.max(0.0) + (-x.abs()).exp().ln_1p())
+    }
+    fn cumsum(&'d self, axis: usize) -> Self {
+        let mut out = self.clone();
+        out.accumulate_axis_inplace(ndarray::Axis(axis), |&prev, cur| *cur += prev);
+        out
+    }
+    fn stack(&'d self, rest: &[&Self]) -> Self {
+        let views: Vec<_> = std::iter::once(self)
+            .chain(rest.iter().copied())
+            .map(|x| x.view())
+            .collect();
+        ndarray::stack(ndarray::Axis(0), &views).expect("Shapes differ")
+    }
+    fn masked_fill(&'d self, mask: &Array<bool, IxDyn>, value: f32) -> Self {
+        let mut out = self.clone();
+        out.zip_mut_with(mask, |x, &m| {
+            if m {
+                *x = value
+            }
+        });
+        out
+    }
+    fn compare(&'d self, other: &Self, cmp: Comparison) -> Self {
+        let mut out = self.clone();
+        out.zip_mut_with(other, |a, &b| *a = if cmp.holds(*a, b) { 1.0 } else { 0.0 });
+        out
+    }
+    fn select(&'d self, mask: &Self, other: &Self) -> Self {
+        let mut out = self.clone();
+        ndarray::Zip::from(&mut out)
+            .and(mask)
+            .and(other)
+            .for_each(|x, &m, &y| {
+                if m == 0.0 {
+                    *x = y
+                }
+            });
+        out
+    }
+    fn pad(&'d self, pads: &[(usize, usize)], value: f32) -> Self {
+        let shape: Vec<usize> = self
+            .shape()
+            .iter()
+            .zip(pads)
+            .map(|(&len, &(before, after))| before + len + after)
+            .collect();
+        let mut out = Array::from_elem(IxDyn(&shape), value);
+        {
+            let mut view = out.view_mut();
+            for (ax, (&(before, _), &len)) in pads.iter().zip(self.shape()).enumerate() {
+                view.slice_axis_inplace(ndarray::Axis(ax), (before..before + len).into());
+            }
+            view.assign(self);
+        }
+        out
+    }
+    fn conj(&'d self) -> Self {
+        self.clone()
+    }
+    fn abs2(&'d self) -> Self {
+        self * self
+    }
+    fn div_eps(&self, other: &'d Self, eps: f32) -> Self {
+        self / &(other + eps)
+    }
+    fn permute(&'d self, axes: &[usize]) -> Self {
+        self.view()
+            .permuted_axes(IxDyn(axes))
+            .as_standard_layout()
+            .into_owned()
+    }
 }
 impl<'d> TensorType<'d> for WgpuArray<'d, f32, IxDyn> {
+    const ON_DEVICE: bool = true;
     fn get_value_cpu(&self) -> Array<f32, IxDyn> {
+        DEVICE_COPIES.fetch_add(1, Ordering::Relaxed);
         self.clone().into_cpu()
     }
     fn tensor(&self) -> &Self {
         self
     }
+    fn upload_like(&'d self, data: Array<f32, IxDyn>) -> Self {
+        data.into_wgpu(self.get_wgpu_device())
+    }
     fn val_like(&'d self, val: f32) -> Self {
         let d = self.get_wgpu_device();
         let shape = self.shape();
@@ -127,6 +559,139 @@ impl<'d> TensorType<'d> for WgpuArray<'d, f32, IxDyn> {
     fn expm(&self) -> Self {
         self.clone().exp()
     }
+    fn floor(&'d self) -> Self {
+        via_cpu(self, |x| x.floor())
+    }
+    fn ceil(&'d self) -> Self {
+        via_cpu(self, |x| x.ceil())
+    }
+    fn round(&'d self) -> Self {
+        via_cpu(self, |x| x.round())
+    }
+    fn ge(&'d self, other: &Self) -> Self {
+        let other = other.get_value_cpu();
+        via_cpu(self, |x| TensorType::ge(x, &other))
+    }
+    fn shape(&self) -> Vec<usize> {
+        self.shape().to_vec()
+    }
+    fn sum(&'d self) -> Self {
+        via_cpu(self, |x| TensorType::sum(x))
+    }
+    fn reshape(&'d self, shape: &[usize]) -> Self {
+        via_cpu(self, |x| TensorType::reshape(x, shape))
+    }
+    fn sum_axes(&'d self, axes: &[usize]) -> Self {
+        via_cpu(self, |x| TensorType::sum_axes(x, axes))
+    }
+    fn tile(&'d self, reps: &[usize]) -> Self {
+        via_cpu(self, |x| TensorType::tile(x, reps))
+    }
+    fn broadcast_to(&'d self, shape: &[usize]) -> Self {
+        via_cpu(self, |x| TensorType::broadcast_to(x, shape))
+    }
+    fn slice_ranges(&'d self, ranges: &[(usize, usize)]) -> Self {
+        via_cpu(self, |x| x.slice_ranges(ranges))
+    }
+    fn scatter_ranges(&'d self, shape: &[usize], ranges: &[(usize, usize)]) -> Self {
+        via_cpu(self, |x| x.scatter_ranges(shape, ranges))
+    }
+    fn gather(&'d self, axis: usize, indices: &[usize]) -> Self {
+        via_cpu(self, |x| TensorType::gather(x, axis, indices))
+    }
+    fn scatter_add(&'d self, shape: &[usize], axis: usize, indices: &[usize]) -> Self {
+        via_cpu(self, |x| x.scatter_add(shape, axis, indices))
+    }
+    fn sqrt(&'d self) -> Self {
+        via_cpu(self, |x| TensorType::sqrt(x))
+    }
+    fn mul_scalar(&'d self, val: f32) -> Self {
+        self * &self.val_like(val)
+    }
+    fn add_scalar(&'d self, val: f32) -> Self {
+        self + &self.val_like(val)
+    }
+    fn log_softmax(&'d self, axis: usize) -> Self {
+        via_cpu(self, |x| x.log_softmax(axis))
+    }
+    fn softmax(&'d self, axis: usize) -> Self {
+        via_cpu(self, |x| x.softmax(axis))
+    }
+    fn one_hot(&'d self, targets: &[usize]) -> Self {
+        via_cpu(self, |x| x.one_hot(targets))
+    }
+    fn clamp(&'d self, min: f32, max: f32) -> Self {
+        via_cpu(self, |x| x.clamp(min, max))
+    }
+    fn in_range(&'d self, min: f32, max: f32) -> Self {
+        via_cpu(self, |x| x.in_range(min, max))
+    }
+    fn sigmoid(&'d self) -> Self {
+        via_cpu(self, |x| x.sigmoid())
+    }
+    fn ln(&'d self) -> Self {
+        via_cpu(self, |x| x.ln())
+    }
+    fn softplus(&'d self) -> Self {
+        via_cpu(self, |x| x.softplus())
+    }
+    fn cumsum(&'d self, axis: usize) -> Self {
+        via_cpu(self, |x| x.cumsum(axis))
+    }
+    fn stack(&'d self, rest: &[&Self]) -> Self {
+        let rest: Vec<_> = rest.iter().map(|x| x.get_value_cpu()).collect();
+        let rest: Vec<_> = rest.iter().collect();
+        via_cpu(self, |x| x.stack(&rest))
+    }
+    fn masked_fill(&'d self, mask: &Array<bool, IxDyn>, value: f32) -> Self {
+        via_cpu(self, |x| x.masked_fill(mask, value))
+    }
+    fn compare(&'d self, other: &Self, cmp: Comparison) -> Self {
+        let other = other.get_value_cpu();
+        via_cpu(self, |x| x.compare(&other, cmp))
+    }
+    fn select(&'d self, mask: &Self, other: &Self) -> Self {
+        let (mask, other) = (mask.get_value_cpu(), other.get_value_cpu());
+        via_cpu(self, |x| TensorType::select(x, &mask, &other))
+    }
+    fn pad(&'d self, pads: &[(usize, usize)], value: f32) -> Self {
+        via_cpu(self, |x| x.pad(pads, value))
+    }
+    fn conj(&'d self) -> Self {
+        self.clone()
+    }
+    fn abs2(&'d self) -> Self {
+        self * self
+    }
+    fn div_eps(&self, other: &'d Self, eps: f32) -> Self {
+        self.div(&other.add_scalar(eps))
+    }
+    fn permute(&'d self, axes: &[usize]) -> Self {
+        via_cpu(self, |x| x.permute(axes))
+    }
+}
+
+///
+/// Run a CPU-only computation on a GPU array and upload the result to the same device
+///
+/// TODO: replace with native shaders as the wgpu backend grows
+///
+fn via_cpu<'d>(
+    x: &'d WgpuArray<'d, f32, IxDyn>,
+    f: impl FnOnce(&Array<f32, IxDyn>) -> Array<f32, IxDyn>,
+) -> WgpuArray<'d, f32, IxDyn> {
+    let d = x.get_wgpu_device();
+    f(&x.get_value_cpu()).into_wgpu(d)
+}
+
+static DEVICE_COPIES: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// How many device-to-host copies have been made so far, to check that inspection loops
+/// are not round-tripping the GPU
+///
+pub fn device_copies() -> usize {
+    DEVICE_COPIES.load(Ordering::Relaxed)
 }
 
 ///
@@ -178,6 +743,9 @@ impl<'d, T: TensorType<'d>> Clone for Raw<'d, T> {
 /// let t = g.tensor(...);
 /// ```
 ///
+/// A Tensor is a cheap `Copy` handle, so using the same one in several ops shares the node:
+/// its gradient is the sum of the contributions from every use, as needed for tied weights
+///
 pub struct Tensor<'d, 'g, T: 'd + TensorType<'d> + Clone> {
     pub graph: &'g Graph<'d, T>,
     pub index: usize,
@@ -192,15 +760,55 @@ impl<'d, T: TensorType<'d> + Clone> Clone for Tensor<'d, '_, T> {
 }
 
 impl<'d, 'g, T: 'd + TensorType<'d> + Clone> Tensor<'d, 'g, T> {
+    ///
+    /// Another handle to the same node, not a copy of its value. Equivalent to copying the
+    /// Tensor, but makes weight tying explicit at the use site
+    ///
+    pub fn share(&self) -> Tensor<'d, 'g, T> {
+        *self
+    }
+
     ///
     /// Returns a CPU copy of the data represented by the Tensor
     ///
+    /// For device arrays the copy off the device is made once and reused until the node is
+    /// recomputed
+    ///
     pub fn value(&self) -> ndarray::ArrayD<f32> {
+        self.cpu_value().expect("Was forward called?")
+    }
+
+    ///
+    /// CPU copy of the value, or None if there is none yet. Shared by `value` and
+    /// `try_value` so that both go through the device cache
+    ///
+    fn cpu_value(&self) -> Option<ndarray::ArrayD<f32>> {
         let nodes = self.graph.nodes.borrow();
-        let node = nodes[self.index].borrow();
-        let val = node.value.as_ref().expect("Was forward called?");
+        let mut node = nodes[self.index].borrow_mut();
+        if let Some(cached) = &node.cpu_value {
+            return Some(cached.clone());
+        }
+        let cpu = node.value.as_ref()?.value().get_value_cpu();
+        if T::ON_DEVICE {
+            node.cpu_value = Some(cpu.clone());
+        }
+        Some(cpu)
+    }
 
-        val.value().get_value_cpu()
+    ///
+    /// The value of a single-element tensor, e.g. a loss, as a plain `f32`. Panics if the
+    /// tensor holds more than one element
+    ///
+    pub fn item(&self) -> f32 {
+        let value = self.value();
+        assert_eq!(
+            value.len(),
+            1,
+            "item() needs a single-element tensor, but v{} has shape {:?}",
+            self.index,
+            value.shape()
+        );
+        value.iter().next().copied().unwrap()
     }
 
     ///
@@ -214,189 +822,1240 @@ impl<'d, 'g, T: 'd + TensorType<'d> + Clone> Tensor<'d, 'g, T> {
     }
 
     ///
-    /// Do a forward pass stopping at the current node
+    /// Whether the node holds a value, i.e. it is a leaf or forward has reached it, so that
+    /// `value` will not panic
     ///
-    /// TODO: this should ideally only flow through nodes that matter
+    pub fn has_value(&self) -> bool {
+        self.graph.nodes.borrow()[self.index]
+            .borrow()
+            .value
+            .is_some()
+    }
+
     ///
-    pub fn forward(&self) {
-        let nodes = self.graph.nodes.borrow_mut();
+    /// Whether backward has left a gradient on the node, so that `grad` will not panic
+    ///
+    pub fn has_grad(&self) -> bool {
+        self.graph.nodes.borrow()[self.index]
+            .borrow()
+            .grad
+            .is_some()
+    }
 
-        for i in 0..self.index + 1 {
-            let mut node = nodes[i].borrow_mut();
-            let d_0 = node.deps[0];
-            let d_1 = node.deps[1];
-            match &mut node.func {
-                Function::None => (),
-                Function::One(f) => {
-                    let n_l: Raw<T> = nodes[d_0].borrow().value.unwrap();
-                    node.value = Some(f.forward(n_l));
-                }
-                Function::Two(f) => {
-                    let n_l: Raw<T> = nodes[d_0].borrow().value.unwrap();
-                    let n_r: Raw<T> = nodes[d_1].borrow().value.unwrap();
-                    node.value = Some(f.forward(n_l, n_r));
-                }
-            }
-        }
+    ///
+    /// Shape of the value, read without copying the data to the CPU
+    ///
+    pub fn shape(&self) -> Vec<usize> {
+        let nodes = self.graph.nodes.borrow();
+        let node = nodes[self.index].borrow();
+        let val = node.value.as_ref().expect("Was forward called?");
+        val.value().shape()
     }
 
     ///
-    /// A backward pass to compute the gradients.
+    /// Number of axes of the value
     ///
-    /// An initial gradient is required, and in typical applications is usually
-    /// all ones
+    pub fn ndim(&self) -> usize {
+        self.shape().len()
+    }
+
     ///
-    pub fn backward(&self, init: T) {
-        let len = self.graph.len();
-        let nodes = self.graph.nodes.borrow();
+    /// Like `value`, but returns an error instead of panicking if forward has not run
+    ///
+    pub fn try_value(&self) -> Result<ndarray::ArrayD<f32>, GraphError> {
+        self.cpu_value()
+            .ok_or(GraphError::NotComputed { index: self.index })
+    }
 
-        {
-            let mut node = nodes[self.index].borrow_mut();
-            node.grad = Some(Raw::new(init));
+    ///
+    /// Like `grad`, but returns an error instead of panicking if backward has not run
+    ///
+    pub fn try_grad(&self) -> Result<ndarray::ArrayD<f32>, GraphError> {
+        let nodes = self.graph.nodes.borrow();
+        let node = nodes[self.index].borrow();
+        match &node.grad {
+            Some(val) => Ok(val.value().get_value_cpu()),
+            None => Err(GraphError::NoGradient {
+                index: self.index,
+                forward_ran: node.value.is_some(),
+            }),
         }
+    }
 
-        for i in (0..len).rev() {
-            {
-                let mut node = nodes[i].borrow_mut();
+    ///
+    /// Replace the value of a leaf, giving it a new version so that the next forward
+    /// recomputes exactly the subgraph downstream of it
+    ///
+    pub fn set_value(&self, new: T) {
+        let nodes = self.graph.nodes.borrow();
+        let mut node = nodes[self.index].borrow_mut();
+        assert!(
+            matches!(node.func, Function::None),
+            "set_value is only allowed on leaves"
+        );
+        node.value = Some(Raw::new(new));
+        node.cpu_value = None;
 
-                match &node.func {
-                    Function::None => (),
-                    Function::One(f) => node.ctx = f.backward(node.grad.unwrap()),
-                    Function::Two(f) => node.ctx = f.backward(node.grad.unwrap()),
-                }
-            }
+        let epoch = self.graph.epoch.get() + 1;
+        self.graph.epoch.set(epoch);
+        node.version = epoch;
+    }
 
-            let node = nodes[i].borrow();
+    ///
+    /// Set whether this node should receive a gradient. Frozen nodes, and ops whose inputs
+    /// are all frozen, are skipped entirely during backward.
+    ///
+    pub fn requires_grad(self, requires_grad: bool) -> Self {
+        let nodes = self.graph.nodes.borrow();
+        nodes[self.index].borrow_mut().requires_grad = requires_grad;
+        self
+    }
 
-            for j in 0..2 {
-                if std::ptr::eq(&*node, nodes[node.deps[j]].as_ptr()) {
-                    continue;
-                }
-                let mut node_d = nodes[node.deps[j]].borrow_mut();
+    ///
+    /// Keep this node's gradient through `Graph::free_intermediate_grads`, e.g. to inspect
+    /// the gradient with respect to an activation
+    ///
+    pub fn retain_grad(self) -> Self {
+        let nodes = self.graph.nodes.borrow();
+        nodes[self.index].borrow_mut().retain_grad = true;
+        self
+    }
 
-                if let Some(grad) = &node_d.grad {
+    ///
+    /// Set the gradient to zeros of the value's shape, replacing any gradient held. Leaf
+    /// gradients then always exist, and each `backward` adds into them, e.g. when summing
+    /// micro-batches before an optimizer step
+    ///
+    pub fn init_grad_zeros(&self) {
+        let nodes = self.graph.nodes.borrow();
+        let mut node = nodes[self.index].borrow_mut();
+        let value = node
+            .value
+            .expect("init_grad_zeros needs a value, was forward called?");
+        node.grad = Some(Raw::new(value.value().val_like(0.0)));
+    }
+
+    ///
+    /// A new leaf holding a copy of this node's value, with no link back to the ops that
+    /// produced it. Cutting a long graph at such a boundary, e.g. carrying a recurrent state
+    /// between epochs, keeps backward from reaching further
+    ///
+    pub fn to_leaf(&self) -> Tensor<'d, 'g, T> {
+        let value = {
+            let nodes = self.graph.nodes.borrow();
+            let node = nodes[self.index].borrow();
+            let value = node
+                .value
+                .expect("to_leaf needs a value, was forward called?");
+            value.value().clone()
+        };
+        self.graph.tensor(value)
+    }
+
+    ///
+    /// Do a forward pass stopping at the current node
+    ///
+    /// Only nodes that are new, impure or downstream of a `set_value` are recomputed
+    ///
+    /// TODO: this should ideally only flow through nodes that matter
+    ///
+    pub fn forward(&self) {
+        self.forward_values();
+        self.graph.discard_recomputable(self.index);
+    }
+
+    ///
+    /// The forward pass, keeping the values of checkpointed nodes
+    ///
+    fn forward_values(&self) {
+        self.run_nodes(|node, nodes| {
+            let fresh = node.version != 0 && !node.is_stale(nodes);
+            !(fresh && node.func.is_pure() && node.value.is_some())
+        });
+    }
+
+    ///
+    /// Bring back the values dropped by checkpoints, and the contexts of ops marked for
+    /// recomputation, e.g. in a deep_clone. Nothing else is run again, so impure ops before
+    /// the checkpoints do not see a second pass
+    ///
+    fn restore_values(&self) {
+        self.run_nodes(|node, _| (node.recompute && node.value.is_none()) || node.version == 0);
+    }
+
+    ///
+    /// Run the op nodes up to this one for which `rerun` holds, in order
+    ///
+    fn run_nodes(&self, rerun: impl Fn(&Node<'d, T>, &[RefCell<Node<'d, T>>]) -> bool) {
+        let nodes = self.graph.nodes.borrow_mut();
+
+        for i in 0..self.index + 1 {
+            let mut node = nodes[i].borrow_mut();
+            if !rerun(&node, &nodes) {
+                continue;
+            }
+            node.version = self.graph.epoch.get();
+            node.cpu_value = None;
+
+            let d_0 = node.deps[0];
+            let d_1 = node.deps[1];
+            let (op, start) = (node.func.name(), self.graph.profile_start());
+            match &mut node.func {
+                Function::None => continue,
+                Function::One(f) => {
+                    let n_l: Raw<T> = nodes[d_0].borrow().value.unwrap();
+                    let value = f.forward(n_l);
+                    node.store(value);
+                }
+                Function::Two(f) => {
+                    let n_l: Raw<T> = nodes[d_0].borrow().value.unwrap();
+                    let n_r: Raw<T> = nodes[d_1].borrow().value.unwrap();
+                    let value = f.forward(n_l, n_r);
+                    node.store(value);
+                }
+            }
+            self.graph.profile_record(op, start, false);
+            if let Some(value) = &node.value {
+                self.graph.run_forward_hooks(i, value.value());
+            }
+        }
+    }
+
+    ///
+    /// A backward pass to compute the gradients.
+    ///
+    /// An initial gradient is required, and in typical applications is usually
+    /// all ones
+    ///
+    /// Leaf gradients accumulate across repeated calls, see `backward_with`
+    ///
+    pub fn backward(&self, init: T) {
+        self.backward_with(init, true)
+    }
+
+    ///
+    /// A backward pass seeded with `init * scale`, for loss scaling in mixed precision or
+    /// large-batch training. Undo it with `Graph::unscale_grads` before stepping
+    ///
+    pub fn backward_scaled(&self, mut init: T, scale: f32) {
+        init.scale(scale);
+        self.backward(init);
+    }
+
+    ///
+    /// A backward pass which either accumulates into the existing leaf gradients
+    /// (`accumulate = true`) or discards them first so that the pass is independent
+    ///
+    /// Gradients of intermediate nodes are always recomputed from scratch
+    ///
+    pub fn backward_with(&self, init: T, accumulate: bool) {
+        let needs_grad = self.graph.needs_grad();
+        self.backward_masked(vec![(self.index, init)], accumulate, &needs_grad);
+    }
+
+    ///
+    /// A backward pass that only computes what is needed for the gradients of the `targets`
+    /// node indices; branches that cannot reach any target are skipped entirely
+    ///
+    pub fn backward_to(&self, init: T, targets: &[usize]) {
+        let needs_grad = self.graph.needs_grad();
+        let nodes = self.graph.nodes.borrow();
+
+        let mut active = Vec::with_capacity(nodes.len());
+        for (i, node) in nodes.iter().enumerate() {
+            let deps = node.borrow().deps;
+            let reaches = targets.contains(&i) || deps.iter().any(|&d| d != i && active[d]);
+            active.push(reaches && needs_grad[i]);
+        }
+        drop(nodes);
+
+        self.backward_masked(vec![(self.index, init)], true, &active);
+    }
+
+    ///
+    /// Run `backward` and return a CPU copy of every leaf gradient keyed by node index, e.g.
+    /// to hand to an optimizer in one go
+    ///
+    pub fn backward_collect(&self, init: T) -> HashMap<usize, ndarray::ArrayD<f32>> {
+        self.backward(init);
+        self.graph
+            .nodes
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| {
+                let node = node.borrow();
+                match (&node.func, node.grad) {
+                    (Function::None, Some(grad)) => Some((index, grad.value().get_value_cpu())),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    ///
+    /// The backward pass over the nodes flagged in `active`, from this node down, seeding
+    /// each `(index, init)` root. This node must be the last of the roots
+    ///
+    pub(crate) fn backward_masked(
+        &self,
+        seeds: Vec<(usize, T)>,
+        accumulate: bool,
+        active: &[bool],
+    ) {
+        let recompute = self.graph.nodes.borrow()[..=self.index].iter().any(|node| {
+            let node = node.borrow();
+            (node.recompute && node.value.is_none()) || node.version == 0
+        });
+        if recompute {
+            self.restore_values();
+        }
+        self.backward_nodes(seeds, accumulate, active);
+        if recompute {
+            self.graph.discard_recomputable(self.index);
+        }
+    }
+
+    fn backward_nodes(&self, seeds: Vec<(usize, T)>, accumulate: bool, active: &[bool]) {
+        let nodes = self.graph.nodes.borrow();
+        let roots: Vec<usize> = seeds.iter().map(|&(index, _)| index).collect();
+
+        for (i, node) in nodes.iter().enumerate() {
+            let mut node = node.borrow_mut();
+            let leaf = matches!(node.func, Function::None);
+            if !leaf || !accumulate {
+                if let Some(grad) = node.grad.take() {
+                    self.graph.recycle(i, grad);
+                }
+            }
+        }
+
+        for (index, init) in seeds {
+            let mut node = nodes[index].borrow_mut();
+            if let Some(val) = node.value {
+                let (seed, out) = (init.shape(), val.value().shape());
+                assert_eq!(
+                    seed, out,
+                    "backward seed has shape {:?} but the output has shape {:?}",
+                    seed, out
+                );
+            }
+            // A root listed twice gets the sum of its seeds
+            match node.grad {
+                Some(grad) => unsafe { (*grad.data).add_assign(&init) },
+                None => node.grad = Some(Raw::new(init)),
+            }
+        }
+
+        // Nodes after this one cannot contribute to its gradient
+        for i in (0..self.index + 1).rev() {
+            if !active[i] {
+                continue;
+            }
+            {
+                let mut node = nodes[i].borrow_mut();
+                // Nodes that do not feed into this one never receive a gradient
+                let grad = match node.grad {
+                    Some(grad) => grad,
+                    None => continue,
+                };
+                self.graph.run_backward_hooks(i, grad);
+
+                let start = self.graph.profile_start();
+                match &node.func {
+                    Function::None => (),
+                    Function::One(f) => node.ctx = f.backward(grad),
+                    Function::Two(f) => node.ctx = f.backward(grad),
+                }
+                if !matches!(node.func, Function::None) {
+                    self.graph.profile_record(node.func.name(), start, true);
+                }
+            }
+
+            let node = nodes[i].borrow();
+
+            for j in 0..2 {
+                if std::ptr::eq(&*node, nodes[node.deps[j]].as_ptr()) || !active[node.deps[j]] {
+                    continue;
+                }
+                let mut node_d = nodes[node.deps[j]].borrow_mut();
+
+                if let Some(grad) = &node_d.grad {
                     if let Some(w) = &node.ctx[j] {
                         unsafe {
-                            *grad.data = grad.value().add(w.value());
+                            (*grad.data).add_assign(w.value());
                         }
                     }
                 } else if let Some(w) = &node.ctx[j] {
-                    node_d.grad = Some(Raw::new(w.value().clone()));
+                    node_d.grad = Some(self.graph.pooled_copy(node.deps[j], w.value()));
+                }
+            }
+        }
+
+        if self.graph.grad_zeros.get() {
+            let mut reached = vec![false; self.index + 1];
+            for root in roots {
+                reached[root] = true;
+            }
+            for i in (0..self.index + 1).rev() {
+                if !reached[i] || !active[i] {
+                    continue;
+                }
+                let mut node = nodes[i].borrow_mut();
+                for &d in node.deps.iter().filter(|&&d| d != i) {
+                    reached[d] = true;
+                }
+                if let (None, Some(value)) = (node.grad, node.value) {
+                    node.grad = Some(Raw::new(value.value().val_like(0.0)));
                 }
             }
         }
     }
 
+    ///
+    /// End a gradient checkpoint segment at this node. The ops between the previous
+    /// checkpoint (or the leaves) and this node drop their values after forward and are
+    /// recomputed during backward, trading compute for memory in deep graphs. Impure ops,
+    /// e.g. batch norm, keep their values, since running them again would change their state
+    ///
+    pub fn checkpoint(self) -> Self {
+        let nodes = self.graph.nodes.borrow();
+        let mut checkpoints = self.graph.checkpoints.borrow_mut();
+        checkpoints.push(self.index);
+
+        let mut stack = nodes[self.index].borrow().deps.to_vec();
+        while let Some(i) = stack.pop() {
+            let mut node = nodes[i].borrow_mut();
+            let boundary = matches!(node.func, Function::None) || checkpoints.contains(&i);
+            if boundary || node.recompute {
+                continue;
+            }
+            // Running an impure op again would change its state, so it keeps its value
+            node.recompute = node.func.is_pure();
+            stack.extend_from_slice(&node.deps);
+        }
+        self
+    }
+
     pub fn matmul(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.same_graph(&other);
+
+        use crate::functions::MatMul;
+        let func = Function::Two(
+            MatMul {
+                x_ctx: None,
+                y_ctx: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, other.index], func)
+    }
+
+    ///
+    /// Contract `axes.0` of this tensor with `axes.1` of `other`, pairwise, as numpy's
+    /// `tensordot`. The free axes of this tensor come first in the result, then those of
+    /// `other`; `tensordot(b, (vec![1], vec![0]))` is a matrix product
+    ///
+    pub fn tensordot(
+        self,
+        other: Tensor<'d, 'g, T>,
+        axes: (Vec<usize>, Vec<usize>),
+    ) -> Tensor<'d, 'g, T> {
+        self.same_graph(&other);
         assert_eq!(
-            self.graph as *const Graph<T>,
-            other.graph as *const Graph<T>
+            axes.0.len(),
+            axes.1.len(),
+            "tensordot contracts axes pairwise, got {:?} and {:?}",
+            axes.0,
+            axes.1
         );
-        let mut nodes = self.graph.nodes.borrow_mut();
 
-        let len = nodes.len();
+        use crate::functions::TensorDot;
+        let func = Function::Two(
+            TensorDot {
+                axes,
+                perms: Default::default(),
+                shapes: Default::default(),
+                mats: [None, None],
+            }
+            .into(),
+        );
+        self.graph.push([self.index, other.index], func)
+    }
 
-        use crate::functions::MatMul;
-        nodes.push(RefCell::new(Node {
-            deps: [self.index, other.index],
-            func: Function::Two(
-                MatMul {
-                    x_ctx: None,
-                    y_ctx: None,
-                }
-                .into(),
-            ),
-            value: None,
-            grad: None,
-            ctx: [None, None],
-        }));
-        Tensor {
-            graph: self.graph,
-            index: len,
-        }
+    ///
+    /// `bias + a @ b`. Nodes hold at most two dependencies, so this records the product
+    /// followed by an add; gradients flow to all three operands.
+    ///
+    pub fn addmm(
+        bias: Tensor<'d, 'g, T>,
+        a: Tensor<'d, 'g, T>,
+        b: Tensor<'d, 'g, T>,
+    ) -> Tensor<'d, 'g, T> {
+        a.matmul(b) + bias
     }
 
     ///
-    /// Take a matrix exponential
+    /// Take a matrix exponential. A 3-D input `[B, N, N]` is a batch, exponentiated matrix by
+    /// matrix
     ///
     /// Note: The matrix must be diagonal.
     ///
     /// TODO: Repeated squaring + Pade approximation for general case
     ///
     pub fn expm(self) -> Tensor<'d, 'g, T> {
-        let mut nodes = self.graph.nodes.borrow_mut();
-
-        let len = nodes.len();
-        use crate::functions::ExpM;
-        nodes.push(RefCell::new(Node {
-            deps: [self.index, self.index],
-            func: Function::One(ExpM { a: None, res: None }.into()),
-            value: None,
-            grad: None,
-            ctx: [None, None],
-        }));
-        Tensor {
-            graph: self.graph,
-            index: len,
+        use crate::functions::{assert_square, ExpM};
+
+        // Leaves already hold a value, so bad shapes fail here rather than at forward
+        if let Some(val) = self.graph.nodes.borrow()[self.index].borrow().value {
+            assert_square(&val.value().shape());
         }
+
+        let func = Function::One(ExpM { a: None, res: None }.into());
+        self.graph.push([self.index, self.index], func)
     }
-}
 
-impl<'d, 'g, T: TensorType<'d> + Clone> ::std::ops::Add for Tensor<'d, 'g, T> {
-    type Output = Tensor<'d, 'g, T>;
-    fn add(self, other: Tensor<'d, 'g, T>) -> Self::Output {
+    ///
+    /// Element-wise floor. The gradient is zero everywhere.
+    ///
+    pub fn floor(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::Floor;
+        self.graph
+            .push([self.index, self.index], Function::One(Floor.into()))
+    }
+
+    ///
+    /// Element-wise ceil. The gradient is zero everywhere.
+    ///
+    pub fn ceil(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::Ceil;
+        self.graph
+            .push([self.index, self.index], Function::One(Ceil.into()))
+    }
+
+    ///
+    /// Element-wise round (half away from zero). The gradient is zero everywhere.
+    ///
+    pub fn round(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::Round;
+        self.graph
+            .push([self.index, self.index], Function::One(Round.into()))
+    }
+
+    ///
+    /// Apply a rounding op in the forward pass while treating it as the identity in the
+    /// backward pass
+    ///
+    pub fn straight_through(self, op: Rounding) -> Tensor<'d, 'g, T> {
+        use crate::functions::StraightThrough;
+        let func = Function::One(StraightThrough { op }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Element-wise maximum of two tensors
+    ///
+    /// The gradient flows to whichever operand is larger (the left one on ties)
+    ///
+    pub fn maximum(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.same_graph(&other);
+
+        use crate::functions::Maximum;
+        let func = Function::Two(Maximum { mask: None }.into());
+        self.graph.push([self.index, other.index], func)
+    }
+
+    ///
+    /// Element-wise minimum of two tensors
+    ///
+    /// The gradient flows to whichever operand is smaller (the left one on ties)
+    ///
+    pub fn minimum(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.same_graph(&other);
+
+        use crate::functions::Minimum;
+        let func = Function::Two(Minimum { mask: None }.into());
+        self.graph.push([self.index, other.index], func)
+    }
+
+    ///
+    /// Evaluate a small subset of einsum specs:
+    /// - `"ij,jk->ik"` matrix product
+    /// - `"ij->ji"` transpose
+    /// - `"ij,ij->"` full contraction (dot product) of equally shaped tensors
+    /// - `"i,j->ij"` outer product of two vectors
+    ///
+    /// Index letters may be renamed, e.g. `"ab,bc->ac"` is also a matrix product. Single
+    /// operand specs take `None` for `b`, e.g. `Tensor::einsum("ij->ji", x, None)`
+    ///
+    pub fn einsum(
+        spec: &str,
+        a: Tensor<'d, 'g, T>,
+        b: impl Into<Option<Tensor<'d, 'g, T>>>,
+    ) -> Tensor<'d, 'g, T> {
+        use crate::functions::{Dot, EinsumSpec, MatMul, Outer};
+
+        let spec = EinsumSpec::parse(spec);
+        let b = b.into();
+        assert_eq!(
+            1 + b.is_some() as usize,
+            spec.arity(),
+            "einsum {:?} expects {} operand(s)",
+            spec,
+            spec.arity()
+        );
+
+        let b = match b {
+            Some(b) => b,
+            None => return a.t(),
+        };
+        a.same_graph(&b);
+
+        let func = match spec {
+            EinsumSpec::MatMul => MatMul {
+                x_ctx: None,
+                y_ctx: None,
+            }
+            .into(),
+            EinsumSpec::Dot => Dot {
+                x_ctx: None,
+                y_ctx: None,
+            }
+            .into(),
+            EinsumSpec::Outer => Outer {
+                x_ctx: None,
+                y_ctx: None,
+            }
+            .into(),
+            EinsumSpec::Transpose => unreachable!(),
+        };
+        a.graph.push([a.index, b.index], Function::Two(func))
+    }
+
+    ///
+    /// Repeat the tensor `reps[i]` times along each axis `i`
+    ///
+    pub fn tile(self, reps: Vec<usize>) -> Tensor<'d, 'g, T> {
+        use crate::functions::Tile;
+        let func = Function::One(Tile { reps }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Expand the tensor to `shape` following `ndarray` broadcasting rules
+    ///
+    pub fn broadcast_to(self, shape: Vec<usize>) -> Tensor<'d, 'g, T> {
+        use crate::functions::BroadcastTo;
+        let func = Function::One(
+            BroadcastTo {
+                shape,
+                in_shape: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Extract the sub-region `start..end` along each axis. Axes without a range are kept whole,
+    /// and an end of `usize::MAX` runs to the end of the axis
+    ///
+    pub fn slice(self, ranges: Vec<(usize, usize)>) -> Tensor<'d, 'g, T> {
+        use crate::functions::Slice;
+        let func = Function::One(
+            Slice {
+                ranges,
+                in_shape: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Select entries along `axis` by index. Indices may repeat, in which case the
+    /// gradients of the copies are accumulated
+    ///
+    pub fn gather(self, axis: usize, indices: Vec<usize>) -> Tensor<'d, 'g, T> {
+        use crate::functions::Gather;
+        let func = Function::One(
+            Gather {
+                axis,
+                indices,
+                in_shape: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Mean cross-entropy between the rows of a [N, C] matrix of logits and integer class
+    /// targets, fused with the softmax for numerical stability
+    ///
+    pub fn softmax_cross_entropy(self, targets: Vec<usize>) -> Tensor<'d, 'g, T> {
+        use crate::functions::SoftmaxCrossEntropy;
+        let func = Function::One(
+            SoftmaxCrossEntropy {
+                targets,
+                softmax: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Piecewise-linear sigmoid: `clamp(x / 6 + 1 / 2, 0, 1)`
+    ///
+    pub fn hard_sigmoid(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::HardSigmoid;
+        let func = Function::One(HardSigmoid { mask: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Piecewise-linear tanh: `clamp(x, -1, 1)`
+    ///
+    pub fn hard_tanh(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::HardTanh;
+        let func = Function::One(HardTanh { mask: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Exponential linear unit: `x` for positive inputs, `alpha * (exp(x) - 1)` otherwise
+    ///
+    pub fn elu(self, alpha: f32) -> Tensor<'d, 'g, T> {
+        use crate::functions::Elu;
+        let func = Function::One(Elu { alpha, deriv: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Sigmoid linear unit (swish): `x * sigmoid(x)`
+    ///
+    pub fn silu(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::Silu;
+        let func = Function::One(
+            Silu {
+                x: None,
+                sigmoid: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Mean negative log-likelihood of a [N, C] matrix of log-probabilities at the integer
+    /// class targets, `-mean(log_prob[i, targets[i]])`
+    ///
+    pub fn nll_loss(self, targets: Vec<usize>) -> Tensor<'d, 'g, T> {
+        use crate::functions::NllLoss;
+        let func = Function::One(
+            NllLoss {
+                targets,
+                x_ctx: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Numerically stable `x - logsumexp(x)` along `axis`
+    ///
+    pub fn log_softmax(self, axis: usize) -> Tensor<'d, 'g, T> {
+        self.log_softmax_t(axis, 1.0)
+    }
+
+    ///
+    /// `log_softmax(x / temperature)` along `axis`
+    ///
+    pub fn log_softmax_t(self, axis: usize, temperature: f32) -> Tensor<'d, 'g, T> {
+        use crate::functions::LogSoftmax;
+        let func = Function::One(
+            LogSoftmax {
+                axis,
+                temperature,
+                softmax: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Split along `axis` into the parts before and after `index`. Both halves are slices of
+    /// `self`, so their gradients accumulate back onto it
+    ///
+    pub fn split(self, axis: usize, index: usize) -> (Tensor<'d, 'g, T>, Tensor<'d, 'g, T>) {
+        let mut head = vec![(0, usize::MAX); axis + 1];
+        let mut tail = head.clone();
+        head[axis] = (0, index);
+        tail[axis] = (index, usize::MAX);
+        (self.slice(head), self.slice(tail))
+    }
+
+    ///
+    /// Replace the entries where `mask` is true with `value`, e.g. `-inf` to mask attention
+    /// scores
+    ///
+    pub fn masked_fill(self, mask: ndarray::ArrayD<bool>, value: f32) -> Tensor<'d, 'g, T> {
+        use crate::functions::MaskedFill;
+        let func = Function::One(MaskedFill { mask, value }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Pad each axis by `(before, after)` entries of `value`, e.g. zero padding for a
+    /// convolution
+    ///
+    pub fn pad(self, pads: Vec<(usize, usize)>, value: f32) -> Tensor<'d, 'g, T> {
+        use crate::functions::Pad;
+        let func = Function::One(
+            Pad {
+                pads,
+                value,
+                in_shape: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Squared magnitude `|z|^2`, i.e. `x^2` for real tensors
+    ///
+    pub fn abs2(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::Abs2;
+        let func = Function::One(Abs2 { a: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Transpose, reversing the axes, e.g. `[m, n] -> [n, m]` for a matrix
+    ///
+    pub fn t(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::Transpose;
+        self.graph
+            .push([self.index, self.index], Function::One(Transpose.into()))
+    }
+
+    ///
+    /// Conjugate transpose `A^H` of a matrix, the same as a transpose for real tensors
+    ///
+    pub fn conj_t(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::ConjTranspose;
+        let func = Function::One(ConjTranspose.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Running sum along `axis`, e.g. `[1, 2, 3] -> [1, 3, 6]`
+    ///
+    pub fn cumsum(self, axis: usize) -> Tensor<'d, 'g, T> {
+        use crate::functions::CumSum;
+        let func = Function::One(CumSum { axis }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Stack `self` and `other`, which must have the same shape, along a new leading axis
+    ///
+    pub fn stack(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.same_graph(&other);
+
+        use crate::functions::Stack;
+        let func = Function::Two(Stack { in_shape: None }.into());
+        self.graph.push([self.index, other.index], func)
+    }
+
+    ///
+    /// Element-wise reciprocal square root `1 / sqrt(x)`
+    ///
+    pub fn rsqrt(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::Rsqrt;
+        let func = Function::One(Rsqrt { res: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Numerically stable softmax along `axis`
+    ///
+    pub fn softmax(self, axis: usize) -> Tensor<'d, 'g, T> {
+        self.softmax_t(axis, 1.0)
+    }
+
+    ///
+    /// `softmax(x / temperature)` along `axis`. Temperatures above one flatten the
+    /// distribution, e.g. for distillation or sampling
+    ///
+    pub fn softmax_t(self, axis: usize, temperature: f32) -> Tensor<'d, 'g, T> {
+        use crate::functions::Softmax;
+        let func = Function::One(
+            Softmax {
+                axis,
+                temperature,
+                res: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Multiply every element by the constant `factor`
+    ///
+    pub fn scale(self, factor: f32) -> Tensor<'d, 'g, T> {
+        use crate::functions::Scale;
+        let func = Function::One(Scale { factor }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Pass the value through unchanged but scale the gradient by `-lambda`, so that layers
+    /// before this one learn to defeat the loss after it
+    ///
+    pub fn grad_reverse(self, lambda: f32) -> Tensor<'d, 'g, T> {
+        use crate::functions::GradReverse;
+        let func = Function::One(GradReverse { lambda }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Largest singular value of a matrix, estimated with `iters` steps of power iteration.
+    /// The gradient is `u v^T` for the top singular vectors.
+    ///
+    pub fn spectral_norm(self, iters: usize) -> Tensor<'d, 'g, T> {
+        use crate::functions::SpectralNorm;
+        let func = Function::One(
+            SpectralNorm {
+                iters,
+                u: None,
+                v: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Frobenius (Euclidean) norm over all elements, `sqrt(sum(x^2))`
+    ///
+    pub fn fro_norm(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::FroNorm;
+        let func = Function::One(
+            FroNorm {
+                x: None,
+                norm: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// The largest absolute value over all elements, `max |x|`
+    ///
+    pub fn abs_max(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::AbsMax;
+        let func = Function::One(
+            AbsMax {
+                in_shape: Vec::new(),
+                at: Vec::new(),
+                sign: 1.0,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Kronecker product of two matrices
+    ///
+    pub fn kron(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.same_graph(&other);
+
+        use crate::functions::Kron;
+        let func = Function::Two(
+            Kron {
+                a4: None,
+                b4: None,
+                shape4: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, other.index], func)
+    }
+
+    ///
+    /// The commutator `[A, B] = AB - BA` of two square matrices
+    ///
+    pub fn commutator(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.bracket(other, false)
+    }
+
+    ///
+    /// The anticommutator `{A, B} = AB + BA` of two square matrices
+    ///
+    pub fn anticommutator(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.bracket(other, true)
+    }
+
+    fn bracket(self, other: Tensor<'d, 'g, T>, anti: bool) -> Tensor<'d, 'g, T> {
+        self.same_graph(&other);
+
+        use crate::functions::Commutator;
+        let func = Function::Two(
+            Commutator {
+                anti,
+                a: None,
+                b: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, other.index], func)
+    }
+
+    ///
+    /// Exponential of a 3x3 skew-symmetric matrix (an so(3) generator) as an exact rotation,
+    /// via Rodrigues' formula. Other inputs fall back to `expm`.
+    ///
+    pub fn expm_so3(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::ExpmSo3;
+        let func = Function::One(
+            ExpmSo3 {
+                k: None,
+                theta: 0.0,
+                general: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Map a tangent 3-vector `v` to the unit quaternion `exp((0, v))`, which rotates by
+    /// `2|v|` about `v`. Quaternions are `[w, x, y, z]` arrays.
+    ///
+    pub fn quat_exp(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::QuatExp;
+        let func = Function::One(QuatExp { v: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Map a unit quaternion `[w, x, y, z]` back to its tangent 3-vector, the inverse of
+    /// `quat_exp` for `|v| < pi`
+    ///
+    pub fn quat_log(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::QuatLog;
+        let func = Function::One(QuatLog { q: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Axis-angle 3-vector `theta * axis` of a 3x3 rotation matrix, the SO(3) logarithm and
+    /// the inverse of `expm_so3` for `theta < pi`. The gradient is singular at `theta = pi`
+    ///
+    pub fn rotation_to_axis_angle(self) -> Tensor<'d, 'g, T> {
+        use crate::functions::RotationLog;
+        let func = Function::One(RotationLog { r: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// 0/1 mask of `self > other`, with zero gradient
+    ///
+    pub fn gt(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.compare(other, Comparison::Gt)
+    }
+
+    ///
+    /// 0/1 mask of `self < other`, with zero gradient
+    ///
+    pub fn lt(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.compare(other, Comparison::Lt)
+    }
+
+    ///
+    /// 0/1 mask of `self >= other`, with zero gradient
+    ///
+    pub fn ge(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.compare(other, Comparison::Ge)
+    }
+
+    ///
+    /// 0/1 mask of `self <= other`, with zero gradient
+    ///
+    pub fn le(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.compare(other, Comparison::Le)
+    }
+
+    ///
+    /// 0/1 mask of `self == other`, with zero gradient
+    ///
+    pub fn eq(self, other: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.compare(other, Comparison::Eq)
+    }
+
+    pub(crate) fn compare(self, other: Tensor<'d, 'g, T>, cmp: Comparison) -> Tensor<'d, 'g, T> {
+        self.same_graph(&other);
+
+        use crate::functions::Compare;
+        let func = Function::Two(
+            Compare {
+                cmp,
+                a: None,
+                b: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, other.index], func)
+    }
+
+    ///
+    /// Sum over `axis`, which is removed unless `keepdims` keeps it with length 1
+    ///
+    pub fn sum_axis(self, axis: usize, keepdims: bool) -> Tensor<'d, 'g, T> {
+        self.reduce_axes(vec![axis], keepdims, false)
+    }
+
+    ///
+    /// Mean over `axis`, which is removed unless `keepdims` keeps it with length 1
+    ///
+    pub fn mean_axis(self, axis: usize, keepdims: bool) -> Tensor<'d, 'g, T> {
+        self.reduce_axes(vec![axis], keepdims, true)
+    }
+
+    ///
+    /// Sum over all of `axes` in one node; the axes are removed
+    ///
+    pub fn sum_axes(self, axes: Vec<usize>) -> Tensor<'d, 'g, T> {
+        self.reduce_axes(axes, false, false)
+    }
+
+    ///
+    /// Mean over all of `axes` in one node; the axes are removed
+    ///
+    pub fn mean_axes(self, axes: Vec<usize>) -> Tensor<'d, 'g, T> {
+        self.reduce_axes(axes, false, true)
+    }
+
+    fn reduce_axes(self, axes: Vec<usize>, keepdims: bool, mean: bool) -> Tensor<'d, 'g, T> {
+        use crate::functions::SumAxis;
+        assert!(
+            axes.iter()
+                .enumerate()
+                .all(|(i, axis)| !axes[..i].contains(axis)),
+            "reduction axes must be distinct, got {:?}",
+            axes
+        );
+        let func = Function::One(
+            SumAxis {
+                axes,
+                keepdims,
+                mean,
+                in_shape: None,
+            }
+            .into(),
+        );
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Reshape to `shape`, which must hold the same number of elements
+    ///
+    pub fn reshape(self, shape: Vec<usize>) -> Tensor<'d, 'g, T> {
+        self.reshape_to(crate::functions::ReshapeTo::Shape(shape))
+    }
+
+    ///
+    /// Collapse every axis from `start_dim` onward into one, e.g. `[N, C, H, W] -> [N, C*H*W]`
+    /// with `start_dim = 1` to feed conv features into a dense layer
+    ///
+    pub fn flatten(self, start_dim: usize) -> Tensor<'d, 'g, T> {
+        self.reshape_to(crate::functions::ReshapeTo::Flatten(start_dim))
+    }
+
+    ///
+    /// Remove `axis`, which must have length 1
+    ///
+    pub fn squeeze(self, axis: usize) -> Tensor<'d, 'g, T> {
+        self.reshape_to(crate::functions::ReshapeTo::Squeeze(axis))
+    }
+
+    ///
+    /// Insert an axis of length 1 at `axis`, e.g. `[3] -> [1, 3]` with `axis = 0`
+    ///
+    pub fn unsqueeze(self, axis: usize) -> Tensor<'d, 'g, T> {
+        self.reshape_to(crate::functions::ReshapeTo::Unsqueeze(axis))
+    }
+
+    fn reshape_to(self, to: crate::functions::ReshapeTo) -> Tensor<'d, 'g, T> {
+        use crate::functions::Reshape;
+        let func = Function::One(Reshape { to, in_shape: None }.into());
+        self.graph.push([self.index, self.index], func)
+    }
+
+    ///
+    /// Binary cross-entropy between probabilities `self` and `target`,
+    /// `-mean(t * ln(p) + (1 - t) * ln(1 - p))`. The target receives no gradient.
+    ///
+    pub fn bce_loss(self, target: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.same_graph(&target);
+
+        use crate::functions::BceLoss;
+        let func = Function::Two(BceLoss { p: None, t: None }.into());
+        self.graph.push([self.index, target.index], func)
+    }
+
+    ///
+    /// Binary cross-entropy on logits, equivalent to `sigmoid(self).bce_loss(target)` but
+    /// numerically stable. The target receives no gradient.
+    ///
+    pub fn bce_with_logits(self, target: Tensor<'d, 'g, T>) -> Tensor<'d, 'g, T> {
+        self.same_graph(&target);
+
+        use crate::functions::BceWithLogits;
+        let func = Function::Two(BceWithLogits { x: None, t: None }.into());
+        self.graph.push([self.index, target.index], func)
+    }
+
+    fn same_graph(&self, other: &Tensor<'d, 'g, T>) {
         assert_eq!(
             self.graph as *const Graph<T>,
             other.graph as *const Graph<T>
         );
-        let mut nodes = self.graph.nodes.borrow_mut();
+    }
+}
 
-        let len = nodes.len();
+impl<'d, 'g, T: TensorType<'d> + Clone> ::std::ops::Add for Tensor<'d, 'g, T> {
+    type Output = Tensor<'d, 'g, T>;
+    fn add(self, other: Tensor<'d, 'g, T>) -> Self::Output {
+        self.same_graph(&other);
 
         use crate::functions::Add;
-        nodes.push(RefCell::new(Node {
-            deps: [self.index, other.index],
-            func: Function::Two(Add.into()),
-            value: None,
-            grad: None,
-            ctx: [None, None],
-        }));
-        Tensor {
-            graph: self.graph,
-            index: len,
-        }
+        self.graph.push(
+            [self.index, other.index],
+            Function::Two(Add { in_shapes: None }.into()),
+        )
     }
 }
 
 impl<'d, 'g, T: TensorType<'d> + Clone> ::std::ops::Mul for Tensor<'d, 'g, T> {
     type Output = Tensor<'d, 'g, T>;
     fn mul(self, other: Tensor<'d, 'g, T>) -> Self::Output {
-        assert_eq!(
-            self.graph as *const Graph<T>,
-            other.graph as *const Graph<T>
-        );
-        let mut nodes = self.graph.nodes.borrow_mut();
-
-        let len = nodes.len();
+        self.same_graph(&other);
 
+        use crate::functions::Mul;
         let m: Mul<'d, T> = Mul {
             x_ctx: None,
             y_ctx: None,
         };
-
-        let func: Function<'d, T> = Function::Two(m.into());
-
-        use crate::functions::Mul;
-        nodes.push(RefCell::new(Node {
-            deps: [self.index, other.index],
-            func,
-            value: None,
-            grad: None,
-            ctx: [None, None],
-        }));
-        Tensor {
-            graph: self.graph,
-            index: len,
-        }
+        self.graph
+            .push([self.index, other.index], Function::Two(m.into()))
     }
 }
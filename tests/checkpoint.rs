@@ -0,0 +1,62 @@
+//! Checking that checkpointing hands memory back needs a global allocator, so this test
+//! gets its own binary
+
+use rust_grad::Graph;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+///
+/// Tracks how many bytes are currently allocated
+///
+struct CountingAlloc;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn checkpoint_frees_dropped_values() {
+    // The bytes a forward pass leaves allocated, and the resulting weight gradient
+    let run = |checkpoint: bool| {
+        let graph = Graph::new();
+        let x = graph.tensor(ndarray::Array::ones((64, 64)).into_dyn());
+        let w = graph.tensor(ndarray::Array::from_elem((64, 64), 0.5).into_dyn());
+
+        let mut h = x;
+        for _ in 0..16 {
+            h = h * w;
+        }
+        if checkpoint {
+            h = h.checkpoint();
+        }
+
+        let before = LIVE_BYTES.load(Ordering::Relaxed);
+        h.forward();
+        let retained = LIVE_BYTES.load(Ordering::Relaxed) - before;
+
+        h.backward(ndarray::Array::ones((64, 64)).into_dyn());
+        (retained, w.grad())
+    };
+
+    let (kept, grad) = run(false);
+    let (dropped, checkpointed_grad) = run(true);
+    assert_eq!(grad, checkpointed_grad);
+
+    // 16 products of 16 KiB each, against only the checkpoint output
+    let array = 64 * 64 * std::mem::size_of::<f32>();
+    assert!(kept >= 16 * array, "retained {} bytes", kept);
+    assert!(dropped < 2 * array, "retained {} bytes", dropped);
+}